@@ -3,15 +3,19 @@ use crate::translation::{
     build_compile_table,
     build_decode_table, 
     encode_instruction,
-    get_bytes_from_line
+    get_bytes_from_line,
+    SourceLine
 };
+use crate::error::{AsmError, Span};
+
+use crate::format::{Image, Segment};
+use crate::processor::cpu::mmu::{PAGE_SIZE, PERM_READ, PERM_WRITE, PERM_EXEC};
+use crate::symbols::{LineEntry, SymbolTable};
 
 use std::collections::HashMap;
-use std::fs::File;
-use std::path::PathBuf;
-use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
 
-use crate::{debug, info, error};
+use crate::{debug, info};
 
 
 
@@ -19,73 +23,170 @@ use crate::{debug, info, error};
 
 #[derive(Clone, Debug)]
 struct Section {
-    _name: String,
-    lines: Vec<String>
+    name: String,
+    lines: Vec<(usize, String)>
 }
 
 impl Section {
-    fn new(_name: String) -> Self{
-        Section { _name, lines: Vec::new() }
+    fn new(name: String) -> Self{
+        Section { name, lines: Vec::new() }
+    }
+    /// the page permissions the section is loaded with: code can be run but
+    /// not written, read-only data only read, and anything else read and 
+    /// written
+    fn flags(&self) -> u8 {
+        match self.name.as_str() {
+            ".text" => PERM_READ | PERM_EXEC,
+            ".rodata" => PERM_READ,
+            _ => PERM_READ | PERM_WRITE
+        }
     }
-    fn push_line(&mut self, line: String) {
-        self.lines.push(line);
+    /// adds a line, along with its (1-based) line number in the source
+    fn push_line(&mut self, line_no: usize, line: String) {
+        self.lines.push((line_no, line));
     }
-    fn get_lines(&self) -> Vec<String>{
+    fn get_lines(&self) -> Vec<(usize, String)>{
         self.lines.clone()
     }
 }
 
 
 
-/// compiles a program in `prog`
-pub fn compile(prog: PathBuf, output: PathBuf) {
+/// renders an assembler error, quoting the offending source line from `prog` 
+/// when the error has a location
+pub fn describe_error(prog: &Path, e: &AsmError) -> String {
+    let span = match e.span() {
+        Some(a) => a,
+        None => return e.to_string()
+    };
+    let source = std::fs::read_to_string(prog).unwrap_or_default();
+    let line = source.lines().nth(span.line.saturating_sub(1)).unwrap_or("").trim();
+
+    format!("{}:{}\n    {}\n    {}{}", 
+        prog.display(), e, line, 
+        " ".repeat(span.start), "^".repeat((span.end - span.start).max(1)))
+}
+
+/// the result of assembling a program
+#[derive(Clone, Debug, Default)]
+pub struct Assembly {
+    /// the machine code, to be loaded at address 0
+    pub bytes: Vec<u8>,
+    /// the address of every label, keyed by its name including the leading `.`
+    pub labels: HashMap<String, u32>,
+    /// which source line each instruction came from, in address order
+    pub lines: Vec<LineEntry>,
+    /// where each section was placed and the permissions it gets
+    pub segments: Vec<Segment>
+}
+
+impl Assembly {
+    /// the loadable image for the program
+    pub fn image(&self) -> Image {
+        if self.segments.is_empty() {
+            return Image::from_bytes(self.bytes.clone());
+        }
+        let runs = self.segments.iter()
+            .map(|s| (s.addr as usize, self.bytes[s.addr as usize..(s.addr + s.size) as usize].to_vec()))
+            .collect();
+        Image { runs, segments: self.segments.clone() }
+    }
+
+    /// the program's labels and line table as a symbol table
+    pub fn symbols(&self) -> SymbolTable {
+        let mut table = SymbolTable::from_labels(&self.labels);
+        for entry in self.lines.iter() {
+            table.insert_line(*entry);
+        }
+        table
+    }
+}
+
+/// loads a program along with its symbols. `.dba` files are assembled
+/// first and carry their own line table; binaries use the symbol file at
+/// `symbols` if one is given
+pub fn load_program(path: &Path, symbols: Option<&Path>) -> Result<(Image, SymbolTable), String> {
+    if path.extension().is_some_and(|e| e == "dba") {
+        let source = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let assembly = assemble(&source).map_err(|e| describe_error(path, &e))?;
+        let mut table = assembly.symbols();
+        table.set_source(Some(std::fs::canonicalize(path).unwrap_or(path.to_path_buf())));
+        return Ok((assembly.image(), table));
+    }
+
+    let image = Image::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let table = match symbols {
+        Some(s) => SymbolTable::read(s)?,
+        None => SymbolTable::default()
+    };
+    Ok((image, table))
+}
+
+/// compiles a program in `prog`, writing the binary to `output` (`a.out` if 
+/// empty)
+pub fn compile(prog: PathBuf, output: PathBuf) -> Result<Assembly, AsmError> {
     info!("Compiling {}...", prog.display());
 
     // read all of the data into a vector
-    let file = match File::open(prog.clone()) {
-        Ok(a) => a,
-        Err(e) => {
-            error!("Failed to open file {}: {}", prog.display(), e);
-        }
+    let source = std::fs::read_to_string(&prog)
+        .map_err(|e| AsmError::Io { path: prog.clone(), error: e.to_string() })?;
+    let assembly = assemble(&source)?;
+
+    let output = match output.as_os_str().is_empty() {
+        true => PathBuf::from("a.out"),
+        false => output
     };
-    let reader = BufReader::new(file);
+    assembly.image().write(&output)
+        .map_err(|e| AsmError::Io { path: output.clone(), error: e.to_string() })?;
 
+    info!("Successfully wrote bytes to file");
+    Ok(assembly)
+}
+
+/// assembles the program in `source`
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
     // initialize variables we need
     let compile_table = build_compile_table();
     let decode_table = build_decode_table();
     let mut sections: Vec<Section> = Vec::new();
     let mut output_bytes: Vec<u8> = Vec::new();
     let mut labels: HashMap<String, u32> = HashMap::new();
+    let mut lines: Vec<LineEntry> = Vec::new();
+    let mut segments: Vec<Segment> = Vec::new();
     let mut prev_bytes: u32 = 0;
     
 
 
     // read file line by line
-    for (index, line) in reader.lines().enumerate() {
-        let line = line.unwrap();
+    for (index, line) in source.lines().enumerate() {
+        let line = line.trim().to_string();
+        let line_no = index + 1;
         let mut lineclone = line.clone();
         lineclone.retain(|x| !x.is_whitespace());
 
         debug!("Line {} - {} characters (prev bytes={})", index, line.len(), prev_bytes);
-        if lineclone.len() == 0 {
+        if lineclone.is_empty() {
             continue;
         }
 
         debug!("{}", line);
 
         // remove comment lines
-        if lineclone.chars().next().unwrap() != ';' {
+        if !lineclone.starts_with(';') {
             // see if the line contains a label or section declaration
             if line.contains('.') {
                 let thing: Vec<&str> = line.split(';').collect();
-                let mut command = thing[0].clone().to_string();
+                let mut command = thing[0].to_string();
                 command.retain(|x| !x.is_whitespace());
             
                 // if its a section, save it
                 if command.contains("section") {
-                    let thing2: String = command.split("section").collect::<Vec<&str>>()[1].clone().to_string();
+                    let thing2: String = command.split("section").collect::<Vec<&str>>()[1].to_string();
                     sections.push(Section::new(thing2));
-                } else if line.chars().next().unwrap() == '.' {
+                    // each section starts on its own page so it can be 
+                    // protected separately
+                    prev_bytes = prev_bytes.next_multiple_of(PAGE_SIZE as u32);
+                } else if line.starts_with('.') {
                     // its a lable so push it to the labels HashMap
                     command.retain(|x| !x.is_whitespace());
                 
@@ -100,25 +201,21 @@ pub fn compile(prog: PathBuf, output: PathBuf) {
                 } else {
                     // its a line that contains a label, but doesnt define one
                     let section = match sections.last_mut() {
-                        None => {
-                            error!("No section declared before first instruction");
-                        }, 
+                        None => return Err(AsmError::NoSection { span: Span::line(line_no, &line) }), 
                         Some(a) => a
                     };
                     prev_bytes += get_bytes_from_line(&line, &decode_table);
-                    section.push_line(line);
+                    section.push_line(line_no, line);
                 }
             } else {
                 // its not a label or section declaration
                 let section = match sections.last_mut() {
-                    None => {
-                        error!("No section declared before first instruction");
-                    }, 
+                    None => return Err(AsmError::NoSection { span: Span::line(line_no, &line) }), 
                     Some(a) => a
                 };
 
                 prev_bytes += get_bytes_from_line(&line, &decode_table);
-                section.push_line(line);
+                section.push_line(line_no, line);
             }
         } 
     }
@@ -132,34 +229,28 @@ pub fn compile(prog: PathBuf, output: PathBuf) {
     debug!("Labels found: {:?}", labels);
 
     for m in sections.iter() {
-        for l in m.get_lines() {
-            let mut inst = match encode_instruction(l, &compile_table, &decode_table, &labels) {
-                Ok(a) => a,
-                Err(e) => {
-                    error!("Failed to compile: {}", e);
-                }
-            };
+        output_bytes.resize(output_bytes.len().next_multiple_of(PAGE_SIZE), 0);
+        let start = output_bytes.len();
+        for (line_no, l) in m.get_lines() {
+            let src = SourceLine { line_no, text: &l };
+            let addr = output_bytes.len() as u32;
+            let mut inst = encode_instruction(src, &compile_table, &decode_table, &labels, addr)?;
+            if !inst.is_empty() {
+                let data = l.split_whitespace().next() == Some("bytes");
+                lines.push(LineEntry { addr, size: inst.len() as u32, line: line_no, data });
+            }
             output_bytes.append(&mut inst);
             debug!("Length of output: {}", output_bytes.len());
         }
+        if output_bytes.len() > start {
+            segments.push(Segment { addr: start as u32, size: (output_bytes.len() - start) as u32, flags: m.flags() });
+        }
     }
 
     debug!("Output: ");
     debug!("{:?}", output_bytes);
 
-    let mut fout: File;
-    if output == PathBuf::from("") {
-        fout = File::create("a.out".to_string()).unwrap();
-    } else {
-        fout = File::create(output).unwrap();
-    }
-
-    for entry in output_bytes.iter() {
-        fout.write_all(&entry.to_be_bytes()).unwrap();
-    }
-
-    info!("Successfully wrote bytes to file");
-    
+    Ok(Assembly { bytes: output_bytes, labels, lines, segments })
 }
 
 
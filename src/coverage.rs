@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use serde_json::{json, Value};
+
+use crate::disasm::Disassembler;
+use crate::format::Image;
+use crate::processor::cpu::{CPU, Step};
+use crate::processor::instructions::Instruction;
+use crate::symbols::SymbolTable;
+
+
+/// how often a conditional branch went each way
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BranchCounts {
+    pub taken: u64,
+    pub not_taken: u64
+}
+
+/// lines and branch directions covered, out of how many there are
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Summary {
+    pub lines_hit: usize,
+    pub lines: usize,
+    pub branches_hit: usize,
+    pub branches: usize
+}
+
+
+/// records which instructions ran and which way conditional branches went,
+/// and maps them back to source lines through the symbol table's line
+/// table. Pass `observe` to `CPU::run_observed`
+pub struct Coverage {
+    symbols: SymbolTable,
+    executed: BTreeMap<usize, u64>,
+    branches: BTreeMap<usize, BranchCounts>
+}
+
+fn is_branch(inst: Instruction) -> bool {
+    matches!(inst, Instruction::JeqReg | Instruction::JeqImm)
+}
+
+impl Coverage {
+    /// starts recording coverage of `image`. Every conditional branch in the
+    /// line table's code is found up front, so branches that never run are
+    /// reported too
+    pub fn new(image: &Image, symbols: SymbolTable) -> Self {
+        let disassembler = Disassembler::new();
+        let mut branches = BTreeMap::new();
+        for entry in symbols.lines().filter(|e| !e.data) {
+            let addr = entry.addr as usize;
+            let bytes = image.bytes_at(addr);
+            if disassembler.decode(bytes, addr).inst.is_some_and(is_branch) {
+                branches.insert(addr, BranchCounts::default());
+            }
+        }
+        Coverage { symbols, executed: BTreeMap::new(), branches }
+    }
+
+    /// records one step
+    pub fn observe(&mut self, _cpu: &CPU, step: &Step) {
+        *self.executed.entry(step.pc).or_default() += 1;
+        if let Some(inst) = step.inst.filter(|i| is_branch(*i)) {
+            let counts = self.branches.entry(step.pc).or_default();
+            match step.next_pc != step.pc + inst.size() {
+                true => counts.taken += 1,
+                false => counts.not_taken += 1
+            }
+        }
+    }
+
+    /// how many times each executed address ran
+    pub fn executed(&self) -> &BTreeMap<usize, u64> {
+        &self.executed
+    }
+
+    /// every conditional branch, executed or not
+    pub fn branches(&self) -> &BTreeMap<usize, BranchCounts> {
+        &self.branches
+    }
+
+    /// how many times each line of code ran, in line order. A line's count
+    /// is that of its first instruction
+    pub fn line_counts(&self) -> BTreeMap<usize, u64> {
+        let mut lines = BTreeMap::new();
+        for entry in self.symbols.lines().filter(|e| !e.data) {
+            let count = self.executed.get(&(entry.addr as usize)).copied().unwrap_or(0);
+            let c = lines.entry(entry.line).or_insert(0);
+            *c = count.max(*c);
+        }
+        lines
+    }
+
+    /// the source line of the code at `addr`
+    fn line_of(&self, addr: usize) -> Option<usize> {
+        self.symbols.line_of(addr as u32).map(|e| e.line)
+    }
+
+    pub fn summary(&self) -> Summary {
+        let lines = self.line_counts();
+        Summary {
+            lines_hit: lines.values().filter(|c| **c > 0).count(),
+            lines: lines.len(),
+            branches_hit: self.branches.values().map(|b| (b.taken > 0) as usize + (b.not_taken > 0) as usize).sum(),
+            branches: self.branches.len() * 2
+        }
+    }
+
+    /// the coverage as JSON: the source file, every executed address, every
+    /// branch and a count for every line of code
+    pub fn to_json(&self) -> Value {
+        json!({
+            "source": self.symbols.source().map(|s| s.display().to_string()),
+            "executed": self.executed.iter()
+                .map(|(addr, count)| json!({ "addr": addr, "count": count, "line": self.line_of(*addr) }))
+                .collect::<Vec<Value>>(),
+            "branches": self.branches.iter()
+                .map(|(addr, b)| json!({
+                    "addr": addr,
+                    "line": self.line_of(*addr),
+                    "taken": b.taken,
+                    "not_taken": b.not_taken
+                }))
+                .collect::<Vec<Value>>(),
+            "lines": self.line_counts().iter()
+                .map(|(line, count)| json!({ "line": line, "count": count }))
+                .collect::<Vec<Value>>()
+        })
+    }
+
+    /// writes an lcov tracefile. Fails if there is no line table to map
+    /// addresses back to the source with
+    pub fn write_lcov(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let source = match self.symbols.source() {
+            Some(a) => a,
+            None => return Err(std::io::Error::other("the program has no line table"))
+        };
+        let lines = self.line_counts();
+        let summary = self.summary();
+
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", source.display())?;
+        for (addr, b) in self.branches.iter() {
+            let line = match self.line_of(*addr) {
+                Some(a) => a,
+                None => continue
+            };
+            // lcov wants `-` for branches whose line never ran
+            let count = |n: u64| match self.executed.contains_key(addr) {
+                true => n.to_string(),
+                false => "-".to_string()
+            };
+            writeln!(out, "BRDA:{},{},0,{}", line, addr, count(b.taken))?;
+            writeln!(out, "BRDA:{},{},1,{}", line, addr, count(b.not_taken))?;
+        }
+        writeln!(out, "BRF:{}", summary.branches)?;
+        writeln!(out, "BRH:{}", summary.branches_hit)?;
+        for (line, count) in lines.iter() {
+            writeln!(out, "DA:{},{}", line, count)?;
+        }
+        writeln!(out, "LF:{}", summary.lines)?;
+        writeln!(out, "LH:{}", summary.lines_hit)?;
+        writeln!(out, "end_of_record")
+    }
+
+    /// writes a one-line-per-file summary of line and branch coverage
+    pub fn write_summary(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let percent = |hit: usize, total: usize| match total {
+            0 => "-".to_string(),
+            t => format!("{:.1}%", hit as f64 * 100.0 / t as f64)
+        };
+        let s = self.summary();
+        let file = self.symbols.source()
+            .and_then(|s| s.file_name())
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or("(no line table)".to_string());
+        writeln!(out, "{:<24} {:>16} {:>16}", "file", "lines", "branches")?;
+        writeln!(out, "{:<24} {:>9} {:>6} {:>9} {:>6}", file,
+            format!("{}/{}", s.lines_hit, s.lines), percent(s.lines_hit, s.lines),
+            format!("{}/{}", s.branches_hit, s.branches), percent(s.branches_hit, s.branches))
+    }
+}
@@ -0,0 +1,564 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use serde_json::{json, Value};
+
+use crate::compile::load_program;
+use crate::processor::cpu::{MachineConfig, RunOutcome, StopHandle};
+use crate::{debug, info};
+use super::repl::flag_letters;
+use super::{Debugger, Expr, StopReason};
+
+
+/// the only thread the adapter reports
+const THREAD_ID: u64 = 1;
+/// `variablesReference`s of the two scopes
+const SCOPE_REGISTERS: u64 = 1;
+const SCOPE_MEMORY: u64 = 2;
+/// how many bytes of memory are previewed for each label
+const LABEL_PREVIEW: usize = 16;
+/// the most bytes one `readMemory` request returns. The rest are reported
+/// as unreadable, and clients ask again for them
+const MAX_READ_MEMORY: usize = 1 << 16;
+
+
+/// serves one debug adapter protocol session, reading requests from `input`
+/// and writing responses and events to `output`, until the client
+/// disconnects
+pub fn serve(input: impl Read + Send + 'static, output: impl Write + Send + 'static) -> std::io::Result<()> {
+    let transport = Transport {
+        out: Arc::new(Mutex::new(Box::new(output))),
+        seq: Arc::new(AtomicU64::new(1))
+    };
+    let stop = Arc::new(Mutex::new(None));
+    let requests = spawn_reader(input, stop.clone());
+    let mut session = Session {
+        transport,
+        dbg: None,
+        stop,
+        pending_output: Arc::new(Mutex::new(Vec::new())),
+        line_breakpoints: Vec::new(),
+        stop_on_entry: false
+    };
+    session.run(requests)
+}
+
+/// reads messages on another thread. `pause` and `disconnect` interrupt a
+/// running program straight away; every message is also handed to the
+/// session
+fn spawn_reader(input: impl Read + Send + 'static, stop: Arc<Mutex<Option<StopHandle>>>) -> Receiver<Option<Value>> {
+    let (tx, rx) = channel();
+
+    std::thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        while let Ok(Some(msg)) = read_message(&mut input) {
+            if let Some("pause" | "disconnect" | "terminate") = msg["command"].as_str() {
+                if let Some(handle) = lock(&stop).as_ref() {
+                    handle.stop();
+                }
+            }
+            if tx.send(Some(msg)).is_err() {
+                return;
+            }
+        }
+        let _ = tx.send(None);
+    });
+
+    rx
+}
+
+/// reads one `Content-Length` framed message, or `None` at end of input
+fn read_message(input: &mut impl BufRead) -> std::io::Result<Option<Value>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                len = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let len = len.ok_or(std::io::Error::new(std::io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0u8; len];
+    input.read_exact(&mut body)?;
+    debug!("dap <- {}", String::from_utf8_lossy(&body));
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// locks a mutex, carrying on if another thread panicked while holding it
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+
+/// writes messages to the client. Shared with the console, which sends
+/// output events while the program runs
+#[derive(Clone)]
+struct Transport {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+    seq: Arc<AtomicU64>
+}
+
+impl Transport {
+    fn send(&self, mut msg: Value) -> std::io::Result<()> {
+        msg["seq"] = json!(self.seq.fetch_add(1, Ordering::SeqCst));
+        let body = msg.to_string();
+        debug!("dap -> {}", body);
+        let mut out = lock(&self.out);
+        write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        out.flush()
+    }
+
+    fn event(&self, event: &str, body: Value) -> std::io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn output(&self, bytes: &[u8]) -> std::io::Result<()> {
+        self.event("output", json!({ "category": "stdout", "output": String::from_utf8_lossy(bytes) }))
+    }
+}
+
+/// the program's console. Output is sent a line at a time as it is
+/// written; whatever is left over is sent when the program stops
+struct Console {
+    transport: Transport,
+    pending: Arc<Mutex<Vec<u8>>>
+}
+
+impl Write for Console {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut pending = lock(&self.pending);
+        pending.extend_from_slice(buf);
+        if let Some(end) = pending.iter().rposition(|b| *b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            self.transport.output(&line)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+
+struct Session {
+    transport: Transport,
+    dbg: Option<Debugger>,
+    /// where the reader thread finds the running program's stop handle
+    stop: Arc<Mutex<Option<StopHandle>>>,
+    pending_output: Arc<Mutex<Vec<u8>>>,
+    /// ids of the breakpoints set from source lines
+    line_breakpoints: Vec<usize>,
+    stop_on_entry: bool
+}
+
+impl Session {
+    fn run(&mut self, requests: Receiver<Option<Value>>) -> std::io::Result<()> {
+        while let Ok(Some(req)) = requests.recv() {
+            if req["type"] != "request" {
+                continue;
+            }
+            let command = req["command"].as_str().unwrap_or("").to_string();
+            let args = req.get("arguments").cloned().unwrap_or(json!({}));
+            let result = self.handle(&command, &args);
+
+            // running commands reply before they run, so report afterwards
+            let (body, after) = match result {
+                Ok(a) => a,
+                Err(e) => {
+                    self.respond(&req, Err(e))?;
+                    continue;
+                }
+            };
+            self.respond(&req, Ok(body))?;
+            match after {
+                After::Nothing => (),
+                After::Initialized => self.transport.event("initialized", json!({}))?,
+                After::Start => match self.stop_on_entry {
+                    true => {
+                        if let Some(dbg) = self.dbg.as_mut() {
+                            dbg.mark_stopped();
+                        }
+                        self.stopped("entry", None, None)?
+                    },
+                    false => self.resume(Debugger::cont)?
+                },
+                After::Continue => self.resume(Debugger::cont)?,
+                After::Next => self.resume(Debugger::step_over)?,
+                After::StepIn => self.resume(Debugger::step)?,
+                After::Paused => self.stopped("pause", None, None)?,
+                After::Disconnect => return Ok(())
+            }
+        }
+        Ok(())
+    }
+
+    fn respond(&self, req: &Value, result: Result<Value, String>) -> std::io::Result<()> {
+        let mut msg = json!({
+            "type": "response",
+            "request_seq": req["seq"],
+            "command": req["command"],
+            "success": result.is_ok()
+        });
+        match result {
+            Ok(body) => msg["body"] = body,
+            Err(e) => msg["message"] = json!(e)
+        }
+        self.transport.send(msg)
+    }
+
+    fn dbg(&mut self) -> Result<&mut Debugger, String> {
+        self.dbg.as_mut().ok_or("no program has been launched".to_string())
+    }
+
+    /// handles one request, returning the response body and what to do once
+    /// the response has been sent
+    fn handle(&mut self, command: &str, args: &Value) -> Result<(Value, After), String> {
+        let body = match command {
+            "initialize" => json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsEvaluateForHovers": true,
+                "supportsSetVariable": true,
+                "supportsReadMemoryRequest": true,
+                "supportsWriteMemoryRequest": true,
+                "supportsTerminateRequest": true
+            }),
+            "launch" => {
+                self.launch(args)?;
+                return Ok((json!({}), After::Initialized));
+            },
+            "setBreakpoints" => self.set_breakpoints(args)?,
+            "setExceptionBreakpoints" => json!({ "breakpoints": [] }),
+            "configurationDone" => return Ok((json!({}), After::Start)),
+            "threads" => json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+            "stackTrace" => self.stack_trace()?,
+            "scopes" => json!({ "scopes": [
+                { "name": "Registers", "variablesReference": SCOPE_REGISTERS, "expensive": false },
+                { "name": "Memory", "variablesReference": SCOPE_MEMORY, "expensive": false }
+            ] }),
+            "variables" => self.variables(args)?,
+            "setVariable" => self.set_variable(args)?,
+            "evaluate" => {
+                let dbg = self.dbg()?;
+                let expr = args["expression"].as_str().unwrap_or("");
+                let v = Expr::parse(expr)?.eval(&dbg.cpu, &dbg.symbols)?;
+                json!({ "result": format!("0x{:x} ({})", v, v), "variablesReference": 0 })
+            },
+            "readMemory" => self.read_memory(args)?,
+            "writeMemory" => self.write_memory(args)?,
+            "continue" => return Ok((json!({ "allThreadsContinued": true }), After::Continue)),
+            "next" => {
+                self.dbg()?;
+                return Ok((json!({}), After::Next));
+            },
+            "stepIn" => {
+                self.dbg()?;
+                return Ok((json!({}), After::StepIn));
+            },
+            "stepOut" => return Err("there are no calls to step out of".to_string()),
+            "pause" => {
+                // the reader already asked a running program to stop. If it
+                // wasn't running, the request is still pending, so clear it
+                // and report the pause ourselves
+                if let Some(handle) = lock(&self.stop).as_ref() {
+                    if handle.take() {
+                        return Ok((json!({}), After::Paused));
+                    }
+                }
+                json!({})
+            },
+            "disconnect" | "terminate" => return Ok((json!({}), After::Disconnect)),
+            c => return Err(format!("unsupported request {}", c))
+        };
+        Ok((body, After::Nothing))
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<(), String> {
+        let program = args["program"].as_str().ok_or("launch needs a `program`")?;
+        let symbols = args["symbols"].as_str().map(Path::new);
+        let (image, symbols) = load_program(Path::new(program), symbols)?;
+        info!("Launching {}", program);
+
+        let mut dbg = Debugger::new(MachineConfig::default(), image, symbols).map_err(|e| e.to_string())?;
+        dbg.cpu.set_console(Box::new(Console {
+            transport: self.transport.clone(),
+            pending: self.pending_output.clone()
+        }));
+        // stdin carries the protocol, so the program reads end of input
+        dbg.cpu.set_console_input(Some(Box::new(std::io::empty())));
+        *lock(&self.stop) = Some(dbg.stop_handle());
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.dbg = Some(dbg);
+        Ok(())
+    }
+
+    /// replaces every line breakpoint with the ones in the request
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let ids = std::mem::take(&mut self.line_breakpoints);
+        let dbg = self.dbg()?;
+        for id in ids {
+            dbg.remove(id);
+        }
+
+        let path = args["source"]["path"].as_str().unwrap_or("");
+        let same_file = match (dbg.symbols.source(), std::fs::canonicalize(path)) {
+            (Some(source), Ok(path)) => source == path,
+            _ => false
+        };
+
+        let mut ret = Vec::new();
+        let mut added = Vec::new();
+        for bp in args["breakpoints"].as_array().cloned().unwrap_or_default() {
+            let line = bp["line"].as_u64().unwrap_or(0) as usize;
+            let entry = match same_file {
+                true => dbg.symbols.addr_of_line(line),
+                false => None
+            };
+            let condition = match bp["condition"].as_str().filter(|c| !c.trim().is_empty()) {
+                Some(c) => Some(Expr::parse(c)?),
+                None => None
+            };
+            match entry {
+                Some(e) => {
+                    let id = dbg.add_breakpoint(e.addr as usize, condition);
+                    added.push(id);
+                    ret.push(json!({ "id": id, "verified": true, "line": e.line }));
+                },
+                None => ret.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "no code at this line"
+                }))
+            }
+        }
+        self.line_breakpoints = added;
+        Ok(json!({ "breakpoints": ret }))
+    }
+
+    fn stack_trace(&mut self) -> Result<Value, String> {
+        let dbg = self.dbg()?;
+        let pc = dbg.cpu.pc();
+        let mut frame = json!({
+            "id": 0,
+            "name": dbg.symbols.describe_str(pc as u32).unwrap_or(format!("0x{:x}", pc)),
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": format!("0x{:x}", pc)
+        });
+        if let (Some(source), Some(entry)) = (dbg.symbols.source(), dbg.symbols.line_of(pc as u32)) {
+            frame["source"] = json!({ "path": source.display().to_string() });
+            frame["line"] = json!(entry.line);
+            frame["column"] = json!(1);
+        }
+        Ok(json!({ "stackFrames": [frame], "totalFrames": 1 }))
+    }
+
+    fn variables(&mut self, args: &Value) -> Result<Value, String> {
+        let dbg = self.dbg()?;
+        let var = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let vars: Vec<Value> = match args["variablesReference"].as_u64() {
+            Some(SCOPE_REGISTERS) => {
+                let mut vars: Vec<Value> = dbg.cpu.regs().iter().enumerate()
+                    .map(|(i, r)| var(format!("r{}", i), format!("0x{:08x}", r)))
+                    .collect();
+                vars.push(var("pc".to_string(), format!("0x{:08x}", dbg.cpu.pc())));
+                vars.push(var("sp".to_string(), format!("0x{:08x}", dbg.cpu.sp())));
+                vars.push(var("fl".to_string(), format!("0x{:02x} [{}]", dbg.cpu.flags(), flag_letters(dbg.cpu.flags()))));
+                vars
+            },
+            // a preview of the memory at each label
+            Some(SCOPE_MEMORY) => dbg.symbols.iter().map(|(addr, name)| {
+                let bytes = dbg.read_memory(addr as usize, LABEL_PREVIEW).unwrap_or_default();
+                let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                let mut v = var(name.to_string(), hex.join(" "));
+                v["memoryReference"] = json!(format!("0x{:x}", addr));
+                v
+            }).collect(),
+            _ => Vec::new()
+        };
+        Ok(json!({ "variables": vars }))
+    }
+
+    fn set_variable(&mut self, args: &Value) -> Result<Value, String> {
+        let dbg = self.dbg()?;
+        if args["variablesReference"].as_u64() != Some(SCOPE_REGISTERS) {
+            return Err("only registers can be changed".to_string());
+        }
+        let value = Expr::parse(args["value"].as_str().unwrap_or(""))?.eval(&dbg.cpu, &dbg.symbols)?;
+        match args["name"].as_str().unwrap_or("") {
+            "pc" => dbg.cpu.set_pc(value as usize),
+            "sp" => dbg.cpu.set_sp(value as usize),
+            "fl" => dbg.cpu.set_flags(value as u8),
+            r => {
+                let reg = r.strip_prefix('r').and_then(|n| n.parse::<u8>().ok())
+                    .ok_or(format!("unknown register {}", r))?;
+                dbg.cpu.set_reg(reg, value).map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(json!({ "value": format!("0x{:08x}", value) }))
+    }
+
+    fn read_memory(&mut self, args: &Value) -> Result<Value, String> {
+        let dbg = self.dbg()?;
+        let addr = memory_reference(args)?;
+        let count = args["count"].as_u64().unwrap_or(0) as usize;
+        // stop at the first byte that can't be read
+        let end = addr.saturating_add(count.min(MAX_READ_MEMORY));
+        let bytes: Vec<u8> = (addr..end).map_while(|a| dbg.cpu.memory.read_u8(a).ok()).collect();
+        let unreadable = count - bytes.len();
+        Ok(json!({
+            "address": format!("0x{:x}", addr),
+            "data": base64_encode(&bytes),
+            "unreadableBytes": unreadable
+        }))
+    }
+
+    fn write_memory(&mut self, args: &Value) -> Result<Value, String> {
+        let dbg = self.dbg()?;
+        let addr = memory_reference(args)?;
+        let bytes = base64_decode(args["data"].as_str().unwrap_or(""))?;
+        dbg.write_memory(addr, &bytes).map_err(|e| e.to_string())?;
+        Ok(json!({ "bytesWritten": bytes.len() }))
+    }
+
+    /// steps or continues, then tells the client why the program stopped
+    fn resume(&mut self, how: fn(&mut Debugger) -> StopReason) -> std::io::Result<()> {
+        let reason = match self.dbg.as_mut() {
+            Some(dbg) => how(dbg),
+            None => return Ok(())
+        };
+
+        let pending: Vec<u8> = lock(&self.pending_output).drain(..).collect();
+        if !pending.is_empty() {
+            self.transport.output(&pending)?;
+        }
+
+        match reason {
+            StopReason::Step => self.stopped("step", None, None),
+            StopReason::Breakpoint(id) => self.stopped("breakpoint", None, Some(id)),
+            StopReason::Watchpoint { .. } => self.stopped("data breakpoint", Some(reason.to_string()), None),
+            StopReason::Interrupted => self.stopped("pause", None, None),
+            StopReason::Program(RunOutcome::Halted(code)) => {
+                self.transport.event("exited", json!({ "exitCode": code }))?;
+                self.transport.event("terminated", json!({}))
+            },
+            StopReason::Program(RunOutcome::Breakpoint(_)) => self.stopped("breakpoint", Some("brk".to_string()), None),
+            StopReason::Program(RunOutcome::Fault(e)) => self.stopped("exception", Some(e.to_string()), None),
+            StopReason::Program(RunOutcome::LimitExceeded(l)) => self.stopped("exception", Some(l.to_string()), None),
+            StopReason::Program(_) => self.stopped("pause", None, None)
+        }
+    }
+
+    fn stopped(&self, reason: &str, text: Option<String>, breakpoint: Option<usize>) -> std::io::Result<()> {
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["description"] = json!(text);
+            body["text"] = json!(text);
+        }
+        if let Some(id) = breakpoint {
+            body["hitBreakpointIds"] = json!([id]);
+        }
+        self.transport.event("stopped", body)
+    }
+}
+
+/// what to do after responding to a request
+enum After {
+    Nothing,
+    /// the program is loaded and ready for breakpoints
+    Initialized,
+    /// configuration is done, so stop on entry or start running
+    Start,
+    Continue,
+    /// steps over the current instruction, running a backward jump's loop
+    /// to completion
+    Next,
+    /// steps a single instruction
+    StepIn,
+    /// a pause arrived while the program wasn't running
+    Paused,
+    Disconnect
+}
+
+/// the address a `memoryReference` and optional `offset` point at
+fn memory_reference(args: &Value) -> Result<usize, String> {
+    let text = args["memoryReference"].as_str().unwrap_or("");
+    let base = match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse::<usize>()
+    }.map_err(|_| format!("invalid memory reference {}", text))?;
+    let offset = args["offset"].as_i64().unwrap_or(0);
+    base.checked_add_signed(offset as isize).ok_or(format!("invalid memory reference {}{:+}", text, offset))
+}
+
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut ret = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => ret.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => ret.push('=')
+            }
+        }
+    }
+    ret
+}
+
+fn base64_decode(text: &str) -> Result<Vec<u8>, String> {
+    let mut ret = Vec::new();
+    let (mut n, mut bits) = (0u32, 0);
+    for c in text.bytes().filter(|c| *c != b'=' && !c.is_ascii_whitespace()) {
+        let v = BASE64.iter().position(|b| *b == c).ok_or(format!("invalid base64 character {}", c as char))?;
+        n = n << 6 | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            ret.push((n >> bits) as u8);
+        }
+    }
+    Ok(ret)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_round_trips() {
+        let data = b"\x00\xffab";
+        for len in 0..=4 {
+            let encoded = base64_encode(&data[..len]);
+            assert_eq!(encoded.len(), len.div_ceil(3) * 4);
+            assert_eq!(base64_decode(&encoded).unwrap(), &data[..len]);
+        }
+    }
+
+    #[test]
+    fn base64_matches_known_encodings() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+        assert!(base64_decode("Zm9v!").is_err());
+    }
+}
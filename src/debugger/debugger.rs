@@ -0,0 +1,338 @@
+use std::fmt::{Display, Formatter};
+
+use crate::disasm::{Disassembler, Disassembly};
+use crate::error::{Access, MemFault};
+use crate::format::Image;
+use crate::processor::cpu::{CPU, Effect, MachineConfig, RunOutcome, StopHandle};
+use crate::symbols::SymbolTable;
+use super::Expr;
+
+
+/// stops execution when `pc` reaches `addr` and `condition` (if any) holds
+#[derive(Clone, Debug)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub addr: usize,
+    pub condition: Option<Expr>,
+    /// how many times the breakpoint has stopped the program
+    pub hits: u64
+}
+
+/// stops execution after an instruction writes to `addr..addr+len`
+#[derive(Clone, Debug)]
+pub struct Watchpoint {
+    pub id: usize,
+    pub addr: usize,
+    pub len: usize
+}
+
+
+/// why the debugger handed control back
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StopReason {
+    /// a step or step-over finished
+    Step,
+    /// the breakpoint with this id was hit
+    Breakpoint(usize),
+    /// an instruction wrote to a watched range. `addr`, `old` and `new`
+    /// describe the whole write
+    Watchpoint { id: usize, addr: usize, old: Vec<u8>, new: Vec<u8> },
+    /// the program stopped by itself: it halted, faulted or executed `brk`
+    Program(RunOutcome),
+    /// a `StopHandle` interrupted execution
+    Interrupted
+}
+
+impl Display for StopReason {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            StopReason::Step => write!(f, "step finished"),
+            StopReason::Breakpoint(id) => write!(f, "breakpoint {}", id),
+            StopReason::Watchpoint { id, addr, old, new } =>
+                write!(f, "watchpoint {}: 0x{:x} changed from {:02x?} to {:02x?}", id, addr, old, new),
+            StopReason::Program(outcome) => write!(f, "program {}", outcome),
+            StopReason::Interrupted => write!(f, "interrupted")
+        }
+    }
+}
+
+
+/// drives a CPU on behalf of a debugger front end, keeping track of
+/// breakpoints and watchpoints
+pub struct Debugger {
+    pub cpu: CPU,
+    pub symbols: SymbolTable,
+    config: MachineConfig,
+    image: Image,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    next_id: usize,
+    finished: Option<RunOutcome>,
+    /// where the program last stopped, so resuming from there doesn't 
+    /// report the breakpoint it's already sitting on
+    last_stop_pc: Option<usize>,
+    stop_handle: StopHandle,
+    disassembler: Disassembler
+}
+
+impl Debugger {
+    /// builds a CPU for `config` and loads `image` into it, failing if the
+    /// image doesn't fit in RAM
+    pub fn new(config: MachineConfig, image: Image, symbols: SymbolTable) -> Result<Self, MemFault> {
+        let mut cpu = CPU::new(&config);
+        cpu.load(&image)?;
+        Ok(Debugger {
+            stop_handle: cpu.stop_handle(),
+            cpu,
+            symbols,
+            config,
+            image,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            next_id: 1,
+            finished: None,
+            last_stop_pc: None,
+            disassembler: Disassembler::new()
+        })
+    }
+
+    /// throws away the machine state and reloads the program, keeping
+    /// breakpoints, watchpoints and the console
+    pub fn restart(&mut self) {
+        let mut cpu = CPU::new(&self.config);
+        cpu.load(&self.image).expect("the image fit when the debugger was created");
+        cpu.set_console(self.cpu.set_console(Box::new(std::io::sink())));
+        cpu.set_console_input(self.cpu.set_console_input(None));
+        self.cpu = cpu;
+        self.stop_handle = self.cpu.stop_handle();
+        self.finished = None;
+        self.last_stop_pc = None;
+    }
+
+    /// a handle that interrupts `step_over` and `cont` from another thread.
+    /// Handles are invalidated by `restart`
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop_handle.clone()
+    }
+
+    /// how the program ended, once it has halted or faulted
+    pub fn finished(&self) -> Option<&RunOutcome> {
+        self.finished.as_ref()
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// adds a breakpoint, returning its id
+    pub fn add_breakpoint(&mut self, addr: usize, condition: Option<Expr>) -> usize {
+        let id = self.take_id();
+        self.breakpoints.push(Breakpoint { id, addr, condition, hits: 0 });
+        id
+    }
+
+    /// adds a write watchpoint, returning its id
+    pub fn add_watchpoint(&mut self, addr: usize, len: usize) -> usize {
+        let id = self.take_id();
+        self.watchpoints.push(Watchpoint { id, addr, len: len.max(1) });
+        id
+    }
+
+    /// removes the breakpoint or watchpoint with this id
+    pub fn remove(&mut self, id: usize) -> bool {
+        let before = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|b| b.id != id);
+        self.watchpoints.retain(|w| w.id != id);
+        before != self.breakpoints.len() + self.watchpoints.len()
+    }
+
+    /// removes every breakpoint at `addr`, returning whether there were any
+    pub fn remove_breakpoints_at(&mut self, addr: usize) -> bool {
+        let before = self.breakpoints.len();
+        self.breakpoints.retain(|b| b.addr != addr);
+        before != self.breakpoints.len()
+    }
+
+    /// removes every watchpoint covering exactly `addr..addr+len`, returning
+    /// whether there were any
+    pub fn remove_watchpoints_at(&mut self, addr: usize, len: usize) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|w| w.addr != addr || w.len != len.max(1));
+        before != self.watchpoints.len()
+    }
+
+    fn take_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    /// executes a single instruction
+    pub fn step(&mut self) -> StopReason {
+        self.resume(true, None)
+    }
+
+    /// executes until the instruction after the current one is reached, so
+    /// backward jumps run their loop to completion
+    pub fn step_over(&mut self) -> StopReason {
+        let next = self.pc_after_current();
+        self.resume(false, Some(next))
+    }
+
+    /// executes until a breakpoint, watchpoint or the program stops
+    pub fn cont(&mut self) -> StopReason {
+        self.resume(false, None)
+    }
+
+    /// the address of the instruction after the one at `pc`
+    fn pc_after_current(&self) -> usize {
+        let pc = self.cpu.pc();
+        let bytes = self.read_available(pc, 8);
+        pc + self.disassembler.decode(&bytes, pc).size().max(1)
+    }
+
+    /// records that the front end has reported the program stopped at the
+    /// current pc, as on entry, so resuming runs past a breakpoint there
+    pub fn mark_stopped(&mut self) {
+        self.last_stop_pc = Some(self.cpu.pc());
+    }
+
+    fn resume(&mut self, single: bool, until: Option<usize>) -> StopReason {
+        let reason = self.run_to_stop(single, until);
+        self.mark_stopped();
+        reason
+    }
+
+    fn run_to_stop(&mut self, single: bool, until: Option<usize>) -> StopReason {
+        if let Some(outcome) = &self.finished {
+            return StopReason::Program(outcome.clone());
+        }
+
+        // a breakpoint where the program starts, or where it was moved to,
+        // stops it before anything runs
+        let pc = self.cpu.pc();
+        if !single && self.last_stop_pc != Some(pc) {
+            if let Some(id) = self.breakpoint_hit(pc) {
+                return StopReason::Breakpoint(id);
+            }
+        }
+
+        loop {
+            if self.stop_handle.take() {
+                return StopReason::Interrupted;
+            }
+
+            let step = self.cpu.step();
+            match step.outcome {
+                Some(outcome @ (RunOutcome::Halted(_) | RunOutcome::Fault(_))) => {
+                    self.finished = Some(outcome.clone());
+                    return StopReason::Program(outcome);
+                },
+                Some(outcome) => return StopReason::Program(outcome),
+                None => ()
+            }
+
+            for effect in step.effects.iter() {
+                if let Effect::MemWrite { addr, old, new } = effect {
+                    let hit = self.watchpoints.iter()
+                        .find(|w| *addr < w.addr + w.len && w.addr < *addr + new.len());
+                    if let Some(w) = hit {
+                        return StopReason::Watchpoint { id: w.id, addr: *addr, old: old.clone(), new: new.clone() };
+                    }
+                }
+            }
+
+            let pc = self.cpu.pc();
+            if single || until == Some(pc) {
+                return StopReason::Step;
+            }
+            if let Some(id) = self.breakpoint_hit(pc) {
+                return StopReason::Breakpoint(id);
+            }
+        }
+    }
+
+    /// finds a breakpoint at `pc` whose condition holds, counting the hit. A
+    /// condition that fails to evaluate stops the program so the user notices
+    fn breakpoint_hit(&mut self, pc: usize) -> Option<usize> {
+        let (cpu, symbols) = (&self.cpu, &self.symbols);
+        let bp = self.breakpoints.iter_mut().find(|b| b.addr == pc && match &b.condition {
+            Some(c) => c.eval(cpu, symbols).map(|v| v != 0).unwrap_or(true),
+            None => true
+        })?;
+        bp.hits += 1;
+        Some(bp.id)
+    }
+
+    /// reads `len` bytes of memory at `addr`. Fails if any can't be read,
+    /// including when the range runs past the top of the address space
+    pub fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, MemFault> {
+        let end = addr.checked_add(len).ok_or(MemFault::Unmapped { addr: usize::MAX, access: Access::Read })?;
+        (addr..end).map(|a| self.cpu.memory.read_u8(a)).collect()
+    }
+
+    /// reads up to `len` bytes at `addr`, stopping at the first that can't be read
+    fn read_available(&self, addr: usize, len: usize) -> Vec<u8> {
+        (addr..addr.saturating_add(len)).map_while(|a| self.cpu.memory.read_u8(a).ok()).collect()
+    }
+
+    /// writes `bytes` to memory at `addr`, even to pages the program can't
+    /// write. Fails if they run past the end of RAM
+    pub fn write_memory(&mut self, addr: usize, bytes: &[u8]) -> Result<(), MemFault> {
+        self.cpu.memory.patch_bytes(addr, bytes)
+    }
+
+    /// disassembles `count` instructions starting at `addr`
+    pub fn disassemble(&self, addr: usize, count: usize) -> Vec<Disassembly> {
+        let mut ret = Vec::new();
+        let mut addr = addr;
+        for _ in 0..count {
+            let bytes = self.read_available(addr, 8);
+            if bytes.is_empty() {
+                break;
+            }
+            let line = self.disassembler.decode(&bytes, addr);
+            addr += line.size();
+            ret.push(line);
+        }
+        ret
+    }
+
+    /// disassembles up to `before` instructions before `addr` and `after`
+    /// from it. Instructions are variable length, so decoding starts at the
+    /// closest label (or address 0) and works forwards
+    pub fn disassemble_around(&self, addr: usize, before: usize, after: usize) -> Vec<Disassembly> {
+        let start = match self.symbols.describe(addr as u32) {
+            Some((_, off)) => addr - off as usize,
+            None => 0
+        };
+        // don't decode huge unlabelled stretches just to show a few lines
+        let start = match addr - start > 0x100 {
+            true => addr,
+            false => start
+        };
+
+        let mut lines = Vec::new();
+        let mut a = start;
+        while a < addr {
+            let bytes = self.read_available(a, 8);
+            if bytes.is_empty() {
+                break;
+            }
+            let line = self.disassembler.decode(&bytes, a);
+            a += line.size();
+            lines.push(line);
+        }
+        // decoding from the label didn't land on `addr`, so just start there
+        if a != addr {
+            lines.clear();
+        }
+        let skip = lines.len().saturating_sub(before);
+        let mut ret: Vec<Disassembly> = lines.into_iter().skip(skip).collect();
+        ret.extend(self.disassemble(addr, after));
+        ret
+    }
+}
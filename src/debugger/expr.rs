@@ -0,0 +1,307 @@
+use std::fmt::{Display, Formatter};
+
+use crate::processor::cpu::CPU;
+use crate::processor::cpu::cpu::{REG_FP, REG_LR};
+use crate::symbols::SymbolTable;
+
+
+/// something an expression can read from the machine
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Operand {
+    Num(u32),
+    Reg(u8),
+    Pc,
+    Sp,
+    Fl,
+    Label(String)
+}
+
+/// binary operators, lowest precedence first
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub
+}
+
+/// a parsed debugger expression, such as `r0 == 0x41` or `.buf + 4`.
+/// Comparisons produce 1 or 0, and `&&`/`||` treat non-zero as true
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Expr {
+    node: Node,
+    text: String
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Node {
+    Operand(Operand),
+    Binary(Op, Box<Node>, Box<Node>)
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl Expr {
+    /// parses an expression
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens: &tokens, pos: 0 };
+        let node = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(Expr { node, text: text.trim().to_string() }),
+            Some(t) => Err(format!("unexpected {} in {}", t, text.trim()))
+        }
+    }
+
+    /// evaluates the expression against the current machine state
+    pub fn eval(&self, cpu: &CPU, symbols: &SymbolTable) -> Result<u32, String> {
+        eval(&self.node, cpu, symbols)
+    }
+}
+
+fn eval(node: &Node, cpu: &CPU, symbols: &SymbolTable) -> Result<u32, String> {
+    match node {
+        Node::Operand(op) => match op {
+            Operand::Num(n) => Ok(*n),
+            Operand::Reg(r) => cpu.get_reg(*r).map_err(|e| e.to_string()),
+            Operand::Pc => Ok(cpu.pc() as u32),
+            Operand::Sp => Ok(cpu.sp() as u32),
+            Operand::Fl => Ok(cpu.flags() as u32),
+            Operand::Label(l) => symbols.addr_of(l).ok_or(format!("unknown label {}", l))
+        },
+        Node::Binary(op, a, b) => {
+            let a = eval(a, cpu, symbols)?;
+            // short circuit so `r0 != 0 && ...` can guard the right hand side
+            match (op, a) {
+                (Op::And, 0) => return Ok(0),
+                (Op::Or, a) if a != 0 => return Ok(1),
+                _ => ()
+            }
+            let b = eval(b, cpu, symbols)?;
+            Ok(match op {
+                Op::Or | Op::And => (b != 0) as u32,
+                Op::Eq => (a == b) as u32,
+                Op::Ne => (a != b) as u32,
+                Op::Lt => (a < b) as u32,
+                Op::Le => (a <= b) as u32,
+                Op::Gt => (a > b) as u32,
+                Op::Ge => (a >= b) as u32,
+                Op::Add => a.wrapping_add(b),
+                Op::Sub => a.wrapping_sub(b)
+            })
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Token {
+    Operand(Operand),
+    Op(Op),
+    Open,
+    Close
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Token::Operand(o) => write!(f, "{:?}", o),
+            Token::Op(o) => write!(f, "{:?}", o),
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")")
+        }
+    }
+}
+
+/// splits an expression into tokens
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let (token, len) = match (c, next) {
+            (' ' | '\t', _) => { i += 1; continue; },
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            ('|', Some('|')) => (Token::Op(Op::Or), 2),
+            ('&', Some('&')) => (Token::Op(Op::And), 2),
+            ('=', Some('=')) => (Token::Op(Op::Eq), 2),
+            ('!', Some('=')) => (Token::Op(Op::Ne), 2),
+            ('<', Some('=')) => (Token::Op(Op::Le), 2),
+            ('>', Some('=')) => (Token::Op(Op::Ge), 2),
+            ('<', _) => (Token::Op(Op::Lt), 1),
+            ('>', _) => (Token::Op(Op::Gt), 1),
+            ('+', _) => (Token::Op(Op::Add), 1),
+            ('-', _) => (Token::Op(Op::Sub), 1),
+            (c, _) if c.is_alphanumeric() || c == '.' || c == '_' => {
+                let len = chars[i..].iter().take_while(|c| c.is_alphanumeric() || **c == '.' || **c == '_').count();
+                let word: String = chars[i..i + len].iter().collect();
+                (Token::Operand(operand(&word)?), len)
+            },
+            (c, _) => return Err(format!("unexpected character {} in {}", c, text.trim()))
+        };
+        tokens.push(token);
+        i += len;
+    }
+    Ok(tokens)
+}
+
+/// parses a single word into an operand
+fn operand(word: &str) -> Result<Operand, String> {
+    let lower = word.to_lowercase();
+    Ok(match lower.as_str() {
+        "pc" => Operand::Pc,
+        "sp" => Operand::Sp,
+        "fl" => Operand::Fl,
+        "fp" => Operand::Reg(REG_FP),
+        "lr" => Operand::Reg(REG_LR),
+        _ if word.starts_with('.') => Operand::Label(word.to_string()),
+        _ => {
+            if let Some(n) = lower.strip_prefix('r').and_then(|r| r.parse::<u8>().ok()) {
+                return Ok(Operand::Reg(n));
+            }
+            let n = match lower.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => lower.parse::<u32>()
+            };
+            Operand::Num(n.map_err(|_| format!("invalid value {}", word))?)
+        }
+    })
+}
+
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize
+}
+
+impl Parser<'_> {
+    fn or(&mut self) -> Result<Node, String> {
+        self.binary(&[Op::Or], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        self.binary(&[Op::And], Parser::compare)
+    }
+
+    fn compare(&mut self) -> Result<Node, String> {
+        self.binary(&[Op::Eq, Op::Ne, Op::Lt, Op::Le, Op::Gt, Op::Ge], Parser::sum)
+    }
+
+    fn sum(&mut self) -> Result<Node, String> {
+        self.binary(&[Op::Add, Op::Sub], Parser::atom)
+    }
+
+    /// parses a left-associative chain of `ops`
+    fn binary(&mut self, ops: &[Op], next: fn(&mut Self) -> Result<Node, String>) -> Result<Node, String> {
+        let mut lhs = next(self)?;
+        while let Some(Token::Op(op)) = self.tokens.get(self.pos) {
+            if !ops.contains(op) {
+                break;
+            }
+            self.pos += 1;
+            lhs = Node::Binary(*op, Box::new(lhs), Box::new(next(self)?));
+        }
+        Ok(lhs)
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Operand(o)) => Ok(Node::Operand(o)),
+            Some(Token::Open) => {
+                let node = self.or()?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::Close) => {
+                        self.pos += 1;
+                        Ok(node)
+                    },
+                    _ => Err("expected )".to_string())
+                }
+            },
+            Some(t) => Err(format!("unexpected {}", t)),
+            None => Err("unexpected end of expression".to_string())
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+    use crate::processor::cpu::MachineConfig;
+
+    fn eval_with(text: &str, cpu: &CPU) -> Result<u32, String> {
+        let symbols = SymbolTable::from_labels(&HashMap::from([(".buf".to_string(), 0x2000)]));
+        Expr::parse(text)?.eval(cpu, &symbols)
+    }
+
+    fn eval(text: &str) -> Result<u32, String> {
+        eval_with(text, &CPU::new(&MachineConfig::default()))
+    }
+
+    #[test]
+    fn operators_bind_by_precedence() {
+        assert_eq!(eval("2 + 3 == 5"), Ok(1));
+        assert_eq!(eval("1 == 1 && 0 == 1"), Ok(0));
+        assert_eq!(eval("1 || 0 && 0"), Ok(1));
+        assert_eq!(eval("(1 || 0) && 0"), Ok(0));
+        assert_eq!(eval("10 - 3 - 2"), Ok(5));
+        assert_eq!(eval("10 - (3 - 2)"), Ok(9));
+    }
+
+    #[test]
+    fn arithmetic_wraps_and_compares_unsigned() {
+        assert_eq!(eval("0 - 1"), Ok(0xffffffff));
+        assert_eq!(eval("0xffffffff + 2"), Ok(1));
+        assert_eq!(eval("0 - 1 > 1"), Ok(1));
+        assert_eq!(eval("3 <= 3 && 3 >= 3 && 2 < 3 && 3 != 2"), Ok(1));
+        assert_eq!(eval("7 && 9"), Ok(1));
+    }
+
+    #[test]
+    fn registers_and_labels_are_read() {
+        let mut cpu = CPU::new(&MachineConfig::default());
+        cpu.set_reg(3, 0x41).unwrap();
+        cpu.set_reg(REG_FP, 0x100).unwrap();
+        cpu.set_reg(REG_LR, 0x200).unwrap();
+        cpu.set_pc(0x30);
+        cpu.set_sp(0x40);
+        cpu.set_flags(0x5);
+        assert_eq!(eval_with("R3 == 0x41", &cpu), Ok(1));
+        assert_eq!(eval_with("fp + lr", &cpu), Ok(0x300));
+        assert_eq!(eval_with("pc + sp + fl", &cpu), Ok(0x75));
+        assert_eq!(eval_with(".buf + 4", &cpu), Ok(0x2004));
+    }
+
+    #[test]
+    fn and_and_or_short_circuit() {
+        assert_eq!(eval("r0 != 0 && .missing"), Ok(0));
+        assert_eq!(eval("r0 == 0 || .missing"), Ok(1));
+    }
+
+    #[test]
+    fn bad_expressions_are_errors() {
+        assert_eq!(eval("1 +"), Err("unexpected end of expression".to_string()));
+        assert_eq!(eval("(1"), Err("expected )".to_string()));
+        assert_eq!(eval("1 2"), Err("unexpected Num(2) in 1 2".to_string()));
+        assert_eq!(eval("1 $ 2"), Err("unexpected character $ in 1 $ 2".to_string()));
+        assert_eq!(eval("0xzz"), Err("invalid value 0xzz".to_string()));
+        assert_eq!(eval(".missing"), Err("unknown label .missing".to_string()));
+        assert!(eval("r16").is_err());
+    }
+}
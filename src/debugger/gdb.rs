@@ -0,0 +1,532 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver};
+
+use crate::error::CpuFault;
+use crate::processor::cpu::RunOutcome;
+use crate::processor::cpu::cpu::NUM_REGS;
+use crate::{debug, info, warn};
+use super::{Debugger, StopReason};
+
+
+/// registers in the order gdb sees them: r0-r15, then pc, sp and fl
+const NUM_GDB_REGS: usize = NUM_REGS + 3;
+
+/// signal numbers used in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+/// the most bytes one `m` packet reads, so the hex reply fits in the
+/// packet size advertised to gdb
+const MAX_MEMORY_READ: usize = (4000 - 4) / 2;
+
+/// how a gdb session ended
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SessionEnd {
+    /// gdb detached, leaving the program to run on
+    Detached,
+    /// gdb killed the program
+    Killed,
+    /// the connection dropped
+    Disconnected
+}
+
+/// the target description sent to gdb
+fn target_xml() -> String {
+    let mut regs = String::new();
+    for i in 0..NUM_REGS {
+        regs += &format!("    <reg name=\"r{}\" bitsize=\"32\" type=\"uint32\" regnum=\"{}\"/>\n", i, i);
+    }
+    format!(concat!(
+        "<?xml version=\"1.0\"?>\n",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n",
+        "<target version=\"1.0\">\n",
+        "  <feature name=\"org.deadbolt.core\">\n",
+        "{}",
+        "    <reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/>\n",
+        "    <reg name=\"sp\" bitsize=\"32\" type=\"data_ptr\" regnum=\"{}\"/>\n",
+        "    <reg name=\"fl\" bitsize=\"32\" type=\"uint32\" regnum=\"{}\"/>\n",
+        "  </feature>\n",
+        "</target>\n"), regs, NUM_REGS, NUM_REGS + 1, NUM_REGS + 2)
+}
+
+
+/// accepts one gdb connection on `listener` and serves it until gdb
+/// detaches, kills the program or disconnects
+pub fn serve(dbg: &mut Debugger, listener: TcpListener) -> std::io::Result<SessionEnd> {
+    let (stream, peer) = listener.accept()?;
+    info!("gdb connected from {}", peer);
+    stream.set_nodelay(true)?;
+
+    let packets = spawn_reader(stream.try_clone()?, dbg);
+    let mut session = Session { stream, ack: true, dbg };
+    session.run(packets)
+}
+
+/// reads packets off the connection on another thread. `^C` interrupts the
+/// debugger immediately; everything else is handed to the session
+fn spawn_reader(mut stream: TcpStream, dbg: &Debugger) -> Receiver<Option<Vec<u8>>> {
+    let (tx, rx) = channel();
+    let stop = dbg.stop_handle();
+
+    std::thread::spawn(move || {
+        let mut packet: Option<Vec<u8>> = None;
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = match stream.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n
+            };
+            for b in buf[..n].iter() {
+                match (&mut packet, *b) {
+                    (None, 0x03) => stop.stop(),
+                    (None, b'$') => packet = Some(Vec::new()),
+                    // acks, and anything else outside a packet
+                    (None, _) => (),
+                    (Some(p), b) => {
+                        p.push(b);
+                        // packets end with `#` and a two digit checksum
+                        if p.len() >= 3 && p[p.len() - 3] == b'#' {
+                            let p = packet.take().unwrap();
+                            if tx.send(Some(p)).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let _ = tx.send(None);
+    });
+
+    rx
+}
+
+
+struct Session<'a> {
+    stream: TcpStream,
+    ack: bool,
+    dbg: &'a mut Debugger
+}
+
+impl Session<'_> {
+    fn run(&mut self, packets: Receiver<Option<Vec<u8>>>) -> std::io::Result<SessionEnd> {
+        while let Ok(Some(raw)) = packets.recv() {
+            let data = match (checked(&raw), self.ack) {
+                (Some(data), true) => {
+                    self.stream.write_all(b"+")?;
+                    data
+                },
+                (None, true) => {
+                    self.stream.write_all(b"-")?;
+                    continue;
+                },
+                // without acks gdb won't resend, so take the packet as it is
+                (_, false) => &raw[..raw.len() - 3]
+            };
+
+            let data = unescape(data);
+            let packet = String::from_utf8_lossy(&data).to_string();
+            debug!("gdb <- {}", packet);
+            match self.handle(&packet) {
+                Ok(Some(end)) => return Ok(end),
+                Ok(None) => (),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(SessionEnd::Disconnected)
+    }
+
+    /// sends a reply packet
+    fn send(&mut self, data: &str) -> std::io::Result<()> {
+        debug!("gdb -> {}", data);
+        let sum = data.bytes().fold(0u8, |a, b| a.wrapping_add(b));
+        self.stream.write_all(format!("${}#{:02x}", data, sum).as_bytes())
+    }
+
+    /// handles a single packet, returning how the session ended if it did
+    fn handle(&mut self, packet: &str) -> std::io::Result<Option<SessionEnd>> {
+        let (cmd, args) = packet.split_at(packet.len().min(1));
+        match cmd {
+            "?" => {
+                // gdb now thinks the program is stopped here, and expects
+                // continuing to run past a breakpoint at this address
+                self.dbg.mark_stopped();
+                let reply = self.last_stop();
+                self.send(&reply)?;
+            },
+            "g" => {
+                let regs: String = (0..NUM_GDB_REGS).map(|r| format!("{:08x}", self.reg(r))).collect();
+                self.send(&regs)?;
+            },
+            "G" => {
+                let values: Vec<Option<u32>> = (0..NUM_GDB_REGS)
+                    .map(|r| args.get(r * 8..r * 8 + 8).and_then(|v| u32::from_str_radix(v, 16).ok()))
+                    .collect();
+                if values.iter().any(|v| v.is_none()) {
+                    return self.send("E01").map(|_| None);
+                }
+                for (r, v) in values.into_iter().enumerate() {
+                    self.set_reg(r, v.unwrap());
+                }
+                self.send("OK")?;
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(r) if r < NUM_GDB_REGS => {
+                    let v = self.reg(r);
+                    self.send(&format!("{:08x}", v))?;
+                },
+                _ => self.send("E01")?
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(r, v)| Some((
+                    usize::from_str_radix(r, 16).ok()?,
+                    u32::from_str_radix(v, 16).ok()?
+                )));
+                match parsed {
+                    Some((r, v)) if r < NUM_GDB_REGS => {
+                        self.set_reg(r, v);
+                        self.send("OK")?;
+                    },
+                    _ => self.send("E01")?
+                }
+            },
+            "m" => {
+                let reply = match parse_addr_len(args) {
+                    Some((addr, len)) if addr.checked_add(len).is_some() => match self.dbg.read_memory(addr, len.min(MAX_MEMORY_READ)) {
+                        Ok(bytes) => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+                        Err(_) => "E01".to_string()
+                    },
+                    _ => "E01".to_string()
+                };
+                self.send(&reply)?;
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(al, data)| Some((parse_addr_len(al)?, decode_hex(data)?)));
+                match parsed {
+                    Some(((addr, len), bytes)) if bytes.len() == len => match self.dbg.write_memory(addr, &bytes) {
+                        Ok(()) => self.send("OK")?,
+                        Err(_) => self.send("E01")?
+                    },
+                    _ => self.send("E01")?
+                }
+            },
+            "Z" | "z" => {
+                let reply = self.breakpoint(cmd == "Z", args);
+                self.send(reply)?;
+            },
+            "s" | "c" => {
+                // resuming at a different address isn't supported
+                if !args.is_empty() {
+                    return self.send("E01").map(|_| None);
+                }
+                let reason = match cmd {
+                    "s" => self.dbg.step(),
+                    _ => self.dbg.cont()
+                };
+                let reply = stop_reply(&reason);
+                self.send(&reply)?;
+            },
+            "D" => {
+                self.send("OK")?;
+                return Ok(Some(SessionEnd::Detached));
+            },
+            "k" => return Ok(Some(SessionEnd::Killed)),
+            "H" | "T" => self.send("OK")?,
+            "q" | "Q" => self.query(packet)?,
+            // anything else is unsupported
+            _ => self.send("")?
+        }
+        Ok(None)
+    }
+
+    /// handles general query and set packets
+    fn query(&mut self, packet: &str) -> std::io::Result<()> {
+        if packet.starts_with("qSupported") {
+            return self.send("PacketSize=4000;qXfer:features:read+;swbreak+;QStartNoAckMode+");
+        }
+        if packet == "QStartNoAckMode" {
+            self.send("OK")?;
+            self.ack = false;
+            return Ok(());
+        }
+        if let Some(rest) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let reply = match parse_addr_len(rest) {
+                Some((off, len)) => {
+                    let xml = target_xml();
+                    let chunk: String = xml.chars().skip(off).take(len).collect();
+                    match off + chunk.len() >= xml.len() {
+                        true => format!("l{}", chunk),
+                        false => format!("m{}", chunk)
+                    }
+                },
+                None => "E01".to_string()
+            };
+            return self.send(&reply);
+        }
+        match packet {
+            "qAttached" => self.send("1"),
+            "qC" => self.send("QC1"),
+            "qfThreadInfo" => self.send("m1"),
+            "qsThreadInfo" => self.send("l"),
+            _ => self.send("")
+        }
+    }
+
+    /// inserts or removes a software breakpoint (`Z0`) or write watchpoint (`Z2`)
+    fn breakpoint(&mut self, insert: bool, args: &str) -> &'static str {
+        let mut parts = args.split(',');
+        let kind = parts.next();
+        let addr = parts.next().and_then(|a| usize::from_str_radix(a, 16).ok());
+        let len = parts.next().and_then(|a| usize::from_str_radix(a, 16).ok()).unwrap_or(1);
+        let addr = match addr {
+            Some(a) => a,
+            None => return "E01"
+        };
+
+        match (kind, insert) {
+            (Some("0"), true) => {
+                self.dbg.add_breakpoint(addr, None);
+                "OK"
+            },
+            (Some("0"), false) => {
+                self.dbg.remove_breakpoints_at(addr);
+                "OK"
+            },
+            (Some("2"), true) => {
+                self.dbg.add_watchpoint(addr, len);
+                "OK"
+            },
+            (Some("2"), false) => {
+                self.dbg.remove_watchpoints_at(addr, len);
+                "OK"
+            },
+            _ => ""
+        }
+    }
+
+    /// the stop reply for the program's current state
+    fn last_stop(&self) -> String {
+        match self.dbg.finished() {
+            Some(outcome) => stop_reply(&StopReason::Program(outcome.clone())),
+            None => format!("S{:02x}", SIGTRAP)
+        }
+    }
+
+    fn reg(&self, r: usize) -> u32 {
+        match r {
+            r if r < NUM_REGS => self.dbg.cpu.regs()[r],
+            r if r == NUM_REGS => self.dbg.cpu.pc() as u32,
+            r if r == NUM_REGS + 1 => self.dbg.cpu.sp() as u32,
+            _ => self.dbg.cpu.flags() as u32
+        }
+    }
+
+    fn set_reg(&mut self, r: usize, v: u32) {
+        match r {
+            r if r < NUM_REGS => {
+                if let Err(e) = self.dbg.cpu.set_reg(r as u8, v) {
+                    warn!("gdb failed to set r{}: {}", r, e);
+                }
+            },
+            r if r == NUM_REGS => self.dbg.cpu.set_pc(v as usize),
+            r if r == NUM_REGS + 1 => self.dbg.cpu.set_sp(v as usize),
+            _ => self.dbg.cpu.set_flags(v as u8)
+        }
+    }
+}
+
+
+/// the stop reply packet for a stop reason
+fn stop_reply(reason: &StopReason) -> String {
+    match reason {
+        StopReason::Step => format!("T{:02x}thread:1;", SIGTRAP),
+        StopReason::Breakpoint(_) => format!("T{:02x}thread:1;swbreak:;", SIGTRAP),
+        StopReason::Watchpoint { addr, .. } => format!("T{:02x}thread:1;watch:{:x};", SIGTRAP, addr),
+        StopReason::Interrupted => format!("T{:02x}thread:1;", SIGINT),
+        StopReason::Program(outcome) => match outcome {
+            RunOutcome::Halted(code) => format!("W{:02x}", code & 0xff),
+            RunOutcome::Fault(fault) => {
+                let signal = match fault {
+                    CpuFault::IllegalOpcode { .. } | CpuFault::IllegalRegister { .. } => SIGILL,
+                    CpuFault::DivideByZero { .. } => SIGFPE,
+                    CpuFault::Memory { .. } => SIGSEGV,
+                    _ => SIGTRAP
+                };
+                format!("T{:02x}thread:1;", signal)
+            },
+            _ => format!("T{:02x}thread:1;", SIGTRAP)
+        }
+    }
+}
+
+/// splits the `#xx` checksum off a packet read by `spawn_reader`, returning
+/// the data if the checksum matches it
+fn checked(raw: &[u8]) -> Option<&[u8]> {
+    let (data, sum) = raw.split_at(raw.len() - 3);
+    let expected = u8::from_str_radix(std::str::from_utf8(&sum[1..]).ok()?, 16).ok()?;
+    let actual = data.iter().fold(0u8, |a, b| a.wrapping_add(*b));
+    (expected == actual).then_some(data)
+}
+
+/// parses `addr,len` in hex
+fn parse_addr_len(text: &str) -> Option<(usize, usize)> {
+    let (a, l) = text.split_once(',')?;
+    Some((usize::from_str_radix(a, 16).ok()?, usize::from_str_radix(l, 16).ok()?))
+}
+
+/// decodes a string of hex byte pairs
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// undoes the `}` escaping gdb applies to `#`, `$`, `}` and `*` in packets
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(data.len());
+    let mut escaped = false;
+    for b in data.iter() {
+        match (escaped, *b) {
+            (false, b'}') => escaped = true,
+            (true, b) => {
+                ret.push(b ^ 0x20);
+                escaped = false;
+            },
+            (false, b) => ret.push(b)
+        }
+    }
+    ret
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread::JoinHandle;
+    use super::*;
+    use crate::compile::assemble;
+    use crate::processor::cpu::MachineConfig;
+    use crate::symbols::SymbolTable;
+
+    /// a client connected to a session serving `source` on another thread
+    struct Client {
+        stream: TcpStream,
+        server: JoinHandle<std::io::Result<SessionEnd>>
+    }
+
+    impl Client {
+        fn new(source: &str) -> Self {
+            let image = assemble(source).unwrap().image();
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = std::thread::spawn(move || {
+                let mut dbg = Debugger::new(MachineConfig::default(), image, SymbolTable::default()).unwrap();
+                serve(&mut dbg, listener)
+            });
+            Client { stream: TcpStream::connect(addr).unwrap(), server }
+        }
+
+        /// sends raw bytes and reads back the ack, and the reply packet's
+        /// data if there is one
+        fn exchange(&mut self, raw: &[u8], reply: bool) -> (u8, String) {
+            self.stream.write_all(raw).unwrap();
+            let mut ack = [0u8];
+            self.stream.read_exact(&mut ack).unwrap();
+            let mut data = Vec::new();
+            if reply {
+                let mut b = [0u8];
+                while b[0] != b'#' {
+                    self.stream.read_exact(&mut b).unwrap();
+                    data.push(b[0]);
+                }
+                let mut sum = [0u8; 2];
+                self.stream.read_exact(&mut sum).unwrap();
+                // drop the `$` and `#`
+                data = data[1..data.len() - 1].to_vec();
+                let actual = data.iter().fold(0u8, |a, b| a.wrapping_add(*b));
+                assert_eq!(format!("{:02x}", actual), String::from_utf8_lossy(&sum));
+            }
+            (ack[0], String::from_utf8(data).unwrap())
+        }
+
+        /// ends the session, which gets no reply
+        fn kill(mut self) {
+            self.stream.write_all(b"$k#6b").unwrap();
+            assert!(matches!(self.server.join().unwrap(), Ok(SessionEnd::Killed)));
+        }
+
+        /// sends a packet with a good checksum and returns the reply
+        fn request(&mut self, data: &str) -> String {
+            let sum = data.bytes().fold(0u8, |a, b| a.wrapping_add(b));
+            let (ack, reply) = self.exchange(format!("${}#{:02x}", data, sum).as_bytes(), true);
+            assert_eq!(ack, b'+');
+            reply
+        }
+    }
+
+    const PROGRAM: &str = "section .text
+        movi r0, 7
+        hlt";
+
+    #[test]
+    fn packets_with_bad_checksums_are_rejected() {
+        let mut client = Client::new(PROGRAM);
+        assert_eq!(client.exchange(b"$?#00", false), (b'-', String::new()));
+        assert_eq!(client.exchange(b"$?#zz", false), (b'-', String::new()));
+        assert_eq!(client.request("?"), "S05");
+        client.kill();
+    }
+
+    #[test]
+    fn memory_writes_read_back() {
+        let mut client = Client::new(PROGRAM);
+        assert_eq!(client.request("m0,6"), "900000000007");
+        assert_eq!(client.request("M2000,4:deadbeef"), "OK");
+        assert_eq!(client.request("m2000,4"), "deadbeef");
+        // `}` escapes the next byte xored with 0x20, here the `2` of `m2000,4`,
+        // and the checksum covers the escaped bytes
+        let sum = b"m}\x12000,4".iter().fold(0u8, |a, b| a.wrapping_add(*b));
+        let (ack, reply) = client.exchange(format!("$m}}\x12000,4#{:02x}", sum).as_bytes(), true);
+        assert_eq!((ack, reply.as_str()), (b'+', "deadbeef"));
+        assert_eq!(client.request("M2001,2:0102ff"), "E01");
+        assert_eq!(client.request("m2000,3"), "deadbe");
+        assert_eq!(client.request("mffffffffffffffff,10"), "E01");
+        client.kill();
+    }
+
+    #[test]
+    fn stop_replies_report_steps_and_exit_codes() {
+        let mut client = Client::new(PROGRAM);
+        assert_eq!(client.request("s"), "T05thread:1;");
+        assert_eq!(client.request("c"), "W07");
+        assert_eq!(client.request("?"), "W07");
+        client.stream.write_all(b"$D#44").unwrap();
+        assert!(matches!(client.server.join().unwrap(), Ok(SessionEnd::Detached)));
+    }
+
+    #[test]
+    fn stop_reply_encoding() {
+        assert_eq!(stop_reply(&StopReason::Breakpoint(0x10)), "T05thread:1;swbreak:;");
+        assert_eq!(stop_reply(&StopReason::Interrupted), "T02thread:1;");
+        let fault = CpuFault::DivideByZero { pc: 0 };
+        assert_eq!(stop_reply(&StopReason::Program(RunOutcome::Fault(fault))), "T08thread:1;");
+        assert_eq!(stop_reply(&StopReason::Program(RunOutcome::Halted(0x1ff))), "Wff");
+    }
+
+    #[test]
+    fn escapes_and_hex_decode() {
+        assert_eq!(unescape(b"a}\x03b}\x5d}\x04"), b"a#b}$");
+        assert_eq!(checked(b"OK#9a"), Some(&b"OK"[..]));
+        assert_eq!(checked(b"OK#9b"), None);
+        assert_eq!(parse_addr_len("1f,a"), Some((0x1f, 10)));
+        assert_eq!(parse_addr_len("1f"), None);
+        assert_eq!(decode_hex("00ff7f"), Some(vec![0, 0xff, 0x7f]));
+        assert_eq!(decode_hex("0"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("é0"), None);
+    }
+}
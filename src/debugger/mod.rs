@@ -0,0 +1,9 @@
+mod debugger;
+mod expr;
+mod repl;
+pub mod dap;
+pub mod gdb;
+
+pub use debugger::{Breakpoint, Debugger, StopReason, Watchpoint};
+pub use expr::Expr;
+pub use repl::repl;
@@ -0,0 +1,290 @@
+use std::io::{BufRead, Write};
+
+use crate::processor::cpu::cpu::{
+    FLAG_ZERO, FLAG_CARRY, FLAG_GREATER, FLAG_OVERFLOW, FLAG_ECHO, FLAG_NEGATIVE,
+    REG_FP, REG_LR
+};
+use super::{Debugger, Expr, StopReason};
+
+
+/// the most bytes one `x` shows, so a mistyped length doesn't read all of
+/// memory
+const MAX_EXAMINE: usize = 4096;
+
+const HELP: &str = "\
+commands:
+  break|b <addr> [if <cond>]   break at an address or label, optionally only when <cond> holds
+  watch|w <addr> [len]         stop after a write to len bytes (default 1) at addr
+  delete|d <id>                remove a breakpoint or watchpoint
+  info|i breakpoints|b         list breakpoints and watchpoints
+  info|i registers|r           show registers and flags (also `regs`)
+  info|i symbols|s             list labels
+  step|s [n]                   execute n instructions (default 1)
+  next|n                       step over the current instruction
+  continue|c                   run until a breakpoint, watchpoint or the program stops
+  x <addr> [len]               examine len bytes of memory (default 16, at most 4096)
+  write <addr> <byte>...       modify memory
+  set <reg> <value>            modify r0-r15, pc, sp or fl
+  disas|l [addr] [n]           disassemble n instructions around pc, or from addr
+  print|p <expr>               evaluate an expression
+  restart                      reload the program
+  quit|q                       exit the debugger
+addresses and values are expressions: numbers, registers, labels, +, -,
+comparisons, && and ||, e.g. `b .loop if r0 == 0x41`. An empty line repeats
+the last command";
+
+
+/// formats the flag register as letters, e.g. `Z-G---`
+pub(super) fn flag_letters(fl: u8) -> String {
+    [(FLAG_ZERO, 'Z'), (FLAG_CARRY, 'C'), (FLAG_GREATER, 'G'),
+     (FLAG_OVERFLOW, 'O'), (FLAG_ECHO, 'E'), (FLAG_NEGATIVE, 'N')]
+        .iter()
+        .map(|(f, c)| if fl & f != 0 { *c } else { '-' })
+        .collect()
+}
+
+/// formats an address, adding the closest label when there is one
+fn describe(dbg: &Debugger, addr: usize) -> String {
+    match dbg.symbols.describe_str(addr as u32) {
+        Some(l) => format!("0x{:08x} <{}>", addr, l),
+        None => format!("0x{:08x}", addr)
+    }
+}
+
+/// evaluates an address or value argument
+fn value(dbg: &Debugger, text: &str) -> Result<u32, String> {
+    Expr::parse(text)?.eval(&dbg.cpu, &dbg.symbols)
+}
+
+
+/// runs the debugger command loop until `quit` or end of input
+pub fn repl(dbg: &mut Debugger, input: impl BufRead, out: &mut impl Write) -> std::io::Result<()> {
+    writeln!(out, "stopped at {}", describe(dbg, dbg.cpu.pc()))?;
+    print_disassembly(dbg, out, dbg.cpu.pc(), 0, 1)?;
+
+    let mut last = String::new();
+    let mut lines = input.lines();
+    loop {
+        write!(out, "(deadbolt) ")?;
+        out.flush()?;
+        let line = match lines.next() {
+            Some(l) => l?,
+            None => break
+        };
+        let line = match line.trim() {
+            "" => last.clone(),
+            l => l.to_string()
+        };
+        last = line.clone();
+
+        match command(dbg, &line, out) {
+            Ok(true) => break,
+            Ok(false) => (),
+            Err(e) => writeln!(out, "error: {}", e)?
+        }
+    }
+    Ok(())
+}
+
+/// runs a single command, returning whether the debugger should exit
+fn command(dbg: &mut Debugger, line: &str, out: &mut impl Write) -> Result<bool, String> {
+    let io = |e: std::io::Error| e.to_string();
+    let (cmd, args) = match line.split_once(char::is_whitespace) {
+        Some((c, a)) => (c, a.trim()),
+        None => (line, "")
+    };
+    let words: Vec<&str> = args.split_whitespace().collect();
+
+    match cmd {
+        "help" | "h" | "?" => writeln!(out, "{}", HELP).map_err(io)?,
+        "quit" | "q" | "exit" => return Ok(true),
+        "break" | "b" => {
+            let (addr, cond) = match args.split_once(" if ") {
+                Some((a, c)) => (a, Some(Expr::parse(c)?)),
+                None => (args, None)
+            };
+            if addr.is_empty() {
+                return Err("usage: break <addr> [if <cond>]".to_string());
+            }
+            let addr = value(dbg, addr)? as usize;
+            let id = dbg.add_breakpoint(addr, cond);
+            writeln!(out, "breakpoint {} at {}", id, describe(dbg, addr)).map_err(io)?;
+        },
+        "watch" | "w" => {
+            let (addr, len) = match words.as_slice() {
+                [a] => (value(dbg, a)?, 1),
+                [a, l] => (value(dbg, a)?, value(dbg, l)?),
+                _ => return Err("usage: watch <addr> [len]".to_string())
+            };
+            let id = dbg.add_watchpoint(addr as usize, len as usize);
+            writeln!(out, "watchpoint {} on {} bytes at {}", id, len, describe(dbg, addr as usize)).map_err(io)?;
+        },
+        "delete" | "d" => {
+            let id = args.parse::<usize>().map_err(|_| "usage: delete <id>".to_string())?;
+            if !dbg.remove(id) {
+                return Err(format!("no breakpoint or watchpoint {}", id));
+            }
+        },
+        "info" | "i" => match args {
+            "breakpoints" | "break" | "b" | "watchpoints" | "watch" | "w" => print_breakpoints(dbg, out).map_err(io)?,
+            "registers" | "regs" | "r" => print_registers(dbg, out).map_err(io)?,
+            "symbols" | "s" => {
+                for (addr, name) in dbg.symbols.iter() {
+                    writeln!(out, "0x{:08x} {}", addr, name).map_err(io)?;
+                }
+            },
+            _ => return Err("usage: info breakpoints|registers|symbols".to_string())
+        },
+        "regs" | "registers" => print_registers(dbg, out).map_err(io)?,
+        "step" | "s" | "si" => {
+            let n = match args {
+                "" => 1,
+                a => value(dbg, a)?
+            };
+            let mut reason = StopReason::Step;
+            for _ in 0..n {
+                reason = dbg.step();
+                if reason != StopReason::Step {
+                    break;
+                }
+            }
+            report(dbg, &reason, out).map_err(io)?;
+        },
+        "next" | "n" | "ni" => {
+            let reason = dbg.step_over();
+            report(dbg, &reason, out).map_err(io)?;
+        },
+        "continue" | "c" => {
+            let reason = dbg.cont();
+            report(dbg, &reason, out).map_err(io)?;
+        },
+        "x" => {
+            let (addr, len) = match words.as_slice() {
+                [a] => (value(dbg, a)? as usize, 16),
+                [a, l] => (value(dbg, a)? as usize, value(dbg, l)? as usize),
+                _ => return Err("usage: x <addr> [len]".to_string())
+            };
+            if len > MAX_EXAMINE {
+                writeln!(out, "showing the first {} bytes", MAX_EXAMINE).map_err(io)?;
+            }
+            let bytes = dbg.read_memory(addr, len.min(MAX_EXAMINE)).map_err(|e| e.to_string())?;
+            for (i, chunk) in bytes.chunks(16).enumerate() {
+                let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+                let text: String = chunk.iter()
+                    .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' })
+                    .collect();
+                writeln!(out, "0x{:08x}: {:<48} {}", addr + i * 16, hex.join(" "), text).map_err(io)?;
+            }
+        },
+        "write" => {
+            let (addr, bytes) = match words.split_first() {
+                Some((a, b)) if !b.is_empty() => (value(dbg, a)? as usize, b),
+                _ => return Err("usage: write <addr> <byte>...".to_string())
+            };
+            let bytes = bytes.iter().map(|b| match value(dbg, b)? {
+                v @ 0..=0xff => Ok(v as u8),
+                v => Err(format!("0x{:x} doesn't fit in a byte", v))
+            }).collect::<Result<Vec<u8>, String>>()?;
+            dbg.write_memory(addr, &bytes).map_err(|e| e.to_string())?;
+        },
+        "set" => {
+            let (target, v) = match args.split_once(char::is_whitespace) {
+                Some((t, v)) => (t, value(dbg, v.trim().trim_start_matches('=').trim())?),
+                None => return Err("usage: set <reg> <value>".to_string())
+            };
+            match target {
+                "pc" => dbg.cpu.set_pc(v as usize),
+                "sp" => dbg.cpu.set_sp(v as usize),
+                "fl" => dbg.cpu.set_flags(v as u8),
+                r => {
+                    let reg = match r {
+                        "fp" => REG_FP,
+                        "lr" => REG_LR,
+                        r => r.strip_prefix('r').and_then(|n| n.parse::<u8>().ok())
+                            .ok_or(format!("unknown register {}", r))?
+                    };
+                    dbg.cpu.set_reg(reg, v).map_err(|e| e.to_string())?;
+                }
+            }
+        },
+        "disas" | "l" | "list" => match words.as_slice() {
+            [] => print_disassembly(dbg, out, dbg.cpu.pc(), 4, 6).map_err(io)?,
+            [a] => print_disassembly(dbg, out, value(dbg, a)? as usize, 0, 10).map_err(io)?,
+            [a, n] => print_disassembly(dbg, out, value(dbg, a)? as usize, 0, value(dbg, n)? as usize).map_err(io)?,
+            _ => return Err("usage: disas [addr] [n]".to_string())
+        },
+        "print" | "p" => {
+            let v = value(dbg, args)?;
+            writeln!(out, "0x{:x} ({})", v, v).map_err(io)?;
+        },
+        "restart" => {
+            dbg.restart();
+            writeln!(out, "restarted at {}", describe(dbg, dbg.cpu.pc())).map_err(io)?;
+        },
+        c => return Err(format!("unknown command {} (try `help`)", c))
+    }
+
+    Ok(false)
+}
+
+/// explains why execution stopped and shows where
+fn report(dbg: &Debugger, reason: &StopReason, out: &mut impl Write) -> std::io::Result<()> {
+    if *reason != StopReason::Step {
+        writeln!(out, "{}", reason)?;
+    }
+    match dbg.finished() {
+        Some(_) => Ok(()),
+        None => {
+            writeln!(out, "stopped at {}", describe(dbg, dbg.cpu.pc()))?;
+            print_disassembly(dbg, out, dbg.cpu.pc(), 0, 1)
+        }
+    }
+}
+
+fn print_registers(dbg: &Debugger, out: &mut impl Write) -> std::io::Result<()> {
+    for (i, r) in dbg.cpu.regs().iter().enumerate() {
+        let name = format!("r{}", i);
+        write!(out, "{:<4}0x{:08x}", name, r)?;
+        match i % 4 {
+            3 => writeln!(out)?,
+            _ => write!(out, "  ")?
+        }
+    }
+    writeln!(out, "pc  {}", describe(dbg, dbg.cpu.pc()))?;
+    writeln!(out, "sp  0x{:08x}", dbg.cpu.sp())?;
+    writeln!(out, "fl  0x{:02x} [{}]", dbg.cpu.flags(), flag_letters(dbg.cpu.flags()))
+}
+
+fn print_breakpoints(dbg: &Debugger, out: &mut impl Write) -> std::io::Result<()> {
+    if dbg.breakpoints().is_empty() && dbg.watchpoints().is_empty() {
+        return writeln!(out, "no breakpoints or watchpoints");
+    }
+    for b in dbg.breakpoints() {
+        write!(out, "{:<3} break {} hits={}", b.id, describe(dbg, b.addr), b.hits)?;
+        match &b.condition {
+            Some(c) => writeln!(out, " if {}", c)?,
+            None => writeln!(out)?
+        }
+    }
+    for w in dbg.watchpoints() {
+        writeln!(out, "{:<3} watch {} len={}", w.id, describe(dbg, w.addr), w.len)?;
+    }
+    Ok(())
+}
+
+fn print_disassembly(dbg: &Debugger, out: &mut impl Write, addr: usize, before: usize, after: usize) -> std::io::Result<()> {
+    let pc = dbg.cpu.pc();
+    for line in dbg.disassemble_around(addr, before, after) {
+        if let Some(label) = dbg.symbols.describe(line.addr as u32).filter(|(_, off)| *off == 0) {
+            writeln!(out, "{}:", label.0)?;
+        }
+        let marker = match (line.addr == pc, dbg.breakpoints().iter().any(|b| b.addr == line.addr)) {
+            (true, _) => "=>",
+            (false, true) => " *",
+            (false, false) => "  "
+        };
+        let bytes: Vec<String> = line.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        writeln!(out, "{} 0x{:08x}:  {:<21} {}", marker, line.addr, bytes.join(" "), line.text)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+
+use crate::translation::{build_decode_table, build_translation_table};
+use crate::processor::instructions::{Instruction, MemOperand};
+use crate::processor::cpu::control::CR_NAMES;
+
+
+/// a single disassembled instruction
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Disassembly {
+    /// address of the first byte of the instruction
+    pub addr: usize,
+    /// the decoded instruction, or `None` if the bytes don't decode
+    pub inst: Option<Instruction>,
+    /// the encoded bytes
+    pub bytes: Vec<u8>,
+    /// the instruction as assembly text
+    pub text: String
+}
+
+impl Disassembly {
+    /// how many bytes the instruction takes up
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+
+/// turns machine code back into assembly text
+pub struct Disassembler {
+    opcodes: HashMap<u8, Instruction>,
+    mnemonics: HashMap<Instruction, &'static str>
+}
+
+impl Default for Disassembler {
+    fn default() -> Self {
+        Disassembler::new()
+    }
+}
+
+impl Disassembler {
+    pub fn new() -> Self {
+        let mnemonics = build_decode_table().into_iter().map(|(m, i)| (i, m)).collect();
+        Disassembler { opcodes: build_translation_table(), mnemonics }
+    }
+
+    /// the assembler mnemonic for `inst`, e.g. `addi`
+    pub fn mnemonic(&self, inst: Instruction) -> &'static str {
+        self.mnemonics[&inst]
+    }
+
+    /// decodes the instruction at the start of `bytes`, which were loaded at
+    /// `addr`. Unknown opcodes and truncated instructions come back as a
+    /// single `(bad)` byte
+    pub fn decode(&self, bytes: &[u8], addr: usize) -> Disassembly {
+        let bad = |b: Option<&u8>| Disassembly {
+            addr,
+            inst: None,
+            bytes: b.map(|a| vec![*a]).unwrap_or_default(),
+            text: match b {
+                Some(a) => format!("(bad) 0x{:02x}", a),
+                None => "(bad)".to_string()
+            }
+        };
+
+        let inst = match bytes.first().and_then(|a| self.opcodes.get(a)) {
+            Some(a) => *a,
+            None => return bad(bytes.first())
+        };
+        let size = inst.size();
+        if bytes.len() < size {
+            return bad(bytes.first());
+        }
+        let b = &bytes[..size];
+        let name = self.mnemonics[&inst];
+        let u32_at = |i: usize| u32::from_be_bytes([b[i], b[i+1], b[i+2], b[i+3]]);
+        let hi = b.get(1).map(|a| a >> 4).unwrap_or(0);
+        let lo = b.get(1).map(|a| a & 0x0f).unwrap_or(0);
+
+        let text = match inst {
+            Instruction::AddReg | Instruction::SubReg | Instruction::MulReg |
+            Instruction::AdcReg | Instruction::SbbReg |
+            Instruction::DivReg | Instruction::SdivReg | Instruction::ModReg | Instruction::SmodReg |
+            Instruction::ShlReg | Instruction::ShrReg | Instruction::SarReg |
+            Instruction::RolReg | Instruction::RorReg |
+            Instruction::AndReg | Instruction::OrReg  | Instruction::XorReg |
+            Instruction::CmpReg | Instruction::Swp | Instruction::MovDregSreg|
+            Instruction::LdReg => format!("{} r{}, r{}", name, hi, lo),
+            Instruction::AddImm | Instruction::SubImm | Instruction::MulImm |
+            Instruction::AdcImm | Instruction::SbbImm |
+            Instruction::DivImm | Instruction::SdivImm | Instruction::ModImm | Instruction::SmodImm |
+            Instruction::ShlImm | Instruction::ShrImm | Instruction::SarImm |
+            Instruction::RolImm | Instruction::RorImm |
+            Instruction::AndImm | Instruction::OrImm  | Instruction::XorImm |
+            Instruction::CmpImm | Instruction::MovDregSaddr | Instruction::MovDregSimm |
+            Instruction::LdImm | Instruction::SfgReg => format!("{} r{}, 0x{:x}", name, b[1], u32_at(2)),
+            Instruction::PushReg | Instruction::Pop |
+            Instruction::JmpReg | Instruction::IntReg | Instruction::JeqReg => format!("{} r{}", name, b[1]),
+            Instruction::Ldb | Instruction::Ldbs | Instruction::Ldh | Instruction::Ldhs |
+            Instruction::Ldw | Instruction::Lea =>
+                format!("{} r{}, {}", name, hi, MemOperand::decode(lo, b[2], u32_at(3))),
+            Instruction::Stb | Instruction::Sth | Instruction::Stw =>
+                format!("{} {}, r{}", name, MemOperand::decode(lo, b[2], u32_at(3)), hi),
+            Instruction::MovDaddrSreg => format!("{} 0x{:x}, r{}", name, u32_at(1), b[5]),
+            Instruction::Rdcr => format!("{} r{}, {}", name, b[1], cr_name(u32_at(2))),
+            Instruction::Wrcr => format!("{} {}, r{}", name, cr_name(u32_at(1)), b[5]),
+            Instruction::PushAddr | Instruction::JmpAddr | Instruction::JeqImm |
+            Instruction::JmpImm | Instruction::IntImm => format!("{} 0x{:x}", name, u32_at(1)),
+            Instruction::SfgImm => format!("{} 0x{:x}, 0x{:x}", name, b[1], b[2]),
+            Instruction::Hlt | Instruction::Nop | Instruction::Brk | Instruction::Iret |
+            Instruction::Cli | Instruction::Sti => name.to_string()
+        };
+
+        Disassembly { addr, inst: Some(inst), bytes: b.to_vec(), text }
+    }
+}
+
+
+/// the assembler's name for control register `cr`, or its number if it
+/// has none
+fn cr_name(cr: u32) -> String {
+    match CR_NAMES.get(cr as usize) {
+        Some(a) => a.to_string(),
+        None => format!("0x{:x}", cr)
+    }
+}
+
+
+/// disassembles all of `bytes`, which were loaded at `base`
+pub fn disassemble(bytes: &[u8], base: usize) -> Vec<Disassembly> {
+    let d = Disassembler::new();
+    let mut ret = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let line = d.decode(&bytes[offset..], base + offset);
+        offset += line.size();
+        ret.push(line);
+    }
+    ret
+}
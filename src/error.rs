@@ -0,0 +1,272 @@
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+
+/// the kind of memory access that faulted
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    /// fetching an instruction
+    Execute
+}
+
+impl Display for Access {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Execute => write!(f, "execute")
+        }
+    }
+}
+
+
+/// faults raised by the MMU
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MemFault {
+    /// the address is past the end of RAM
+    Unmapped { addr: usize, access: Access },
+    /// the address's page doesn't allow the access. `perms` are the page's
+    /// `PERM_*` bits
+    Protected { addr: usize, access: Access, perms: u8 },
+    /// with paging on, the page table has no mapping for the virtual address
+    /// (`present` is false) or its mapping doesn't allow the access
+    PageFault { addr: usize, access: Access, present: bool, user: bool },
+    /// moving the page holding the address to or from the swap file failed
+    Swap { addr: usize, error: String }
+}
+
+impl MemFault {
+    /// numeric code for the fault, as seen by guest exception handlers
+    pub fn code(&self) -> u32 {
+        match self {
+            MemFault::Unmapped { .. } => 0x1,
+            MemFault::Protected { .. } => 0x2,
+            MemFault::PageFault { .. } => 0x3,
+            MemFault::Swap { .. } => 0x4
+        }
+    }
+}
+
+impl Display for MemFault {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            MemFault::Unmapped { addr, access } =>
+                write!(f, "{} of unmapped address 0x{:x}", access, addr),
+            MemFault::Protected { addr, access, perms } =>
+                write!(f, "{} of protected address 0x{:x} (page is {})", access, addr, perms_str(*perms)),
+            MemFault::PageFault { addr, access, present, user } => {
+                let why = match present {
+                    true => "protection violation",
+                    false => "page not present"
+                };
+                let mode = match user {
+                    true => "user",
+                    false => "supervisor"
+                };
+                write!(f, "page fault on {} of 0x{:x} in {} mode ({})", access, addr, mode, why)
+            },
+            MemFault::Swap { addr, error } =>
+                write!(f, "swapping the page at 0x{:x} failed: {}", addr, error)
+        }
+    }
+}
+
+impl std::error::Error for MemFault {}
+
+/// renders `PERM_*` bits as `rwx`, with `-` for each one that's missing
+pub fn perms_str(perms: u8) -> String {
+    use crate::processor::cpu::mmu::{PERM_READ, PERM_WRITE, PERM_EXEC};
+    [(PERM_READ, 'r'), (PERM_WRITE, 'w'), (PERM_EXEC, 'x')].iter()
+        .map(|(bit, c)| match perms & bit {
+            0 => '-',
+            _ => *c
+        })
+        .collect()
+}
+
+
+/// faults raised by the CPU while executing an instruction. `pc` is always the
+/// address of the faulting instruction
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CpuFault {
+    /// the opcode doesn't decode to an instruction
+    IllegalOpcode { pc: usize, opcode: u8 },
+    /// an instruction named a register that doesn't exist
+    IllegalRegister { pc: usize, reg: u8 },
+    /// division or remainder by zero
+    DivideByZero { pc: usize },
+    /// a memory access faulted
+    Memory { pc: usize, fault: MemFault },
+    /// `int` was given a code with no handler
+    UnknownInterrupt { pc: usize, code: u32 },
+    /// an interrupt handler failed to talk to the host
+    Io { pc: usize, error: String },
+    /// `rdcr`, `wrcr`, `iret`, `cli` or `sti` ran in user mode
+    Privileged { pc: usize },
+    /// an interrupt request arrived with interrupts enabled but no handler
+    /// installed for its vector
+    UnhandledIrq { pc: usize, irq: u8 }
+}
+
+impl CpuFault {
+    /// numeric code for the fault, as seen by guest exception handlers. 
+    /// Memory faults use 0x10 plus the `MemFault` code
+    pub fn code(&self) -> u32 {
+        match self {
+            CpuFault::IllegalOpcode { .. } => 0x1,
+            CpuFault::IllegalRegister { .. } => 0x2,
+            CpuFault::DivideByZero { .. } => 0x3,
+            CpuFault::UnknownInterrupt { .. } => 0x4,
+            CpuFault::Io { .. } => 0x5,
+            CpuFault::Privileged { .. } => 0x6,
+            CpuFault::UnhandledIrq { .. } => 0x7,
+            CpuFault::Memory { fault, .. } => 0x10 + fault.code()
+        }
+    }
+
+    /// the address of the faulting instruction
+    pub fn pc(&self) -> usize {
+        match self {
+            CpuFault::IllegalOpcode { pc, .. } | CpuFault::IllegalRegister { pc, .. } |
+            CpuFault::DivideByZero { pc } | CpuFault::Memory { pc, .. } |
+            CpuFault::UnknownInterrupt { pc, .. } | CpuFault::Io { pc, .. } |
+            CpuFault::Privileged { pc } | CpuFault::UnhandledIrq { pc, .. } => *pc
+        }
+    }
+
+    /// attaches the faulting pc to a memory fault raised through `?`
+    pub fn at(self, at: usize) -> Self {
+        match self {
+            CpuFault::Memory { fault, .. } => CpuFault::Memory { pc: at, fault },
+            a => a
+        }
+    }
+}
+
+/// lets instructions use `?` on MMU results. The pc is filled in by
+/// `CpuFault::at` once the fault reaches the decode loop
+impl From<MemFault> for CpuFault {
+    fn from(fault: MemFault) -> Self {
+        CpuFault::Memory { pc: 0, fault }
+    }
+}
+
+impl Display for CpuFault {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            CpuFault::IllegalOpcode { pc, opcode } =>
+                write!(f, "illegal opcode 0x{:x} at pc=0x{:x}", opcode, pc),
+            CpuFault::IllegalRegister { pc, reg } =>
+                write!(f, "illegal register r{} at pc=0x{:x}", reg, pc),
+            CpuFault::DivideByZero { pc } =>
+                write!(f, "divide by zero at pc=0x{:x}", pc),
+            CpuFault::Memory { pc, fault } =>
+                write!(f, "{} at pc=0x{:x}", fault, pc),
+            CpuFault::UnknownInterrupt { pc, code } =>
+                write!(f, "unknown interrupt code 0x{:x} at pc=0x{:x}", code, pc),
+            CpuFault::Io { pc, error } =>
+                write!(f, "interrupt I/O failed at pc=0x{:x}: {}", pc, error),
+            CpuFault::Privileged { pc } =>
+                write!(f, "privileged instruction in user mode at pc=0x{:x}", pc),
+            CpuFault::UnhandledIrq { pc, irq } =>
+                write!(f, "interrupt request {} has no handler at pc=0x{:x}", irq, pc)
+        }
+    }
+}
+
+impl std::error::Error for CpuFault {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CpuFault::Memory { fault, .. } => Some(fault),
+            _ => None
+        }
+    }
+}
+
+
+/// a location in an assembly source file. `line` is 1-based, `start`/`end` are
+/// the 0-based byte range of the offending text within the line
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize
+}
+
+impl Span {
+    /// a span covering a whole line
+    pub fn line(line: usize, text: &str) -> Self {
+        Span { line, start: 0, end: text.len() }
+    }
+
+    /// a span covering the first occurrence of `token` in `text`, falling
+    /// back to the whole line
+    pub fn of(line: usize, text: &str, token: &str) -> Self {
+        match text.find(token) {
+            Some(start) if !token.is_empty() => Span { line, start, end: start + token.len() },
+            _ => Span::line(line, text)
+        }
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.start + 1)
+    }
+}
+
+
+/// errors raised while assembling a program
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AsmError {
+    /// the source file couldn't be read or the output couldn't be written
+    Io { path: PathBuf, error: String },
+    /// an instruction appeared before any `section`
+    NoSection { span: Span },
+    /// the mnemonic isn't a known instruction or directive
+    UnknownInstruction { span: Span, name: String },
+    /// a label was referenced but never defined
+    UndefinedLabel { span: Span, name: String },
+    /// a register operand isn't `r0`-`r15` or an alias
+    InvalidRegister { span: Span, name: String },
+    /// a number couldn't be parsed, or doesn't fit its field
+    InvalidNumber { span: Span, text: String },
+    /// an operand is malformed or the wrong kind for the instruction
+    InvalidOperand { span: Span, reason: String }
+}
+
+impl AsmError {
+    /// where in the source the error was found, if anywhere
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            AsmError::Io { .. } => None,
+            AsmError::NoSection { span } | AsmError::UnknownInstruction { span, .. } |
+            AsmError::UndefinedLabel { span, .. } | AsmError::InvalidRegister { span, .. } |
+            AsmError::InvalidNumber { span, .. } | AsmError::InvalidOperand { span, .. } => Some(*span)
+        }
+    }
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            AsmError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            AsmError::NoSection { span } =>
+                write!(f, "{}: no section declared before first instruction", span),
+            AsmError::UnknownInstruction { span, name } =>
+                write!(f, "{}: unknown instruction {}", span, name),
+            AsmError::UndefinedLabel { span, name } =>
+                write!(f, "{}: undefined label {}", span, name),
+            AsmError::InvalidRegister { span, name } =>
+                write!(f, "{}: invalid register {}", span, name),
+            AsmError::InvalidNumber { span, text } =>
+                write!(f, "{}: invalid number {}", span, text),
+            AsmError::InvalidOperand { span, reason } =>
+                write!(f, "{}: {}", span, reason)
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
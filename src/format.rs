@@ -0,0 +1,199 @@
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use crate::processor::cpu::mmu::{MAX_RAM_SIZE, PAGE_SIZE};
+
+
+/// marks a binary that starts with a segment table
+const MAGIC: &[u8; 4] = b"\x7fDBX";
+
+/// a run of the image whose pages share the same `PERM_*` permissions
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Segment {
+    pub addr: u32,
+    pub size: u32,
+    pub flags: u8
+}
+
+
+/// a loadable program image: runs of bytes to copy into memory, each at its
+/// own address, and execution starts at address 0. Images without segments
+/// are a single run at 0 and are loaded without memory protection.
+///
+/// On disk, an image without segments is just its bytes. One with segments
+/// starts with `MAGIC`, the number of segments and an `addr size flags`
+/// triple of big-endian u32s for each, followed by each segment's bytes in
+/// turn
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Image {
+    /// `(address, bytes)` for each run, one per non-empty segment
+    pub runs: Vec<(usize, Vec<u8>)>,
+    pub segments: Vec<Segment>
+}
+
+impl Image {
+    /// wraps raw machine code
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Image { runs: vec![(0, bytes)], segments: Vec::new() }
+    }
+
+    /// reads an image from a binary on disk
+    pub fn read(path: &Path) -> std::io::Result<Self> {
+        Image::parse(std::fs::read(path)?)
+    }
+
+    /// decodes a binary, with or without a segment table
+    pub fn parse(data: Vec<u8>) -> std::io::Result<Self> {
+        if !data.starts_with(MAGIC) {
+            return Ok(Image::from_bytes(data));
+        }
+        let invalid = |what: &str| Error::new(ErrorKind::InvalidData, format!("bad segment table: {}", what));
+        let word = |at: usize| match data.get(at..at + 4) {
+            Some(w) => Ok(u32::from_be_bytes([w[0], w[1], w[2], w[3]])),
+            None => Err(invalid("truncated"))
+        };
+
+        let count = word(MAGIC.len())? as usize;
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut at = MAGIC.len() + 4;
+        // everything the segments load has to be in the file, so checking
+        // that first keeps a short file from asking for lots of memory
+        let mut total = 0usize;
+        for _ in 0..count {
+            let flags = u8::try_from(word(at + 8)?).map_err(|_| invalid("unknown flags"))?;
+            let seg = Segment { addr: word(at)?, size: word(at + 4)?, flags };
+            match (seg.addr as usize).checked_add(seg.size as usize) {
+                Some(a) if a <= MAX_RAM_SIZE => (),
+                _ => return Err(invalid("segment runs past the end of memory"))
+            }
+            total += seg.size as usize;
+            segments.push(seg);
+            at += 12;
+        }
+        let mut sorted: Vec<&Segment> = segments.iter().filter(|s| s.size > 0).collect();
+        sorted.sort_by_key(|s| s.addr);
+        if sorted.windows(2).any(|w| w[0].addr as usize + w[0].size as usize > w[1].addr as usize) {
+            return Err(invalid("segments overlap"));
+        }
+        if at.checked_add(total).is_none_or(|end| end > data.len()) {
+            return Err(invalid("truncated"));
+        }
+
+        let mut runs = Vec::new();
+        for seg in segments.iter() {
+            let size = seg.size as usize;
+            if size > 0 {
+                runs.push((seg.addr as usize, data[at..at + size].to_vec()));
+            }
+            at += size;
+        }
+        Ok(Image { runs, segments })
+    }
+
+    /// the loaded bytes from `addr` to the end of the run holding it, or
+    /// nothing if no run does
+    pub fn bytes_at(&self, addr: usize) -> &[u8] {
+        self.runs.iter()
+            .find(|(start, bytes)| (*start..start + bytes.len()).contains(&addr))
+            .map_or(&[], |(start, bytes)| &bytes[addr - start..])
+    }
+
+    /// one past the highest address the image loads anything at
+    pub fn end(&self) -> usize {
+        self.runs.iter().map(|(start, bytes)| start + bytes.len()).max().unwrap_or(0)
+    }
+
+    /// the lowest page aligned address of `len` free bytes below `limit`
+    /// that no segment touches, preferring the space after the image
+    pub fn free_space(&self, len: usize, limit: usize) -> Option<usize> {
+        let taken: Vec<(usize, usize)> = self.segments.iter()
+            .filter(|s| s.size > 0)
+            .map(|s| (s.addr as usize / PAGE_SIZE * PAGE_SIZE, (s.addr as usize + s.size as usize).next_multiple_of(PAGE_SIZE)))
+            .collect();
+        let after = self.end().next_multiple_of(PAGE_SIZE);
+        std::iter::once(after).chain(std::iter::once(0)).chain(taken.iter().map(|(_, end)| *end))
+            .find(|start| {
+                start.checked_add(len).is_some_and(|end| end <= limit && taken.iter().all(|(s, e)| end <= *s || *e <= *start))
+            })
+    }
+
+    /// encodes the image as a binary
+    pub fn to_binary(&self) -> Vec<u8> {
+        if self.segments.is_empty() {
+            return self.bytes_at(0).to_vec();
+        }
+        let mut out = MAGIC.to_vec();
+        out.extend((self.segments.len() as u32).to_be_bytes());
+        for seg in self.segments.iter() {
+            out.extend(seg.addr.to_be_bytes());
+            out.extend(seg.size.to_be_bytes());
+            out.extend((seg.flags as u32).to_be_bytes());
+        }
+        for seg in self.segments.iter() {
+            let size = seg.size as usize;
+            let bytes = self.bytes_at(seg.addr as usize);
+            let bytes = &bytes[..bytes.len().min(size)];
+            out.extend(bytes);
+            // sections with nothing to load still take up their size
+            out.resize(out.len() + size - bytes.len(), 0);
+        }
+        out
+    }
+
+    /// writes the image out as a binary
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, self.to_binary())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(segments: &[(u32, u32)], data: usize) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend((segments.len() as u32).to_be_bytes());
+        for (addr, size) in segments.iter() {
+            out.extend(addr.to_be_bytes());
+            out.extend(size.to_be_bytes());
+            out.extend(5u32.to_be_bytes());
+        }
+        out.resize(out.len() + data, 0xaa);
+        out
+    }
+
+    #[test]
+    fn segments_load_as_runs() {
+        let image = Image::parse(table(&[(0, 4), (0x2000, 0), (0x3000, 2)], 6)).unwrap();
+        assert_eq!(image.runs, [(0, vec![0xaa; 4]), (0x3000, vec![0xaa; 2])]);
+        assert_eq!(image.end(), 0x3002);
+        assert_eq!(image.bytes_at(0x3001), [0xaa]);
+        assert!(image.bytes_at(0x1000).is_empty());
+        assert_eq!(Image::parse(image.to_binary()).unwrap(), image);
+    }
+
+    #[test]
+    fn short_files_are_rejected_before_allocating() {
+        let err = Image::parse(table(&[(0xfffff000, 0x1000)], 10)).unwrap_err();
+        assert_eq!(err.to_string(), "bad segment table: truncated");
+    }
+
+    #[test]
+    fn bad_tables_are_rejected() {
+        let err = Image::parse(table(&[(0xffffffff, 2)], 2)).unwrap_err();
+        assert_eq!(err.to_string(), "bad segment table: segment runs past the end of memory");
+        let err = Image::parse(table(&[(0x10, 0x10), (0, 0x11)], 0x21)).unwrap_err();
+        assert_eq!(err.to_string(), "bad segment table: segments overlap");
+    }
+
+    #[test]
+    fn free_space_prefers_after_the_image() {
+        let image = Image::parse(table(&[(0, 0x1800)], 0x1800)).unwrap();
+        assert_eq!(image.free_space(0x3000, 0x10000), Some(0x2000));
+        let image = Image::parse(table(&[(0x1000, 0x1000), (0xd000, 0x3000)], 0x4000)).unwrap();
+        assert_eq!(image.free_space(0x3000, 0x10000), Some(0x2000));
+        assert_eq!(image.free_space(0x1000, 0x10000), Some(0));
+        assert_eq!(image.free_space(0xc000, 0x10000), None);
+    }
+}
@@ -0,0 +1,36 @@
+//! Deadbolt: an assembler, disassembler and emulator for the DeadBolt 
+//! instruction set.
+//!
+//! Most users want `assemble` to turn source into an `Assembly`, and 
+//! `CPU::new` to run the resulting image on a machine described by a 
+//! `MachineConfig`.
+#![allow(clippy::upper_case_acronyms, clippy::module_inception)]
+
+pub mod processor;
+pub mod compile;
+pub mod coverage;
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+pub mod format;
+pub mod profile;
+pub mod symbols;
+pub mod trace;
+mod translation;
+#[macro_use]
+pub mod log;
+
+// lets the logging macros reach `log` from crates that don't depend on it
+#[doc(hidden)]
+pub use ::log as __log;
+
+pub use processor::cpu::{CPU, Effect, IrqHandle, Limit, MachineConfig, MemAccess, MMU, Replacement, RunOutcome, Step, StopHandle, SwapStats, TlbStats};
+pub use processor::instructions::{Instruction, AddrMode, MemOperand};
+pub use compile::{assemble, compile, Assembly};
+pub use coverage::Coverage;
+pub use disasm::{disassemble, Disassembler, Disassembly};
+pub use error::{Access, AsmError, CpuFault, MemFault, Span};
+pub use format::Image;
+pub use profile::Profiler;
+pub use symbols::{LineEntry, SymbolTable};
+pub use trace::{TraceFormat, TraceRecord, Tracer};
@@ -1,79 +1,180 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
 use colored::Colorize;
+use ::log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// subsystem targets that can be filtered with `--log-level`
+pub const TARGETS: [&str; 5] = ["mmu", "cpu", "interrupts", "asm", "deadbolt"];
+
+// `trace!` through `error!` log under the subsystem of the module they're 
+// called from. They're exported so the CLI can use them, but hidden, since
+// library users have the `log` crate's own
 
+#[doc(hidden)]
 #[macro_export]
-macro_rules! debug {
-    () => {
-        crate::log::log(crate::log::LogType::LogDebug, String::new());
+macro_rules! trace {
+    ($($x:tt)*) => {
+        $crate::__log::trace!(target: $crate::log::subsystem(module_path!()), $($x)*)
     };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! debug {
     ($($x:tt)*) => {
-        crate::log::log(crate::log::LogType::LogDebug, std::format_args!($($x)*).to_string())
+        $crate::__log::debug!(target: $crate::log::subsystem(module_path!()), $($x)*)
     };
 }
 
+#[doc(hidden)]
 #[macro_export]
 macro_rules! info {
-    () => {
-        crate::log::log(crate::log::LogType::LogInfo, String::new());
-    };
     ($($x:tt)*) => {
-        crate::log::log(crate::log::LogType::LogInfo, std::format_args!($($x)*).to_string())
+        $crate::__log::info!(target: $crate::log::subsystem(module_path!()), $($x)*)
     };
 }
 
+#[doc(hidden)]
 #[macro_export]
 macro_rules! warn {
-    () => {
-        crate::log::log(crate::log::LogType::LogWarn, String::new());
-    };
     ($($x:tt)*) => {
-        crate::log::log(crate::log::LogType::LogWarn, std::format_args!($($x)*).to_string())
+        $crate::__log::warn!(target: $crate::log::subsystem(module_path!()), $($x)*)
     };
 }
 
+#[doc(hidden)]
 #[macro_export]
 macro_rules! error {
-    () => {
-        log(LogType::LogErr, String::new());
+    ($($x:tt)*) => {
+        $crate::__log::error!(target: $crate::log::subsystem(module_path!()), $($x)*)
     };
-    ($($x:tt)*) => {{
-        crate::log::log(crate::log::LogType::LogErr, std::format_args!($($x)*).to_string());
-        println!("{}", std::backtrace::Backtrace::force_capture());
-        std::process::exit(1);
-    }};
 }
 
 
+/// maps the module a log call was made from to its subsystem target
+pub fn subsystem(module: &str) -> &'static str {
+    match module.rsplit("::").next() {
+        Some("mmu") | Some("paging") | Some("swap") => "mmu",
+        Some("cpu") => "cpu",
+        Some("interrupts") | Some("irq") => "interrupts",
+        Some("compile") | Some("translation") => "asm",
+        _ => "deadbolt"
+    }
+}
+
 
+/// where log records end up
+enum Sink {
+    Stderr,
+    File(Mutex<File>)
+}
 
-pub enum LogType {
-    LogDebug,
-    LogInfo,
-    LogWarn,
-    LogErr
+/// logger behind the macros. Records are filtered by a default level, which
+/// can be overridden per subsystem target
+pub struct Logger {
+    default: LevelFilter,
+    targets: Vec<(&'static str, LevelFilter)>,
+    sink: Sink
 }
 
-/// logs data with color and handy pre-labeling
-pub fn log(
-    ltype: LogType, 
-    string: String
-) {
-    let strng = &string[..];
-    _log(ltype, strng);
+impl Logger {
+    /// parses a filter spec such as `info`, `cpu=debug` or `warn,mmu=trace,asm=off`
+    pub fn new(spec: &str) -> Result<Self, String> {
+        let mut default = LevelFilter::Info;
+        let mut targets = Vec::new();
+
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let target = match TARGETS.iter().find(|t| **t == target.trim()) {
+                        Some(t) => *t,
+                        None => return Err(format!("unknown log target {}", target.trim()))
+                    };
+                    targets.push((target, parse_level(level)?));
+                },
+                None => default = parse_level(directive)?
+            }
+        }
+
+        Ok(Logger { default, targets, sink: Sink::Stderr })
+    }
+
+    /// sends records to `path` instead of stderr, truncating it
+    pub fn with_file(mut self, path: &Path) -> Result<Self, String> {
+        let f = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        self.sink = Sink::File(Mutex::new(f));
+        Ok(self)
+    }
+
+    /// the level a target is allowed to log at
+    fn level_for(&self, target: &str) -> LevelFilter {
+        match self.targets.iter().rev().find(|(t, _)| *t == target) {
+            Some((_, level)) => *level,
+            None => self.default
+        }
+    }
+
+    /// installs the logger as the global `log` backend. Only the first call
+    /// takes effect
+    pub fn install(self) {
+        let max = self.targets.iter().map(|(_, l)| *l).fold(self.default, std::cmp::max);
+        if ::log::set_boxed_logger(Box::new(self)).is_ok() {
+            ::log::set_max_level(max);
+        }
+    }
 }
 
-pub fn _log(
-    ltype: LogType, 
-    string: &str
-) {
-    // print fancy colors depending the input type
-    match ltype {
-        LogType::LogDebug   => {
-            if cfg!(debug_assertions) {
-                println!("{} - {}", "[DEBUG]".bright_cyan(), string.bright_blue())
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // failing to write a log line is never worth stopping the emulator for,
+        // so write errors are dropped
+        match &self.sink {
+            Sink::Stderr => {
+                let label = match record.level() {
+                    Level::Trace => "[TRACE]".bright_black(),
+                    Level::Debug => "[DEBUG]".bright_cyan(),
+                    Level::Info => "[INFO]".green(),
+                    Level::Warn => "[WARN]".yellow(),
+                    Level::Error => "[FAIL]".red()
+                };
+                let _ = match record.level() {
+                    Level::Trace | Level::Debug => writeln!(std::io::stderr(), "{} {} - {}",
+                        label, record.target(), record.args().to_string().bright_blue()),
+                    _ => writeln!(std::io::stderr(), "{} - {}", label, record.args())
+                };
+            },
+            Sink::File(f) => {
+                if let Ok(mut f) = f.lock() {
+                    let _ = writeln!(f, "[{}] {} - {}", record.level(), record.target(), record.args());
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        match &self.sink {
+            Sink::Stderr => { let _ = std::io::stderr().flush(); },
+            Sink::File(f) => {
+                if let Ok(mut f) = f.lock() {
+                    let _ = f.flush();
+                }
             }
         }
-        LogType::LogInfo    => println!("{} - {}", "[INFO]".green(), string),
-        LogType::LogWarn    => println!("{} - {}", "[WARN]".yellow(), string),
-        LogType::LogErr     => println!("{} - {}", "[FAIL]".red(), string),
     }
-}
\ No newline at end of file
+}
+
+/// parses a single level name
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    level.trim().parse::<LevelFilter>().map_err(|_| format!("unknown log level {}", level.trim()))
+}
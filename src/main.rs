@@ -1,36 +1,145 @@
-//#![feature(format_args_nl)]
+use std::io::{BufWriter, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use clap::{Command, ArgMatches, arg, value_parser, ArgAction};
+use ::log::LevelFilter;
+use deadbolt::compile::describe_error;
+use deadbolt::log::Logger;
+use deadbolt::trace::{first_divergence, parse_filter, read_trace, write_divergence};
+use deadbolt::debugger::{dap, gdb, repl, Debugger};
+use deadbolt::debugger::gdb::SessionEnd;
+use deadbolt::{compile, Coverage, disassemble, Disassembler, info, warn, error, Image, Limit, MachineConfig, Profiler, Replacement, RunOutcome, SymbolTable, TraceFormat, TraceRecord, Tracer, CPU};
 
-mod processor;
-mod compile;
-mod translation;
-#[macro_use]
-mod log;
-
-use std::io::Read;
-use std::path::PathBuf;
-
-use clap::{Command, arg, value_parser, ArgAction};
-use compile::compile;
+/// exit status when the program stops at a `brk` (128 + SIGTRAP)
+const EXIT_BREAKPOINT: i32 = 133;
+/// exit status when the program runs out of steps (as `timeout(1)` does)
+const EXIT_STEP_LIMIT: i32 = 124;
+/// exit status when execution is stopped early (128 + SIGINT)
+const EXIT_STOPPED: i32 = 130;
+/// exit status when the program runs into a memory, stack or interrupt 
+/// limit (128 + SIGKILL, as if a sandbox had killed it)
+const EXIT_LIMIT: i32 = 137;
+/// exit status for a `hlt` whose code doesn't fit in a status, or would be
+/// mistaken for one of the above
+const EXIT_HALT_UNREPRESENTABLE: i32 = 255;
 
 fn main() {
     // parse command line arguments
     let matches = Command::new("DeadBolt")
                         .about("Compiler and emulator for the DeadBolt instruction set")
+                        .arg(arg!(-v --verbose "Log more detail (repeat for trace output)").global(true)
+                        .action(ArgAction::Count))
+                        .arg(arg!(-q --quiet "Log less (repeat to silence logging)").global(true)
+                        .action(ArgAction::Count))
+                        .arg(arg!(--"log-level" <SPEC> "Log filter, e.g. `debug` or `warn,cpu=trace,mmu=off`").global(true).required(false)
+                        .action(ArgAction::Set))
+                        .arg(arg!(--"log-file" <PATH> "Write log output to a file instead of stderr").global(true).required(false).value_parser(value_parser!(PathBuf))
+                        .action(ArgAction::Set))
                         .subcommand(
                             Command::new("compile")
                                     .about("Compiles a program from source assembly file")
                                     .arg(arg!(-f --file <VALUE> "File to compile").required(false).value_parser(value_parser!(PathBuf))
                                     .action(ArgAction::Set))
                                     .arg(arg!(-o --output <VALUE> "Path to save binary to").required(false).value_parser(value_parser!(PathBuf))
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--symbols <PATH> "Also write the label table to this file").required(false).value_parser(value_parser!(PathBuf))
                                     .action(ArgAction::Set)))
                         .subcommand(
                                 Command::new("run")
                                     .about("Runs a binary")
                                     .arg(arg!(-i --input <VALUE> "Path to the binary to run").required(true).value_parser(value_parser!(PathBuf))
                                     .action(ArgAction::Set))
+                                    .arg(arg!(--"max-steps" <N> "Stop after executing this many instructions").required(false).value_parser(value_parser!(u64))
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--"ram-size" <BYTES> "How much RAM the machine has, e.g. 65536, 0x10000 or 64K (default: 4G)").required(false).value_parser(parse_size)
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--"stack-size" <BYTES> "How much stack programs with segments get between their guard pages (default: 64K)").required(false).value_parser(parse_size)
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--timeout <SECS> "Stop after running for this many seconds").required(false).value_parser(parse_seconds)
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--"max-pages" <N> "Stop instead of allocating more than this many 4K pages").required(false).value_parser(value_parser!(usize))
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--"max-stack" <BYTES> "Stop instead of growing the stack past this many bytes").required(false).value_parser(value_parser!(usize))
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--"allow-int" <CODES> "Only allow these interrupt codes, e.g. 0x80,0xa0 (repeatable)").required(false).value_parser(parse_code)
+                                    .value_delimiter(',')
+                                    .action(ArgAction::Append))
+                                    .arg(arg!(--"tlb-entries" <N> "How many translations the TLB caches once the program turns paging on (default: 16)").required(false).value_parser(value_parser!(usize))
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--frames <N> "Back RAM with only N 4K frames, swapping the other pages out").required(false).value_parser(value_parser!(usize))
+                                    .conflicts_with("max-pages")
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--replacement <POLICY> "Page replacement policy with --frames: fifo, lru, clock or optimal (default: fifo)").required(false).value_parser(Replacement::from_name)
+                                    .requires("frames")
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--"swap-file" <PATH> "Where --frames swaps pages to (default: a temporary file)").required(false).value_parser(value_parser!(PathBuf))
+                                    .requires("frames")
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--"ws-window" <N> "How many memory references the working set is measured over (default: 10000)").required(false).value_parser(value_parser!(u64))
+                                    .requires("frames")
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--gdb <ADDR> "Wait for gdb to connect on ADDR (e.g. :1234) before running").required(false)
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--symbols <PATH> "Label table written by `compile --symbols`").required(false).value_parser(value_parser!(PathBuf))
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--trace <PATH> "Record every executed instruction to this file").required(false).value_parser(value_parser!(PathBuf))
+                                    .conflicts_with("gdb")
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--"trace-format" <FORMAT> "jsonl or csv (default: from the trace file's extension)").required(false)
+                                    .requires("trace")
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--"trace-filter" <RANGE> "Only trace instructions in a label, an address or a start..end range (repeatable)").required(false)
+                                    .requires("trace")
+                                    .action(ArgAction::Append))
+                                    .arg(arg!(--profile "Count instructions and cycles, and print a report to stderr at the end")
+                                    .conflicts_with("gdb")
+                                    .action(ArgAction::SetTrue))
+                                    .arg(arg!(--"profile-folded" <PATH> "Also write folded call stacks for flamegraph tools").required(false).value_parser(value_parser!(PathBuf))
+                                    .requires("profile")
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--coverage <PATH> "Write the executed addresses, branches and lines to this JSON file").required(false).value_parser(value_parser!(PathBuf))
+                                    .conflicts_with("gdb")
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--lcov <PATH> "Write source line and branch coverage as an lcov tracefile").required(false).value_parser(value_parser!(PathBuf))
+                                    .conflicts_with("gdb")
+                                    .action(ArgAction::Set))
+                        )
+                        .subcommand(
+                                Command::new("disasm")
+                                    .about("Disassembles a binary")
+                                    .arg(arg!(-i --input <VALUE> "Path to the binary to disassemble").required(true).value_parser(value_parser!(PathBuf))
+                                    .action(ArgAction::Set))
+                        )
+                        .subcommand(
+                                Command::new("debug")
+                                    .about("Runs a program under the interactive debugger")
+                                    .arg(arg!(-i --input <VALUE> "Path to the binary, or a .dba source file, to debug").required(true).value_parser(value_parser!(PathBuf))
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--symbols <PATH> "Label table written by `compile --symbols`").required(false).value_parser(value_parser!(PathBuf))
+                                    .action(ArgAction::Set))
+                        )
+                        .subcommand(
+                                Command::new("tracediff")
+                                    .about("Finds the first instruction where two traces from `run --trace` differ")
+                                    .arg(arg!(<A> "First trace").value_parser(value_parser!(PathBuf)))
+                                    .arg(arg!(<B> "Second trace").value_parser(value_parser!(PathBuf)))
+                                    .arg(arg!(-C --context <N> "Instructions to show either side of the divergence").required(false).value_parser(value_parser!(usize))
+                                    .default_value("3")
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--"symbols-a" <PATH> "Symbol file or .dba source for the first trace's program").required(false).value_parser(value_parser!(PathBuf))
+                                    .action(ArgAction::Set))
+                                    .arg(arg!(--"symbols-b" <PATH> "Symbol file or .dba source for the second trace's program").required(false).value_parser(value_parser!(PathBuf))
+                                    .action(ArgAction::Set))
+                        )
+                        .subcommand(
+                                Command::new("dap")
+                                    .about("Serves the debug adapter protocol on stdin/stdout, for editors")
                         ).get_matches();
 
+    init_logging(&matches);
+
     // determine which subcommand we will be using
     if let Some(m) = matches.subcommand_matches("compile") {
         // compile the thing
@@ -39,20 +148,178 @@ fn main() {
             Some(a) => a.clone(),
             None => PathBuf::from("")
         };
-        compile(path, output);
-    } else if let Some(m) = matches.subcommand_matches("run") {
-        // run program
-        let path = m.get_one::<PathBuf>("input").unwrap();
-        let mut f = std::fs::File::open(path).unwrap();
-        let mut prog: Vec<u8> = Vec::new();
-        f.read_to_end(&mut prog).unwrap();
-        let mut proc = processor::cpu::CPU::init(prog);
-        match proc.run() {
-            Ok(_) => (),
+        let assembly = match compile(path.clone(), output) {
+            Ok(a) => a,
             Err(e) => {
-                error!("Encountered fatal error: {}\n{}", e, proc);
+                error!("Failed to compile {}", describe_error(&path, &e));
+                std::process::exit(1);
+            }
+        };
+        if let Some(sym) = m.get_one::<PathBuf>("symbols") {
+            let mut symbols = assembly.symbols();
+            symbols.set_source(Some(std::fs::canonicalize(&path).unwrap_or(path.clone())));
+            if let Err(e) = symbols.write(sym) {
+                error!("Failed to write symbols to {}: {}", sym.display(), e);
+                std::process::exit(1);
             }
+        }
+    } else if let Some(m) = matches.subcommand_matches("run") {
+        // run program
+        let (image, symbols) = load_program(
+            m.get_one::<PathBuf>("input").unwrap(),
+            m.get_one::<PathBuf>("symbols").map(|a| a.as_path())
+        );
+        let config = MachineConfig {
+            ram_size: m.get_one::<usize>("ram-size").copied(),
+            stack_size: m.get_one::<usize>("stack-size").copied(),
+            step_limit: m.get_one::<u64>("max-steps").copied(),
+            timeout: m.get_one::<Duration>("timeout").copied(),
+            max_pages: m.get_one::<usize>("max-pages").copied(),
+            max_stack: m.get_one::<usize>("max-stack").copied(),
+            allowed_interrupts: m.get_many::<u32>("allow-int").map(|a| a.copied().collect()),
+            tlb_entries: m.get_one::<usize>("tlb-entries").copied(),
+            frames: m.get_one::<usize>("frames").copied(),
+            replacement: m.get_one::<Replacement>("replacement").copied().unwrap_or_default(),
+            swap_file: m.get_one::<PathBuf>("swap-file").cloned(),
+            ws_window: m.get_one::<u64>("ws-window").copied()
         };
+
+        // optionally hand control to gdb first
+        if let Some(addr) = m.get_one::<String>("gdb") {
+            let addr = match addr.starts_with(':') {
+                true => format!("127.0.0.1{}", addr),
+                false => addr.clone()
+            };
+            let listener = match TcpListener::bind(&addr) {
+                Ok(a) => a,
+                Err(e) => {
+                    error!("Failed to listen on {}: {}", addr, e);
+                    std::process::exit(1);
+                }
+            };
+            info!("Waiting for gdb to connect on {}...", addr);
+
+            let mut dbg = new_debugger(config, image, symbols);
+            let outcome = match gdb::serve(&mut dbg, listener) {
+                Ok(SessionEnd::Detached) => match dbg.finished() {
+                    Some(a) => a.clone(),
+                    None => dbg.cpu.run()
+                },
+                Ok(SessionEnd::Killed | SessionEnd::Disconnected) => RunOutcome::Stopped,
+                Err(e) => {
+                    error!("gdb connection failed: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            exit_with(outcome, &dbg.cpu);
+        }
+
+        let mut proc = CPU::new(&config);
+        if let Err(e) = proc.load(&image) {
+            error!("Failed to load the program: {}", e);
+            std::process::exit(1);
+        }
+
+        // optionally trace, profile or measure coverage of every instruction
+        // as it runs
+        let trace = m.get_one::<PathBuf>("trace");
+        let coverage = m.contains_id("coverage") || m.contains_id("lcov");
+        if trace.is_some() || m.get_flag("profile") || coverage {
+            let mut tracer = trace.map(|path| make_tracer(m, path, &symbols));
+            let mut profiler = match m.get_flag("profile") {
+                true => Some(Profiler::new(symbols.clone())),
+                false => None
+            };
+            let mut coverage = match coverage {
+                true => Some(Coverage::new(&image, symbols.clone())),
+                false => None
+            };
+            let outcome = proc.run_observed(&mut |cpu, step| {
+                if let Some(t) = tracer.as_mut() {
+                    t.observe(cpu, step);
+                }
+                if let Some(p) = profiler.as_mut() {
+                    p.observe(cpu, step);
+                }
+                if let Some(c) = coverage.as_mut() {
+                    c.observe(cpu, step);
+                }
+            });
+
+            if let (Some(t), Some(path)) = (tracer, trace) {
+                if let Err(e) = t.finish() {
+                    error!("Failed to write trace to {}: {}", path.display(), e);
+                }
+            }
+            if let Some(p) = profiler {
+                write_profile(m, &p);
+            }
+            if let Some(c) = coverage {
+                write_coverage(m, &c);
+            }
+            exit_with(outcome, &proc);
+        }
+
+        let outcome = proc.run();
+        exit_with(outcome, &proc);
+    } else if let Some(m) = matches.subcommand_matches("disasm") {
+        // print the program back out as assembly
+        let (image, _) = load_program(m.get_one::<PathBuf>("input").unwrap(), None);
+        for (start, bytes) in image.runs.iter() {
+            for line in disassemble(bytes, *start) {
+                let bytes: Vec<String> = line.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                println!("{:08x}:  {:<21} {}", line.addr, bytes.join(" "), line.text);
+            }
+        }
+    } else if let Some(m) = matches.subcommand_matches("debug") {
+        // hand the program over to the debugger
+        let (image, symbols) = load_program(
+            m.get_one::<PathBuf>("input").unwrap(), 
+            m.get_one::<PathBuf>("symbols").map(|a| a.as_path())
+        );
+        let mut dbg = new_debugger(MachineConfig::default(), image, symbols);
+        if let Err(e) = repl(&mut dbg, std::io::stdin().lock(), &mut std::io::stdout()) {
+            error!("Debugger I/O failed: {}", e);
+            std::process::exit(1);
+        }
+    } else if let Some(m) = matches.subcommand_matches("tracediff") {
+        // exits like diff(1): 0 when the traces match, 1 when they don't
+        let traces: Vec<(String, Vec<TraceRecord>, SymbolTable)> = [("A", "symbols-a"), ("B", "symbols-b")].iter().map(|(arg, sym)| {
+            let path = m.get_one::<PathBuf>(arg).unwrap();
+            let records = match read_trace(path) {
+                Ok(a) => a,
+                Err(e) => {
+                    error!("Failed to read trace {}", e);
+                    std::process::exit(2);
+                }
+            };
+            let symbols = match m.get_one::<PathBuf>(sym) {
+                Some(s) => load_symbols(s),
+                None => SymbolTable::default()
+            };
+            (path.display().to_string(), records, symbols)
+        }).collect();
+        let (a, b) = (&traces[0], &traces[1]);
+
+        match first_divergence(&a.1, &b.1) {
+            None => println!("traces are identical ({} instructions)", a.1.len()),
+            Some(div) => {
+                let context = *m.get_one::<usize>("context").unwrap();
+                let result = write_divergence(&mut std::io::stdout(), &div,
+                    (&a.0, &a.1, &a.2), (&b.0, &b.1, &b.2), context);
+                if let Err(e) = result {
+                    error!("Failed to write report: {}", e);
+                    std::process::exit(2);
+                }
+                std::process::exit(1);
+            }
+        }
+    } else if matches.subcommand_matches("dap").is_some() {
+        // the client tells us what to launch
+        if let Err(e) = dap::serve(std::io::stdin(), std::io::stdout()) {
+            error!("Debug adapter I/O failed: {}", e);
+            std::process::exit(1);
+        }
     } else {
         println!("No command provided. Use --help to see commands");
 
@@ -60,3 +327,241 @@ fn main() {
 
     
 }
+
+/// the disassembly of the instruction at `pc`, for fault reports
+fn instruction_at(proc: &CPU, pc: usize) -> String {
+    let bytes: Vec<u8> = (pc..pc + 8).map_while(|a| proc.memory.read_u8(a).ok()).collect();
+    format!("0x{:08x}: {}", pc, Disassembler::new().decode(&bytes, pc).text)
+}
+
+/// builds a debugger for the program, exiting if it doesn't fit in RAM
+fn new_debugger(config: MachineConfig, image: Image, symbols: SymbolTable) -> Debugger {
+    match Debugger::new(config, image, symbols) {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Failed to load the program: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// reports how a program stopped and exits with the matching status
+fn exit_with(outcome: RunOutcome, proc: &CPU) -> ! {
+    let tlb = proc.memory.tlb_stats();
+    if tlb.hits + tlb.misses > 0 {
+        info!("TLB: {} hits, {} misses, {} flushes", tlb.hits, tlb.misses, tlb.flushes);
+    }
+    if let Some(swap) = proc.memory.swap_stats() {
+        info!("Paging: {} references, {} faults ({} from swap), {} evictions, {} writebacks",
+            swap.references, swap.faults, swap.swap_ins, swap.evictions, swap.writebacks);
+        info!("Working set: {} pages touched, {:.1} on average per {} references, {} at most",
+            swap.pages, swap.mean_working_set(), swap.ws_window, swap.ws_peak);
+    }
+    match outcome {
+        RunOutcome::Halted(code) => std::process::exit(halt_status(code)),
+        RunOutcome::Breakpoint(pc) => {
+            warn!("Stopped at breakpoint at 0x{:x}\n{}", pc, proc);
+            std::process::exit(EXIT_BREAKPOINT);
+        },
+        RunOutcome::StepLimit => {
+            warn!("Step limit reached\n{}", proc);
+            std::process::exit(EXIT_STEP_LIMIT);
+        },
+        a @ (RunOutcome::Stopped | RunOutcome::Reached(_)) => {
+            warn!("Execution {}\n{}", a, proc);
+            std::process::exit(EXIT_STOPPED);
+        },
+        RunOutcome::LimitExceeded(limit) => {
+            warn!("Stopped at 0x{:x}: {}\n{}", proc.pc(), limit, proc);
+            match limit {
+                Limit::Timeout(_) => std::process::exit(EXIT_STEP_LIMIT),
+                _ => std::process::exit(EXIT_LIMIT)
+            }
+        },
+        RunOutcome::Fault(e) => {
+            error!("Encountered fatal error (fault code 0x{:x}): {}\n    {}\n{}", e.code(), e, instruction_at(proc, e.pc()), proc);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// the exit status for a program that halted with `code`. Statuses are only
+/// 8 bits on Unix, so codes past 255 would wrap (256 to success), and the 
+/// emulator's own statuses have to stay unambiguous
+fn halt_status(code: u32) -> i32 {
+    match i32::try_from(code) {
+        Ok(a @ 0..=255) if ![EXIT_STEP_LIMIT, EXIT_STOPPED, EXIT_BREAKPOINT, EXIT_LIMIT].contains(&a) => a,
+        _ => {
+            warn!("Program halted with {}, exiting with {} instead", code, EXIT_HALT_UNREPRESENTABLE);
+            EXIT_HALT_UNREPRESENTABLE
+        }
+    }
+}
+
+/// parses a (possibly fractional) number of seconds
+fn parse_seconds(text: &str) -> Result<Duration, String> {
+    match text.parse::<f64>().map(Duration::try_from_secs_f64) {
+        Ok(Ok(a)) => Ok(a),
+        _ => Err(format!("'{}' is not a number of seconds", text))
+    }
+}
+
+/// parses a size in bytes, in decimal or `0x` hex, optionally followed by
+/// `K`, `M` or `G`
+fn parse_size(text: &str) -> Result<usize, String> {
+    let (digits, scale) = match text.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&text[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&text[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&text[..i], 1 << 30),
+        _ => (text, 1)
+    };
+    let parsed = match digits.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => digits.parse::<usize>()
+    };
+    match parsed.ok().and_then(|n| n.checked_mul(scale)) {
+        Some(n) if n <= deadbolt::processor::cpu::mmu::MAX_RAM_SIZE => Ok(n),
+        _ => Err(format!("'{}' is not a size of at most 4G", text))
+    }
+}
+
+/// parses an interrupt code in decimal or `0x` hex
+fn parse_code(text: &str) -> Result<u32, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse::<u32>()
+    };
+    parsed.map_err(|_| format!("'{}' is not an interrupt code", text))
+}
+
+/// opens the trace file and sets up the tracer from `run`'s trace arguments,
+/// exiting if they are invalid
+fn make_tracer(m: &ArgMatches, path: &Path, symbols: &SymbolTable) -> Tracer {
+    let format = match m.get_one::<String>("trace-format").map(|f| TraceFormat::from_name(f)) {
+        Some(Ok(a)) => a,
+        Some(Err(e)) => {
+            error!("{}", e);
+            std::process::exit(2);
+        },
+        None => TraceFormat::from_path(path)
+    };
+    let file = match std::fs::File::create(path) {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Failed to create {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut tracer = Tracer::new(Box::new(BufWriter::new(file)), format);
+    for spec in m.get_many::<String>("trace-filter").into_iter().flatten() {
+        match parse_filter(spec, symbols) {
+            Ok(range) => tracer.add_filter(range),
+            Err(e) => {
+                error!("Invalid --trace-filter {}: {}", spec, e);
+                std::process::exit(2);
+            }
+        }
+    }
+    tracer
+}
+
+/// prints the profile report to stderr and writes folded stacks if asked to
+fn write_profile(m: &ArgMatches, profiler: &Profiler) {
+    if let Err(e) = profiler.write_report(&mut std::io::stderr()) {
+        error!("Failed to write profile: {}", e);
+    }
+    if let Some(path) = m.get_one::<PathBuf>("profile-folded") {
+        let result = std::fs::File::create(path).and_then(|f| {
+            let mut out = BufWriter::new(f);
+            profiler.write_folded(&mut out)?;
+            out.flush()
+        });
+        if let Err(e) = result {
+            error!("Failed to write folded stacks to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// writes the coverage files that were asked for, and a summary to stderr
+fn write_coverage(m: &ArgMatches, coverage: &Coverage) {
+    if let Some(path) = m.get_one::<PathBuf>("coverage") {
+        let result = std::fs::write(path, format!("{:#}\n", coverage.to_json()));
+        if let Err(e) = result {
+            error!("Failed to write coverage to {}: {}", path.display(), e);
+        }
+    }
+    if let Some(path) = m.get_one::<PathBuf>("lcov") {
+        let result = std::fs::File::create(path).and_then(|f| {
+            let mut out = BufWriter::new(f);
+            coverage.write_lcov(&mut out)?;
+            out.flush()
+        });
+        if let Err(e) = result {
+            error!("Failed to write lcov report to {}: {}", path.display(), e);
+        }
+    }
+    if let Err(e) = coverage.write_summary(&mut std::io::stderr()) {
+        error!("Failed to write coverage summary: {}", e);
+    }
+}
+
+/// reads a symbol file, or assembles a `.dba` file for its symbols, exiting
+/// on failure
+fn load_symbols(path: &Path) -> SymbolTable {
+    let symbols = match path.extension().is_some_and(|e| e == "dba") {
+        true => deadbolt::compile::load_program(path, None).map(|(_, s)| s),
+        false => SymbolTable::read(path)
+    };
+    match symbols {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Failed to load symbols from {}", e);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// loads a program to run, along with its labels, exiting if it can't be
+/// read or assembled
+fn load_program(path: &Path, symbols: Option<&Path>) -> (Image, SymbolTable) {
+    match deadbolt::compile::load_program(path, symbols) {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Failed to load {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+
+/// sets up the logger from `-v`/`-q`, `--log-level` and `--log-file`. An
+/// explicit `--log-level` wins over the verbosity flags
+fn init_logging(matches: &ArgMatches) {
+    const LEVELS: [LevelFilter; 6] = [
+        LevelFilter::Off, LevelFilter::Error, LevelFilter::Warn,
+        LevelFilter::Info, LevelFilter::Debug, LevelFilter::Trace
+    ];
+    let verbosity = 3 + matches.get_count("verbose") as isize - matches.get_count("quiet") as isize;
+    let default = LEVELS[verbosity.clamp(0, 5) as usize].to_string();
+    let spec = matches.get_one::<String>("log-level").unwrap_or(&default);
+
+    let logger = match Logger::new(spec) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Invalid --log-level: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let logger = match matches.get_one::<PathBuf>("log-file") {
+        Some(path) => match logger.with_file(path) {
+            Ok(l) => l,
+            Err(e) => {
+                eprintln!("Failed to open log file {}", e);
+                std::process::exit(2);
+            }
+        },
+        None => logger
+    };
+    logger.install();
+}
@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::processor::cpu::swap::Replacement;
+
+/// the stack size programs with segments get unless configured otherwise
+pub const DEFAULT_STACK_SIZE: usize = 64 * 1024;
+
+/// describes the machine a `CPU` emulates. Use `MachineConfig::default()`
+/// and override the fields you care about
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MachineConfig {
+    /// how many bytes of RAM the machine has, starting at address 0. `None`
+    /// gives it the whole 32-bit address space
+    pub ram_size: Option<usize>,
+    /// how many bytes of stack a program with segments gets, between two 
+    /// guard pages. `None` gives it `DEFAULT_STACK_SIZE`
+    pub stack_size: Option<usize>,
+    /// how many instructions a single call to `CPU::run` may execute
+    pub step_limit: Option<u64>,
+    /// how long a single call to `CPU::run` may take
+    pub timeout: Option<Duration>,
+    /// how many pages of memory may be resident at once. Not used when
+    /// `frames` is set
+    pub max_pages: Option<usize>,
+    /// how many bytes the stack may grow above where it started
+    pub max_stack: Option<usize>,
+    /// the only interrupt codes the program may raise. `None` allows all of
+    /// them
+    pub allowed_interrupts: Option<Vec<u32>>,
+    /// how many translations the TLB caches once the program turns paging
+    /// on. `None` gives it `DEFAULT_TLB_ENTRIES`
+    pub tlb_entries: Option<usize>,
+    /// backs RAM with this many 4K physical frames, swapping the pages that
+    /// don't fit out to a file. `None` keeps every page resident
+    pub frames: Option<usize>,
+    /// which page makes way when every frame is taken
+    pub replacement: Replacement,
+    /// where evicted pages go. `None` uses a temporary file that's removed
+    /// afterwards
+    pub swap_file: Option<PathBuf>,
+    /// how many references the working set is measured over. `None` gives
+    /// `DEFAULT_WS_WINDOW`
+    pub ws_window: Option<u64>
+}
@@ -0,0 +1,79 @@
+/// number of control registers
+pub const NUM_CRS: usize = 13;
+
+/// the processor's mode, as `STATUS_*` bits
+pub const CR_STATUS: u8 = 0;
+/// physical address of the page directory used while paging is on
+pub const CR_PTBR: u8 = 1;
+/// physical address of the vector table. Zero means no handlers are installed
+pub const CR_VBR: u8 = 2;
+/// where the trapping instruction was
+pub const CR_EPC: u8 = 3;
+/// the flags when the trap happened
+pub const CR_EFLAGS: u8 = 4;
+/// `CR_STATUS` when the trap happened
+pub const CR_ESTATUS: u8 = 5;
+/// why the trap happened: the fault's code plus `CAUSE_*` bits for
+/// exceptions, or `CAUSE_IRQ` and the line for interrupt requests
+pub const CR_CAUSE: u8 = 6;
+/// the virtual address that faulted, or the opcode, register or interrupt
+/// code that other exceptions are about
+pub const CR_BADADDR: u8 = 7;
+/// writing an address drops its page from the TLB, and writing `0xffffffff`
+/// flushes the whole TLB. Always reads as zero
+pub const CR_TLBINV: u8 = 8;
+/// interrupt request lines that can't be delivered, one bit each
+pub const CR_IMASK: u8 = 9;
+/// interrupt request lines waiting to be delivered. Writing drops the lines
+/// that are set
+pub const CR_IPEND: u8 = 10;
+/// four bits of priority per interrupt request line, line 0 lowest. Higher
+/// priorities are delivered first
+pub const CR_IPRIO: u8 = 11;
+/// raises the timer's interrupt request line every this many instructions.
+/// Zero stops the timer
+pub const CR_TIMER: u8 = 12;
+
+/// the names the assembler accepts for each control register
+pub const CR_NAMES: [&str; NUM_CRS] = [
+    "status", "ptbr", "vbr", "epc", "eflags", "estatus", "cause", "badaddr", "tlbinv",
+    "imask", "ipend", "iprio", "timer"
+];
+
+/// addresses are translated through the page tables at `CR_PTBR`
+pub const STATUS_PAGING: u32 = 1 << 0;
+/// the CPU is in user mode, so can only touch user pages and can't use
+/// `rdcr`, `wrcr`, `iret`, `cli` or `sti`
+pub const STATUS_USER: u32 = 1 << 1;
+/// interrupt requests are delivered. Cleared on entry to a trap handler
+pub const STATUS_IE: u32 = 1 << 2;
+/// the `STATUS_*` bits that can be set
+pub const STATUS_MASK: u32 = STATUS_PAGING | STATUS_USER | STATUS_IE;
+
+/// the vector division by zero is delivered to
+pub const VEC_DIVIDE: u32 = 0;
+/// the vector illegal opcodes and registers are delivered to
+pub const VEC_ILLEGAL: u32 = 6;
+/// the vector privileged instructions in user mode and accesses to 
+/// unmapped or protected physical memory are delivered to
+pub const VEC_PROTECTION: u32 = 13;
+/// the vector page faults are delivered to
+pub const VEC_PAGE_FAULT: u32 = 14;
+/// the vector `int` codes without a host handler are delivered to
+pub const VEC_UNKNOWN_INT: u32 = 15;
+/// the vector of interrupt request line 0. Line `n` uses `VEC_IRQ_BASE + n`
+pub const VEC_IRQ_BASE: u32 = 32;
+
+/// set in `CR_CAUSE` when a page fault hit a present page it wasn't allowed
+/// to touch, rather than a missing one
+pub const CAUSE_PRESENT: u32 = 1 << 8;
+/// set in `CR_CAUSE` when the faulting memory access was a write
+pub const CAUSE_WRITE: u32 = 1 << 9;
+/// set in `CR_CAUSE` when the exception happened in user mode
+pub const CAUSE_USER: u32 = 1 << 10;
+/// set in `CR_CAUSE` when the faulting memory access was an instruction
+/// fetch
+pub const CAUSE_FETCH: u32 = 1 << 11;
+/// set in `CR_CAUSE` when the trap was an interrupt request, whose line is
+/// in the low bits
+pub const CAUSE_IRQ: u32 = 1 << 12;
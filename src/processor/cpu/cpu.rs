@@ -1,43 +1,179 @@
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
 use crate::translation::{
     build_translation_table,
     convert_to_signed
 };
 
-use crate::processor::cpu::mmu::MMU;
+use crate::processor::cpu::mmu::{MMU, MAX_RAM_SIZE, PAGE_SIZE, PERM_READ, PERM_WRITE};
+use crate::processor::cpu::control::{
+    NUM_CRS, CR_STATUS, CR_PTBR, CR_VBR, CR_EPC, CR_EFLAGS, CR_ESTATUS, CR_CAUSE, CR_BADADDR, CR_TLBINV,
+    CR_IMASK, CR_IPEND, CR_IPRIO, CR_TIMER,
+    STATUS_PAGING, STATUS_USER, STATUS_IE, STATUS_MASK,
+    VEC_DIVIDE, VEC_ILLEGAL, VEC_PROTECTION, VEC_PAGE_FAULT, VEC_UNKNOWN_INT, VEC_IRQ_BASE,
+    CAUSE_PRESENT, CAUSE_WRITE, CAUSE_USER, CAUSE_FETCH, CAUSE_IRQ
+};
+use crate::processor::cpu::irq::{InterruptController, IrqHandle, NUM_IRQS};
+use crate::processor::cpu::paging::{DEFAULT_TLB_ENTRIES, PTE_FRAME};
+use crate::processor::cpu::swap::DEFAULT_WS_WINDOW;
+use crate::processor::cpu::{MachineConfig, DEFAULT_STACK_SIZE};
+use crate::processor::cpu::mmu::MemAccess;
+use crate::processor::cpu::step::{Effect, Step, StopHandle};
+use crate::format::Image;
+use crate::error::Access;
 use crate::processor::cpu::interrupts::{IntFn, build_interrupt_table};
-use crate::processor::instructions::Instruction;
+use crate::processor::instructions::{Instruction, AddrMode, MemOperand};
 use crate::debug;
+use crate::error::{CpuFault, MemFault};
+
+
+/// set when an operation produces zero, or a compare finds two equal values
+pub const FLAG_ZERO: u8 = 1 << 0;
+/// set on unsigned overflow (add/mul) or borrow (sub/cmp)
+pub const FLAG_CARRY: u8 = 1 << 1;
+/// set when a compare finds the first operand (unsigned) greater than the second
+pub const FLAG_GREATER: u8 = 1 << 2;
+/// set on signed (two's complement) overflow
+pub const FLAG_OVERFLOW: u8 = 1 << 3;
+/// makes `int_readcon` echo input back to the console
+pub const FLAG_ECHO: u8 = 1 << 4;
+/// set when the result of an operation has its sign bit set
+pub const FLAG_NEGATIVE: u8 = 1 << 5;
+
+
+
+/// number of general purpose registers
+pub const NUM_REGS: usize = 16;
+/// register conventionally used as the frame pointer (`fp`)
+pub const REG_FP: u8 = 13;
+/// register conventionally used as the link register (`lr`)
+pub const REG_LR: u8 = 14;
+
+
+/// the kinds of shift/rotate the ALU can perform
+#[derive(Clone, Copy, Debug)]
+enum Shift {
+    Shl,
+    Shr,
+    Sar,
+    Rol,
+    Ror
+}
 
 
+/// why a call to `CPU::run` returned
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RunOutcome {
+    /// the program executed `hlt`. Carries the exit code from `r0`
+    Halted(u32),
+    /// the program executed `brk` at the contained address. `pc` has already
+    /// moved past it, so running again resumes the program
+    Breakpoint(usize),
+    /// the step limit was reached before the program stopped
+    StepLimit,
+    /// `run_until` reached the contained address
+    Reached(usize),
+    /// a `StopHandle` asked the CPU to stop
+    Stopped,
+    /// the program ran into one of the machine's resource limits
+    LimitExceeded(Limit),
+    /// the CPU hit a fatal error
+    Fault(CpuFault)
+}
 
 
+/// a resource limit from `MachineConfig` that a program ran into
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Limit {
+    /// the run took longer than the timeout
+    Timeout(Duration),
+    /// a write needed a new page while the contained number were resident.
+    /// The instruction has run, but that write was discarded
+    ResidentPages(usize),
+    /// a push would have grown the stack past the contained number of bytes.
+    /// `pc` is left on the push
+    StackDepth(usize),
+    /// the program raised an interrupt that isn't allowed. `pc` is left on 
+    /// the `int`
+    Interrupt(u32)
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Limit::Timeout(t) => write!(f, "timed out after {:?}", t),
+            Limit::ResidentPages(n) => write!(f, "resident page limit of {} reached", n),
+            Limit::StackDepth(n) => write!(f, "stack depth limit of {} bytes reached", n),
+            Limit::Interrupt(code) => write!(f, "interrupt 0x{:x} is not allowed", code)
+        }
+    }
+}
+
+impl Display for RunOutcome {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            RunOutcome::Halted(code) => write!(f, "halted with exit code {}", code),
+            RunOutcome::Breakpoint(pc) => write!(f, "breakpoint at 0x{:x}", pc),
+            RunOutcome::StepLimit => write!(f, "step limit reached"),
+            RunOutcome::Reached(pc) => write!(f, "reached 0x{:x}", pc),
+            RunOutcome::Stopped => write!(f, "stopped"),
+            RunOutcome::LimitExceeded(l) => write!(f, "limit exceeded: {}", l),
+            RunOutcome::Fault(e) => write!(f, "fault: {}", e)
+        }
+    }
+}
+
+
+/// how many instructions run between checks of the clock
+const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
+
+/// called with every step taken by `CPU::run_observed`
+pub type StepObserver<'a> = &'a mut dyn FnMut(&CPU, &Step);
 
 /// implements the cpu's functionality
 pub struct CPU {
     // general purpose registers
-    r0: u32,
-    r1: u32,
-    r2: u32,
-    r3: u32,
+    regs: [u32; NUM_REGS],
 
     // specialized registers
     pc: usize, // program counter
     sp: usize, // stack pointer
     fl: u8, // flag register
     /*
-    Flags: 
-        Zero: 1
-        Carry: 2
-        Greater: 3
+    Flags (bit):
+        Zero: 0
+        Carry: 1
+        Greater: 2
+        Overflow: 3
         Echo: 4
+        Negative: 5
     */
+    cr: [u32; NUM_CRS], // control registers, see `control`
+    /// set while the first instruction of a trap handler hasn't run yet, so
+    /// a handler that can't even be fetched stops the CPU instead of 
+    /// trapping forever
+    trapped: bool,
+    /// pending interrupt requests and the interval timer
+    irqs: InterruptController,
 
     // program information
     pub memory: MMU,
     interrupt_table: HashMap<u32, IntFn>,
-    prog: Vec<u8>, 
+    console: Box<dyn Write + Send>,
+    console_input: Option<Box<dyn Read + Send>>,
+
+    // run control
+    step_limit: Option<u64>,
+    timeout: Option<Duration>,
+    max_stack: Option<usize>,
+    allowed_interrupts: Option<Vec<u32>>,
+    /// where the stack started, which `max_stack` is measured from
+    stack_base: usize,
+    stack_size: usize,
+    stop: Option<RunOutcome>,
+    stop_handle: StopHandle,
 
     decode_table: HashMap<u8, Instruction>
 }
@@ -45,76 +181,407 @@ pub struct CPU {
 
 impl Display for CPU {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        write!(f, "r0={}, r1={}, r2={}, r3={}\npc={}, sp={}, fl={}",
-            self.r0, self.r1, self.r2, self.r3, self.pc, self.sp, self.fl)
+        for (i, r) in self.regs.iter().enumerate() {
+            let sep = match i % 4 {
+                3 => "\n",
+                _ => ", "
+            };
+            write!(f, "r{}={}{}", i, r, sep)?;
+        }
+        write!(f, "pc={}, sp={}, fl={}", self.pc, self.sp, self.fl)
     }
 }
 
 
 impl CPU {
-    /// initializes the CPU and loads the program into memory
-    pub fn init(prog: Vec<u8>) -> Self{
+    /// initializes the CPU with the default machine and loads the program 
+    /// into memory
+    pub fn init(prog: Vec<u8>) -> Self {
+        let mut cpu = CPU::new(&MachineConfig::default());
+        cpu.load(&Image::from_bytes(prog)).expect("programs larger than the address space can't be loaded");
+        cpu
+    }
+
+    /// builds a CPU for the machine described by `config`, with empty memory
+    pub fn new(config: &MachineConfig) -> Self {
         // initialize...
+        let mut memory = MMU::with_size(config.ram_size.unwrap_or(MAX_RAM_SIZE));
+        memory.set_page_limit(config.max_pages);
+        memory.set_tlb_entries(config.tlb_entries.unwrap_or(DEFAULT_TLB_ENTRIES));
+        if let Some(frames) = config.frames {
+            memory.set_swap(frames, config.replacement, config.swap_file.clone(), config.ws_window.unwrap_or(DEFAULT_WS_WINDOW));
+        }
         CPU {
             // ... GP registers ...
-            r0: 0,
-            r1: 0,
-            r2: 0,
-            r3: 0,
+            regs: [0; NUM_REGS],
 
             // ... special registers ...
             pc: 0,
             sp: 0,
             fl: 0,
+            cr: [0; NUM_CRS],
+            trapped: false,
+            irqs: InterruptController::default(),
 
             // ... program related stuff
-            memory: MMU::new(),
-            prog,
+            memory,
+            step_limit: config.step_limit,
+            timeout: config.timeout,
+            max_stack: config.max_stack,
+            allowed_interrupts: config.allowed_interrupts.clone(),
+            stack_base: 0,
+            stack_size: config.stack_size.unwrap_or(DEFAULT_STACK_SIZE),
+            stop: None,
+            stop_handle: StopHandle::default(),
             interrupt_table: build_interrupt_table(),
+            console: Box::new(std::io::stdout()),
+            console_input: None,
             decode_table: build_translation_table()
         }
     }
 
-    /// run the processor
-    pub fn run(&mut self) -> Result<(), String>{
+    /// copies each run of a program image into memory and points `pc` at
+    /// address 0, in supervisor mode with paging off. Faults if the image 
+    /// doesn't fit in RAM.
+    ///
+    /// If the image has segments, their pages get the segment's permissions
+    /// and the stack moves to its own pages, after the image if there's 
+    /// room, with a guard page that can't be touched on either side
+    pub fn load(&mut self, image: &Image) -> Result<(), MemFault> {
+        self.cr = [0; NUM_CRS];
+        self.trapped = false;
+        self.irqs.reset();
+        self.sync_mmu();
+        // an earlier program's permissions don't apply to this one
+        self.memory.unprotect();
+        for (addr, bytes) in image.runs.iter() {
+            self.memory.patch_bytes(*addr, bytes)?;
+        }
+        self.pc = 0;
+
+        if !image.segments.is_empty() {
+            for seg in image.segments.iter() {
+                self.memory.protect(seg.addr as usize, seg.size as usize, seg.flags);
+            }
+            let size = self.stack_size.next_multiple_of(PAGE_SIZE);
+            let below = match image.free_space(size + 2 * PAGE_SIZE, self.memory.size()) {
+                Some(a) => a,
+                None => return Err(MemFault::Unmapped { addr: self.memory.size(), access: Access::Write })
+            };
+            let stack = below + PAGE_SIZE;
+            let above = stack + size;
+            self.memory.protect(below, PAGE_SIZE, 0);
+            self.memory.protect(stack, above - stack, PERM_READ | PERM_WRITE);
+            self.memory.protect(above, PAGE_SIZE, 0);
+            // pushes move `sp` up before writing, so the first lands on the
+            // stack's first word
+            self.sp = stack - 4;
+        }
+        self.stack_base = self.sp;
+        Ok(())
+    }
+
+    /// limits how many instructions a single call to `run` or `run_until` 
+    /// may execute
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.step_limit = limit;
+    }
+
+    /// a handle that can stop `run`, `run_for` or `run_until` from another 
+    /// thread
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop_handle.clone()
+    }
+
+    /// a handle that raises interrupt requests on this CPU, from any thread
+    pub fn irq_handle(&self) -> IrqHandle {
+        self.irqs.handle()
+    }
+
+    /// sends the program's console output (`int 0x80` and echoed input) to
+    /// `out` instead of stdout, returning the previous destination
+    pub fn set_console(&mut self, out: Box<dyn Write + Send>) -> Box<dyn Write + Send> {
+        std::mem::replace(&mut self.console, out)
+    }
+
+    /// where console output currently goes
+    pub(crate) fn console(&mut self) -> &mut dyn Write {
+        &mut self.console
+    }
+
+    /// reads console input (`int 0xa0`) from `input` instead of the
+    /// terminal, returning the previous source. `None` goes back to reading
+    /// keys from the terminal
+    pub fn set_console_input(&mut self, input: Option<Box<dyn Read + Send>>) -> Option<Box<dyn Read + Send>> {
+        std::mem::replace(&mut self.console_input, input)
+    }
+
+    /// where console input comes from, if not the terminal
+    pub(crate) fn console_input(&mut self) -> Option<&mut dyn Read> {
+        self.console_input.as_mut().map(|r| r as &mut dyn Read)
+    }
+
+    /// run the processor until it halts, hits a breakpoint or the step limit, 
+    /// faults or is stopped
+    pub fn run(&mut self) -> RunOutcome {
+        self.run_loop(self.step_limit, None, None)
+    }
+
+    /// runs at most `n` instructions, returning `RunOutcome::StepLimit` if the
+    /// program is still running afterwards
+    pub fn run_for(&mut self, n: u64) -> RunOutcome {
+        self.run_loop(Some(n), None, None)
+    }
+
+    /// runs until `pc` reaches `addr`, returning `RunOutcome::Reached`. At 
+    /// least one instruction is executed, so running until the current `pc` 
+    /// goes round a loop
+    pub fn run_until(&mut self, addr: usize) -> RunOutcome {
+        self.run_loop(self.step_limit, Some(addr), None)
+    }
+
+    /// like `run`, but hands every instruction's `Step` to `observer` along
+    /// with the machine state after it. Slower, since each step is recorded
+    pub fn run_observed(&mut self, observer: StepObserver) -> RunOutcome {
+        self.run_loop(self.step_limit, None, Some(observer))
+    }
+
+    /// executes a single instruction, recording what it did
+    pub fn step(&mut self) -> Step {
+        let pc = self.pc;
+        let regs = self.regs;
+        let (sp, fl) = (self.sp, self.fl);
+        let inst = match self.memory.read_u8(pc) {
+            Ok(op) => self.decode_table.get(&op).copied(),
+            Err(_) => None
+        };
 
-        // load the program into memory
-        for i in 0..self.prog.len() {
-            self.memory[i] = self.prog[i];
-            assert_eq!(self.memory[i], self.prog[i]);
+        self.memory.start_recording();
+        let outcome = self.execute();
+        let accesses = self.memory.stop_recording();
+
+        // instruction fetches aren't interesting, so leave them out
+        let fetch = pc..pc + inst.map(|i| i.size()).unwrap_or(1);
+        let mut effects = self.memory_effects(&accesses, fetch);
+        for (r, (old, new)) in regs.iter().zip(self.regs.iter()).enumerate() {
+            if old != new {
+                effects.push(Effect::Reg { reg: r as u8, old: *old, new: *new });
+            }
         }
+        if sp != self.sp {
+            effects.push(Effect::Sp { old: sp, new: self.sp });
+        }
+        if fl != self.fl {
+            effects.push(Effect::Flags { old: fl, new: self.fl });
+        }
+
+        Step { pc, inst, next_pc: self.pc, effects, outcome }
+    }
 
+    /// steps until `limit` instructions have run, `until` is reached, or the
+    /// program stops. With an `observer`, every step is recorded and passed
+    /// to it
+    fn run_loop(&mut self, limit: Option<u64>, until: Option<usize>, mut observer: Option<StepObserver>) -> RunOutcome {
+        let mut steps: u64 = 0;
+        let started = Instant::now();
         loop {
-            debug!("\n{}", self);
-            self.decode_and_execute()?;
+            if limit.is_some_and(|limit| steps >= limit) {
+                return RunOutcome::StepLimit;
+            }
+            if self.stop_handle.take() {
+                return RunOutcome::Stopped;
+            }
+            // reading the clock every instruction is slow, so only check 
+            // every so often
+            if let Some(timeout) = self.timeout {
+                if steps.is_multiple_of(TIMEOUT_CHECK_INTERVAL) && started.elapsed() >= timeout {
+                    return RunOutcome::LimitExceeded(Limit::Timeout(timeout));
+                }
+            }
+
+            let outcome = match observer.as_mut() {
+                Some(observer) => {
+                    let step = self.step();
+                    observer(self, &step);
+                    step.outcome
+                },
+                None => self.execute()
+            };
+            if let Some(outcome) = outcome {
+                return outcome;
+            }
+            steps += 1;
+
+            if until == Some(self.pc) {
+                return RunOutcome::Reached(self.pc);
+            }
         }
     }
 
-    /// decodes and executes instruction
-    fn decode_and_execute(&mut self) -> Result<usize, String> {
-        let inst = self.memory[self.pc];
+    /// executes a single instruction, then delivers any interrupt request
+    /// that is ready. Returns why the program stopped if it did
+    fn execute(&mut self) -> Option<RunOutcome> {
+        debug!("\n{}", self);
+        if let Err(e) = self.decode_and_execute() {
+            return Some(RunOutcome::Fault(e));
+        }
+        // the write that needed a page past the limit went nowhere, so stop
+        // before the program sees the damage
+        if let Some(n) = self.memory.take_page_limit_hit() {
+            return Some(RunOutcome::LimitExceeded(Limit::ResidentPages(n)));
+        }
+        if let Some(outcome) = self.stop.take() {
+            return Some(outcome);
+        }
+
+        if self.cr[CR_TIMER as usize] != 0 {
+            self.irqs.tick(self.cr[CR_TIMER as usize]);
+        }
+        if self.cr[CR_STATUS as usize] & STATUS_IE != 0 {
+            if let Err(e) = self.deliver_irq() {
+                return Some(RunOutcome::Fault(e));
+            }
+        }
+        None
+    }
+
+    /// enters the handler of the highest priority unmasked interrupt 
+    /// request, if one is pending. `epc` is the next instruction, so `iret`
+    /// carries on from there
+    fn deliver_irq(&mut self) -> Result<(), CpuFault> {
+        let irq = match self.irqs.take(self.cr[CR_IMASK as usize], self.cr[CR_IPRIO as usize]) {
+            Some(a) => a,
+            None => return Ok(())
+        };
+        debug!("IRQ {}", irq);
+        match self.trap(VEC_IRQ_BASE + irq as u32, CAUSE_IRQ | irq as u32, 0) {
+            true => Ok(()),
+            false => Err(CpuFault::UnhandledIrq { pc: self.pc, irq })
+        }
+    }
+
+    /// groups recorded byte accesses into runs of contiguous reads and writes,
+    /// skipping any that fall in `fetch`
+    fn memory_effects(&self, accesses: &[MemAccess], fetch: std::ops::Range<usize>) -> Vec<Effect> {
+        let mut effects: Vec<Effect> = Vec::new();
+        for a in accesses.iter() {
+            if a.access == Access::Read && fetch.contains(&a.addr) {
+                continue;
+            }
+            let new = a.new;
+
+            match (effects.last_mut(), a.access) {
+                (Some(Effect::MemRead { addr, bytes }), Access::Read) if *addr + bytes.len() == a.addr => {
+                    bytes.push(a.value);
+                },
+                (Some(Effect::MemWrite { addr, old, new: n }), Access::Write) if *addr + old.len() == a.addr => {
+                    old.push(a.value);
+                    n.push(new);
+                },
+                (_, Access::Read) => effects.push(Effect::MemRead { addr: a.addr, bytes: vec![a.value] }),
+                (_, Access::Write) => effects.push(Effect::MemWrite { addr: a.addr, old: vec![a.value], new: vec![new] }),
+                (_, Access::Execute) => ()
+            }
+        }
+        effects
+    }
+
+    /// decodes and executes instruction. Faults that are exceptions go to 
+    /// the guest's handler if it has one, with `pc` left on the faulting 
+    /// instruction
+    fn decode_and_execute(&mut self) -> Result<usize, CpuFault> {
+        let pc = self.pc;
+        let fault = match self.dispatch() {
+            Ok(a) => {
+                self.trapped = false;
+                return Ok(a);
+            },
+            Err(e) => e.at(pc)
+        };
+        let (vector, detail) = match exception(&fault) {
+            Some(a) => a,
+            None => return Err(fault)
+        };
+
+        let mut cause = fault.code();
+        if let CpuFault::Memory { fault: MemFault::PageFault { present: true, .. }, .. } = fault {
+            cause |= CAUSE_PRESENT;
+        }
+        if let CpuFault::Memory { fault: MemFault::Unmapped { access, .. } | MemFault::Protected { access, .. } |
+                                         MemFault::PageFault { access, .. }, .. } = fault {
+            match access {
+                Access::Write => cause |= CAUSE_WRITE,
+                Access::Execute => cause |= CAUSE_FETCH,
+                Access::Read => ()
+            }
+        }
+        if self.is_user_mode() {
+            cause |= CAUSE_USER;
+        }
+        match self.trap(vector, cause, detail) {
+            true => Ok(0),
+            false => Err(fault)
+        }
+    }
+
+    /// enters the guest's handler for `vector`, saving where the CPU was in
+    /// the control registers and switching to supervisor mode with 
+    /// interrupts disabled. Returns 
+    /// false, changing nothing, if no handler is installed or the handler
+    /// itself couldn't start
+    fn trap(&mut self, vector: u32, cause: u32, badaddr: u32) -> bool {
+        if self.trapped || self.cr[CR_VBR as usize] == 0 {
+            return false;
+        }
+        let entry = (self.cr[CR_VBR as usize] as usize) + 4 * vector as usize;
+        let handler = match self.memory.read_physical_u32(entry) {
+            Ok(0) | Err(_) => return false,
+            Ok(a) => a
+        };
+        debug!("TRAP vector {} to 0x{:x}, cause 0x{:x}", vector, handler, cause);
+
+        self.cr[CR_EPC as usize] = self.pc as u32;
+        self.cr[CR_EFLAGS as usize] = self.fl as u32;
+        self.cr[CR_ESTATUS as usize] = self.cr[CR_STATUS as usize];
+        self.cr[CR_CAUSE as usize] = cause;
+        self.cr[CR_BADADDR as usize] = badaddr;
+        self.cr[CR_STATUS as usize] &= !(STATUS_USER | STATUS_IE);
+        self.sync_mmu();
+        self.pc = handler as usize;
+        self.trapped = true;
+        true
+    }
+
+    /// points the MMU at the page table and mode the control registers say
+    fn sync_mmu(&mut self) {
+        let status = self.cr[CR_STATUS as usize];
+        let base = match status & STATUS_PAGING {
+            0 => None,
+            _ => Some((self.cr[CR_PTBR as usize] & PTE_FRAME) as usize)
+        };
+        self.memory.set_page_table(base);
+        self.memory.set_user_mode(status & STATUS_USER != 0);
+    }
+
+    /// decodes the instruction at `pc` and runs its implementation
+    fn dispatch(&mut self) -> Result<usize, CpuFault> {
+        self.memory.check_execute(self.pc, 1)?;
+        let inst = self.memory.read_u8(self.pc)?;
         //debug!("OPCODE 0x{:x}", inst);
         let inst_type = match self.decode_table.get(&inst) {
             Some(a) => a,
-            None => return Err(format!("Illegal instruction 0x{:x}", inst))
+            None => return Err(CpuFault::IllegalOpcode { pc: self.pc, opcode: inst })
         };
         
-        // match the instruction, saving how much we need to increment the program counter
-        let incr = match inst_type {
-            Instruction::AddReg | Instruction::SubReg | Instruction::MulReg | 
-            Instruction::AndReg | Instruction::OrReg  | Instruction::XorReg | 
-            Instruction::CmpReg | Instruction::Swp | Instruction::MovDregSreg|
-            Instruction::LdReg => 2,
-            Instruction::AddImm | Instruction::SubImm | Instruction::MulImm | 
-            Instruction::AndImm | Instruction::OrImm  | Instruction::XorImm | 
-            Instruction::CmpImm | Instruction::MovDregSaddr | Instruction::MovDregSimm | Instruction::LdImm | Instruction::SfgReg => 6,
-            Instruction::PushReg | Instruction::Pop | 
-            Instruction::JmpReg | Instruction::IntReg | Instruction::JeqReg => 2,
-            Instruction::MovDaddrSreg => 6,
-            Instruction::PushAddr | Instruction::JmpAddr | Instruction::JeqImm | Instruction::JmpImm | Instruction::IntImm => 5,
-            Instruction::SfgImm => 3,
-            Instruction::Hlt | Instruction::Nop => 1   
-        };
+        // save how much we need to increment the program counter
+        let incr = inst_type.size();
+        self.memory.check_execute(self.pc, incr)?;
+
+        // refuse instructions that would break a limit before they run
+        if let Some(limit) = self.check_limits(*inst_type)? {
+            self.stop = Some(RunOutcome::LimitExceeded(limit));
+            return Ok(0);
+        }
 
         // this double lookup is bad...
         match inst_type {
@@ -124,6 +591,28 @@ impl CPU {
             Instruction::SubImm => self.sub_imm(),
             Instruction::MulReg => self.mul_reg(),  
             Instruction::MulImm => self.mul_imm(),            
+            Instruction::AdcReg => self.adc_reg(),
+            Instruction::AdcImm => self.adc_imm(),
+            Instruction::SbbReg => self.sbb_reg(),
+            Instruction::SbbImm => self.sbb_imm(),
+            Instruction::DivReg => self.div_reg(),
+            Instruction::DivImm => self.div_imm(),
+            Instruction::SdivReg => self.sdiv_reg(),
+            Instruction::SdivImm => self.sdiv_imm(),
+            Instruction::ModReg => self.mod_reg(),
+            Instruction::ModImm => self.mod_imm(),
+            Instruction::SmodReg => self.smod_reg(),
+            Instruction::SmodImm => self.smod_imm(),
+            Instruction::ShlReg => self.shl_reg(),
+            Instruction::ShlImm => self.shl_imm(),
+            Instruction::ShrReg => self.shr_reg(),
+            Instruction::ShrImm => self.shr_imm(),
+            Instruction::SarReg => self.sar_reg(),
+            Instruction::SarImm => self.sar_imm(),
+            Instruction::RolReg => self.rol_reg(),
+            Instruction::RolImm => self.rol_imm(),
+            Instruction::RorReg => self.ror_reg(),
+            Instruction::RorImm => self.ror_imm(),
             Instruction::AndReg => self.and_reg(),
             Instruction::AndImm => self.and_imm(),
             Instruction::OrReg => self.or_reg(),
@@ -153,6 +642,15 @@ impl CPU {
             },
             Instruction::LdImm => self.ld_imm(),
             Instruction::LdReg => self.ld_reg(),
+            Instruction::Ldb => self.ldb(),
+            Instruction::Ldbs => self.ldbs(),
+            Instruction::Ldh => self.ldh(),
+            Instruction::Ldhs => self.ldhs(),
+            Instruction::Ldw => self.ldw(),
+            Instruction::Stb => self.stb(),
+            Instruction::Sth => self.sth(),
+            Instruction::Stw => self.stw(),
+            Instruction::Lea => self.lea(),
             Instruction::SfgImm => self.sfg_imm(),
             Instruction::SfgReg => self.sfg_reg(),
             Instruction::Swp => self.swp(),
@@ -161,8 +659,18 @@ impl CPU {
             Instruction::Pop => self.pop(),
             Instruction::IntImm => self.int_imm(),
             Instruction::IntReg => self.int_reg(),
+            Instruction::Rdcr => self.rdcr(),
+            Instruction::Wrcr => self.wrcr(),
+            Instruction::Iret => {
+                return self.iret();
+            },
+            Instruction::Cli => self.cli(),
+            Instruction::Sti => self.sti(),
             Instruction::Nop => self.nop(),
-            Instruction::Hlt => self.hlt(),
+            Instruction::Hlt => {
+                return self.hlt();
+            },
+            Instruction::Brk => self.brk(),
 
             //_ => panic!("Unknown opcode: 0x{:x}", &inst)
         }?;
@@ -173,25 +681,120 @@ impl CPU {
 
     }
 
+    /// checks whether running `inst` would push the stack too deep or raise
+    /// an interrupt that isn't allowed
+    fn check_limits(&self, inst: Instruction) -> Result<Option<Limit>, CpuFault> {
+        match inst {
+            Instruction::PushAddr | Instruction::PushReg => {
+                let depth = (self.sp + 4).saturating_sub(self.stack_base);
+                match self.max_stack {
+                    Some(max) if depth > max => Ok(Some(Limit::StackDepth(max))),
+                    _ => Ok(None)
+                }
+            },
+            Instruction::IntImm | Instruction::IntReg => {
+                let allowed = match &self.allowed_interrupts {
+                    Some(a) => a,
+                    None => return Ok(None)
+                };
+                let code = match inst {
+                    Instruction::IntImm => self.memory.read_u32(self.pc + 1)?,
+                    _ => self.get_reg(self.memory.read_u8(self.pc + 1)?)?
+                };
+                match allowed.contains(&code) {
+                    true => Ok(None),
+                    false => Ok(Some(Limit::Interrupt(code)))
+                }
+            },
+            _ => Ok(None)
+        }
+    }
+
+    /// gets the program counter
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// moves the program counter
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    /// gets the stack pointer
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    /// moves the stack pointer
+    pub fn set_sp(&mut self, sp: usize) {
+        self.sp = sp;
+    }
+
+    /// gets the flag register
+    pub fn flags(&self) -> u8 {
+        self.fl
+    }
+
+    /// overwrites the flag register
+    pub fn set_flags(&mut self, fl: u8) {
+        self.fl = fl;
+    }
+
+    /// gets the value of control register `cr`. Unknown ones read as zero
+    pub fn control_reg(&self, cr: u8) -> u32 {
+        match cr {
+            CR_TLBINV => 0,
+            CR_IPEND => self.irqs.pending(),
+            _ => self.cr.get(cr as usize).copied().unwrap_or(0)
+        }
+    }
+
+    /// sets control register `cr` as `wrcr` does, switching paging and
+    /// modes, flushing the TLB or dropping interrupt requests to match. 
+    /// Writes to unknown ones are ignored
+    pub fn set_control_reg(&mut self, cr: u8, v: u32) {
+        match cr {
+            CR_STATUS => self.cr[CR_STATUS as usize] = v & STATUS_MASK,
+            CR_PTBR => {
+                self.cr[CR_PTBR as usize] = v;
+                self.memory.flush_tlb();
+            },
+            CR_TLBINV => match v {
+                0xffffffff => self.memory.flush_tlb(),
+                a => self.memory.flush_tlb_page(a as usize)
+            },
+            CR_IMASK => self.cr[CR_IMASK as usize] = v & ((1 << NUM_IRQS) - 1),
+            CR_IPEND => self.irqs.clear(v),
+            _ => if let Some(a) = self.cr.get_mut(cr as usize) {
+                *a = v;
+            }
+        }
+        self.sync_mmu();
+    }
+
+    /// whether the CPU is in user mode
+    pub fn is_user_mode(&self) -> bool {
+        self.cr[CR_STATUS as usize] & STATUS_USER != 0
+    }
+
+    /// gets all of the general purpose registers
+    pub fn regs(&self) -> &[u32; NUM_REGS] {
+        &self.regs
+    }
+
     /// gets the value of a register
-    pub fn get_reg(&self, r: u8) -> Result<u32, String> {
-        match r {
-            0 => Ok(self.r0),
-            1 => Ok(self.r1),
-            2 => Ok(self.r2),
-            3 => Ok(self.r3),
-            _ => Err(format!("Illicit value {}", r))
+    pub fn get_reg(&self, r: u8) -> Result<u32, CpuFault> {
+        match self.regs.get(r as usize) {
+            Some(a) => Ok(*a),
+            None => Err(CpuFault::IllegalRegister { pc: self.pc, reg: r })
         }
     }
 
     /// sets the value of a register
-    pub fn set_reg(&mut self, r: u8, v: u32) -> Result<(), String> {
-        match r {
-            0 => self.r0 = v,
-            1 => self.r1 = v,
-            2 => self.r2 = v,
-            3 => self.r3 = v,
-            _ => return Err(format!("Illicit value {}", r))
+    pub fn set_reg(&mut self, r: u8, v: u32) -> Result<(), CpuFault> {
+        match self.regs.get_mut(r as usize) {
+            Some(a) => *a = v,
+            None => return Err(CpuFault::IllegalRegister { pc: self.pc, reg: r })
         };
 
         Ok(())
@@ -199,203 +802,287 @@ impl CPU {
 
     /// checks if a certain flag is set
     pub fn is_flag_set(&self, flag: u8) -> bool {
-        let v = flag & self.fl;
-        match v {
-            0 => false,
-            _ => true
+        flag & self.fl != 0
+    }
+
+    /// decodes the `dest`/`src` register nibbles of a two-register instruction
+    fn reg_pair(&self) -> Result<(u8, u8), CpuFault> {
+        let regs = self.memory.read_u8(self.pc + 1)?;
+        Ok(((regs & 0xf0) >> 4, regs & 0x0f))
+    }
+
+    /// decodes the register and 32-bit immediate of a register-immediate instruction
+    fn reg_imm(&self) -> Result<(u8, u32), CpuFault> {
+        Ok((self.memory.read_u8(self.pc + 1)?, self.memory.read_u32(self.pc + 2)?))
+    }
+
+    /// updates ZERO, CARRY, OVERFLOW and NEGATIVE from the result of an ALU 
+    /// operation. GREATER and ECHO are left untouched
+    fn set_alu_flags(&mut self, res: u32, carry: bool, overflow: bool) {
+        let mut fl = self.fl & !(FLAG_ZERO | FLAG_CARRY | FLAG_OVERFLOW | FLAG_NEGATIVE);
+        if res == 0 {
+            fl |= FLAG_ZERO;
+        }
+        if carry {
+            fl |= FLAG_CARRY;
+        }
+        if overflow {
+            fl |= FLAG_OVERFLOW;
+        }
+        if res & 0x80000000 != 0 {
+            fl |= FLAG_NEGATIVE;
+        }
+        self.fl = fl;
+    }
+
+    /// computes `a + b + carry_in`, wrapping and setting the flags
+    fn alu_add(&mut self, a: u32, b: u32, carry_in: bool) -> u32 {
+        let (res, c1) = a.overflowing_add(b);
+        let (res, c2) = res.overflowing_add(carry_in as u32);
+        // signed overflow when both operands share a sign the result doesn't
+        let overflow = (a ^ res) & (b ^ res) & 0x80000000 != 0;
+        self.set_alu_flags(res, c1 || c2, overflow);
+        res
+    }
+
+    /// computes `a - b - borrow_in`, wrapping and setting the flags. CARRY is
+    /// set when the subtraction borrows
+    fn alu_sub(&mut self, a: u32, b: u32, borrow_in: bool) -> u32 {
+        let (res, b1) = a.overflowing_sub(b);
+        let (res, b2) = res.overflowing_sub(borrow_in as u32);
+        // signed overflow when the operands differ in sign and the result 
+        // doesn't share the sign of `a`
+        let overflow = (a ^ b) & (a ^ res) & 0x80000000 != 0;
+        self.set_alu_flags(res, b1 || b2, overflow);
+        res
+    }
+
+    /// computes `a * b`, keeping the low 32 bits. CARRY is set when the 
+    /// unsigned product doesn't fit, OVERFLOW when the signed one doesn't
+    fn alu_mul(&mut self, a: u32, b: u32) -> u32 {
+        let full = a as u64 * b as u64;
+        let res = full as u32;
+        let overflow = (a as i32).overflowing_mul(b as i32).1;
+        self.set_alu_flags(res, full >> 32 != 0, overflow);
+        res
+    }
+
+    /// sets ZERO and NEGATIVE from the result of a logical operation, clearing
+    /// CARRY and OVERFLOW
+    fn alu_logic(&mut self, res: u32) -> u32 {
+        self.set_alu_flags(res, false, false);
+        res
+    }
+
+    /// computes `a / b`, faulting on a zero divisor. Signed division of 
+    /// `i32::MIN` by -1 wraps and sets OVERFLOW
+    fn alu_div(&mut self, a: u32, b: u32, signed: bool) -> Result<u32, CpuFault> {
+        if b == 0 {
+            return Err(CpuFault::DivideByZero { pc: self.pc });
+        }
+        let (res, overflow) = match signed {
+            true => {
+                let (q, o) = (a as i32).overflowing_div(b as i32);
+                (q as u32, o)
+            },
+            false => (a / b, false)
+        };
+        self.set_alu_flags(res, false, overflow);
+        Ok(res)
+    }
+
+    /// computes `a % b`, faulting on a zero divisor. The signed remainder takes
+    /// the sign of `a`
+    fn alu_rem(&mut self, a: u32, b: u32, signed: bool) -> Result<u32, CpuFault> {
+        if b == 0 {
+            return Err(CpuFault::DivideByZero { pc: self.pc });
         }
+        let res = match signed {
+            true => (a as i32).wrapping_rem(b as i32) as u32,
+            false => a % b
+        };
+        self.set_alu_flags(res, false, false);
+        Ok(res)
+    }
+
+    /// shifts or rotates `a` by the low 5 bits of `n`. CARRY receives the last
+    /// bit shifted (or rotated) out, and is cleared for a zero count
+    fn alu_shift(&mut self, op: Shift, a: u32, n: u32) -> u32 {
+        let n = n & 0x1f;
+        let (res, carry) = match op {
+            Shift::Shl => (a << n, n != 0 && (a >> (32 - n)) & 1 != 0),
+            Shift::Shr => (a >> n, n != 0 && (a >> (n - 1)) & 1 != 0),
+            Shift::Sar => (((a as i32) >> n) as u32, n != 0 && (a >> (n - 1)) & 1 != 0),
+            Shift::Rol => {
+                let r = a.rotate_left(n);
+                (r, n != 0 && r & 1 != 0)
+            },
+            Shift::Ror => {
+                let r = a.rotate_right(n);
+                (r, n != 0 && r & 0x80000000 != 0)
+            }
+        };
+        self.set_alu_flags(res, carry, false);
+        res
     }
 
     /// adds value in `src` into `dest`
-    fn add_reg(&mut self) -> Result<usize, String> {
-        let dest = (self.memory[self.pc + 1] & 0xf0) >> 4; 
-        let src = self.memory[self.pc + 1] & 0x0f;
+    fn add_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
 
         debug!("ADD r{},r{}", dest, src);
-        let v = self.get_reg(src)?;
-        
-        match dest {
-            0 => self.r0 += v,
-            1 => self.r1 += v,
-            2 => self.r2 += v,
-            3 => self.r3 += v,
-            _ => return Err(format!("Illicit destination value {}", dest))        
-        };
+        let a = self.get_reg(dest)?;
+        let b = self.get_reg(src)?;
+        let v = self.alu_add(a, b, false);
+        self.set_reg(dest, v)?;
         Ok(2)
     }
 
-    fn add_imm(&mut self) -> Result<usize, String> {
-        let dest = self.memory[self.pc + 1];
-        let src = self.memory.get_u32(self.pc + 2)?;
+    /// adds `imm` into `dest`
+    fn add_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_imm()?;
 
         debug!("ADDI r{},0x{:x}", dest, src);
-        match dest {
-            0 => self.r0 += src,
-            1 => self.r1 += src,
-            2 => self.r2 += src,
-            3 => self.r3 += src,
-            _ => return Err(format!("Illicit destination value {}", dest))            
-        };
+        let a = self.get_reg(dest)?;
+        let v = self.alu_add(a, src, false);
+        self.set_reg(dest, v)?;
+        Ok(6)
+    }
+
+    /// adds value in `src` plus the CARRY flag into `dest`
+    fn adc_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
+
+        debug!("ADC r{},r{}", dest, src);
+        let a = self.get_reg(dest)?;
+        let b = self.get_reg(src)?;
+        let v = self.alu_add(a, b, self.is_flag_set(FLAG_CARRY));
+        self.set_reg(dest, v)?;
+        Ok(2)
+    }
+
+    /// adds `imm` plus the CARRY flag into `dest`
+    fn adc_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_imm()?;
 
+        debug!("ADCI r{},0x{:x}", dest, src);
+        let a = self.get_reg(dest)?;
+        let v = self.alu_add(a, src, self.is_flag_set(FLAG_CARRY));
+        self.set_reg(dest, v)?;
         Ok(6)
     }
 
     /// performs logical AND operation, storing result in `dest`
-    fn and_reg(&mut self) -> Result<usize, String> {
-        let dest = (self.memory[self.pc + 1] & 0xf0) >> 4; 
-        let src = self.memory[self.pc + 1] & 0x0f;
+    fn and_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
 
         debug!("AND r{},r{}", dest, src);
-        let o = self.get_reg(src)?;
-        match dest {
-            0 => self.r0 &= o,
-            1 => self.r1 &= o,
-            2 => self.r2 &= o,
-            3 => self.r3 &= o,
-            _ => return Err(format!("Illicit destination value {}", dest))         
-        }
+        let v = self.get_reg(dest)? & self.get_reg(src)?;
+        let v = self.alu_logic(v);
+        self.set_reg(dest, v)?;
         Ok(2)
     }
 
     /// performs logical AND operation, storing result in `dest`
-    fn and_imm(&mut self) -> Result<usize, String> {
-        let dest = self.memory[self.pc + 1];
-        let src = self.memory.get_u32(self.pc + 2)?;
+    fn and_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_imm()?;
 
         debug!("ANDI r{},0x{:x}", dest, src);
-        match dest {
-            0 => self.r0 &= src,
-            1 => self.r1 &= src,
-            2 => self.r2 &= src,
-            3 => self.r3 &= src,
-            _ => return Err(format!("Illicit destination value {}", dest))           
-        }
-
+        let v = self.get_reg(dest)? & src;
+        let v = self.alu_logic(v);
+        self.set_reg(dest, v)?;
         Ok(6)
     }
 
     /// performs logical OR operation, storing result in `dest`
-    fn or_reg(&mut self) -> Result<usize, String> {
-        let dest = (self.memory[self.pc + 1] & 0xf0) >> 4; 
-        let src = self.memory[self.pc + 1] & 0x0f;
-
+    fn or_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
 
         debug!("OR r{},r{}", dest, src);
-        let o = self.get_reg(src)?;
-        match dest {
-            0 => self.r0 |= o,
-            1 => self.r1 |= o,
-            2 => self.r2 |= o,
-            3 => self.r3 |= o,
-            _ => return Err(format!("Illicit destination value {}", dest))         
-        }
-
+        let v = self.get_reg(dest)? | self.get_reg(src)?;
+        let v = self.alu_logic(v);
+        self.set_reg(dest, v)?;
         Ok(2)
     }
 
     /// performs logical OR operation, storing result in `dest`
-    fn or_imm(&mut self) -> Result<usize, String> {
-        let dest = self.memory[self.pc + 1];
-        let src = self.memory.get_u32(self.pc + 2)?;
+    fn or_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_imm()?;
 
         debug!("ORI r{},0x{:x}", dest, src);
-        match dest {
-            0 => self.r0 |= src,
-            1 => self.r1 |= src,
-            2 => self.r2 |= src,
-            3 => self.r3 |= src,
-            _ => return Err(format!("Illicit destination value {}", dest))           
-        }
-
+        let v = self.get_reg(dest)? | src;
+        let v = self.alu_logic(v);
+        self.set_reg(dest, v)?;
         Ok(6)
     }
 
     /// performs logical XOR operation, storing result in `dest`
-    fn xor_reg(&mut self) -> Result<usize, String> {
-        let dest = (self.memory[self.pc + 1] & 0xf0) >> 4; 
-        let src = self.memory[self.pc + 1] & 0x0f;
-
+    fn xor_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
 
         debug!("XOR r{},r{}", dest, src);
-        let o = self.get_reg(src)?;
-        match dest {
-            0 => self.r0 ^= o,
-            1 => self.r1 ^= o,
-            2 => self.r2 ^= o,
-            3 => self.r3 ^= o,
-            _ => return Err(format!("Illicit destination value {}", dest))            
-        }
-
+        let v = self.get_reg(dest)? ^ self.get_reg(src)?;
+        let v = self.alu_logic(v);
+        self.set_reg(dest, v)?;
         Ok(2)
     }
 
     /// performs logical XOR operation, storing result in `dest`
-    fn xor_imm(&mut self) -> Result<usize, String> {
-        let dest = self.memory[self.pc + 1];
-        let src = self.memory.get_u32(self.pc + 2)?;
+    fn xor_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_imm()?;
 
         debug!("XORI r{},0x{:x}", dest, src);
-        match dest {
-            0 => self.r0 ^= src,
-            1 => self.r1 ^= src,
-            2 => self.r2 ^= src,
-            3 => self.r3 ^= src,
-            _ => return Err(format!("Illicit destination value {}", dest))         
-        }
-
+        let v = self.get_reg(dest)? ^ src;
+        let v = self.alu_logic(v);
+        self.set_reg(dest, v)?;
         Ok(6)
     }
 
-    /// compares two register values, storing success in
-    fn cmp_reg(&mut self) -> Result<usize, String> {
-        let test = (self.memory[self.pc + 1] & 0xf0) >> 4; 
-        let src = self.memory[self.pc + 1] & 0x0f;
+    /// compares `test` against `other` by subtracting them, setting the 
+    /// arithmetic flags plus GREATER (unsigned) without storing the result
+    fn compare(&mut self, test: u32, other: u32) {
+        self.alu_sub(test, other, false);
+        if test > other {
+            self.fl |= FLAG_GREATER;
+        } else {
+            self.fl &= !FLAG_GREATER;
+        }
+    }
 
+    /// compares two register values, setting the flags accordingly
+    fn cmp_reg(&mut self) -> Result<usize, CpuFault> {
+        let (test, src) = self.reg_pair()?;
 
         debug!("CMP r{},r{}", test, src);
-        
-        let o = self.get_reg(src)? as i64;
-        let t = self.get_reg(test)? as i64;
-        let v = match t-o {
-            0 => 1,
-            _ => 0
-        };
-        self.fl |= v;
-
+        let t = self.get_reg(test)?;
+        let o = self.get_reg(src)?;
+        self.compare(t, o);
         Ok(2)
     }
 
-    /// compares two register values, storing success in
-    fn cmp_imm(&mut self) -> Result<usize, String> {
-        let test = self.memory[self.pc + 1];
-        let src = self.memory.get_u32(self.pc + 2)?;
+    /// compares a register value against `imm`, setting the flags accordingly
+    fn cmp_imm(&mut self) -> Result<usize, CpuFault> {
+        let (test, src) = self.reg_imm()?;
 
         debug!("CMPI r{},0x{:x}", test, src);
-        
-        let t = self.get_reg(test)? as i64;
-        let src = src as i64;
-        debug!("\t{}-{}\n\n\n\n", t, src);
-        let v = match t-src {
-            0 => 1,
-            _ => 0
-        };
-        self.fl |= v;
+        let t = self.get_reg(test)?;
+        self.compare(t, src);
         Ok(6)
     }
 
     /// sets the flag value according to `val` and `flags`
-    fn sfg_imm(&mut self) -> Result<usize, String> {
-        let flag = self.memory[self.pc + 1];
-        let val = self.memory[self.pc + 2];
+    fn sfg_imm(&mut self) -> Result<usize, CpuFault> {
+        let flag = self.memory.read_u8(self.pc + 1)?;
+        let val = self.memory.read_u8(self.pc + 2)?;
         
         debug!("SFGI 0x{:x}, 0x{:x}", flag, val);
         self.fl ^= val << flag;
         Ok(3)
     }
 
-    fn sfg_reg(&mut self) -> Result<usize, String> {
-        let r = self.memory[self.pc + 1];
-        let val = self.memory[self.pc + 2];
+    fn sfg_reg(&mut self) -> Result<usize, CpuFault> {
+        let r = self.memory.read_u8(self.pc + 1)?;
+        let val = self.memory.read_u8(self.pc + 2)?;
         let flag = self.get_reg(r)? as u8;
 
         debug!("SFGR 0x{:x}, 0x{:x}", flag, val);
@@ -404,167 +1091,456 @@ impl CPU {
     }
 
     /// loads a 32-bit value from `addr` into `dest`
-    fn ld_imm(&mut self) -> Result<usize, String> {
-        let dest = self.memory[self.pc + 1]; 
-        let addr = self.memory.get_u32(self.pc+2)? as usize;
+    fn ld_imm(&mut self) -> Result<usize, CpuFault> {
+        let dest = self.memory.read_u8(self.pc + 1)?; 
+        let addr = self.memory.read_u32(self.pc+2)? as usize;
 
         debug!("LOAD r{}, 0x{:x}", dest, addr);
-        match dest {
-            0 => self.r0 = self.memory.get_u32(addr).unwrap(),
-            1 => self.r1 = self.memory.get_u32(addr).unwrap(),
-            2 => self.r2 = self.memory.get_u32(addr).unwrap(),
-            3 => self.r3 = self.memory.get_u32(addr).unwrap(),
-            _ => return Err(format!("Illicit destination value {}", dest))          
-        }
+        let v = self.memory.read_u32(addr)?;
+        self.set_reg(dest, v)?;
 
         Ok(6)
     }
 
     /// loads a 32-bit value from `src` into `dest`
-    fn ld_reg(&mut self) -> Result<usize, String> {
-        let dest = (self.memory[self.pc + 1] & 0xf0) >> 4; 
-        let src = self.memory[self.pc + 1] & 0x0f;
+    fn ld_reg(&mut self) -> Result<usize, CpuFault> {
+        let dest = (self.memory.read_u8(self.pc + 1)? & 0xf0) >> 4; 
+        let src = self.memory.read_u8(self.pc + 1)? & 0x0f;
 
         let addr = self.get_reg(src)? as usize;
 
         debug!("LOAD r{}, r{}", dest, src);
-        match dest {
-            0 => self.r0 = self.memory.get_u32(addr).unwrap(),
-            1 => self.r1 = self.memory.get_u32(addr).unwrap(),
-            2 => self.r2 = self.memory.get_u32(addr).unwrap(),
-            3 => self.r3 = self.memory.get_u32(addr).unwrap(),
-            _ => return Err(format!("Illicit destination value {}", dest))          
-        }
+        let v = self.memory.read_u32(addr)?;
+        self.set_reg(dest, v)?;
 
         Ok(2)
     }
 
-    /// multiplies `dest` with `src`, storing in `dest`
-    fn mul_reg(&mut self) -> Result<usize, String> {
-        let dest = (self.memory[self.pc + 1] & 0xf0) >> 4; 
-        let src = self.memory[self.pc + 1] & 0x0f;
+    /// decodes the register and memory operand of a load/store/lea, returning
+    /// the register and the effective address
+    fn mem_operand(&self) -> Result<(u8, usize), CpuFault> {
+        let (reg, base) = self.reg_pair()?;
+        let mode = self.memory.read_u8(self.pc + 2)?;
+        let disp = self.memory.read_u32(self.pc + 3)?;
+        let op = MemOperand::decode(base, mode, disp);
+
+        let addr = match op.mode {
+            AddrMode::BaseDisp => self.get_reg(op.base)?,
+            AddrMode::BaseIndex => {
+                let index = self.get_reg(op.index)?.wrapping_mul(op.scale as u32);
+                self.get_reg(op.base)?.wrapping_add(index)
+            },
+            AddrMode::PcRelative => self.pc as u32,
+            AddrMode::Absolute => 0
+        };
+        Ok((reg, addr.wrapping_add(op.disp) as usize))
+    }
+
+    /// computes the effective address of the memory operand into `dest`
+    /// without accessing memory
+    fn lea(&mut self) -> Result<usize, CpuFault> {
+        let (dest, addr) = self.mem_operand()?;
+
+        debug!("LEA r{}, 0x{:x}", dest, addr);
+        self.set_reg(dest, addr as u32)?;
+        Ok(7)
+    }
 
+    /// loads a byte from the memory operand into `dest`, zero-extending it
+    fn ldb(&mut self) -> Result<usize, CpuFault> {
+        let (dest, addr) = self.mem_operand()?;
+
+        debug!("LDB r{}, [0x{:x}]", dest, addr);
+        let v = self.memory.read_u8(addr)? as u32;
+        self.set_reg(dest, v)?;
+        Ok(7)
+    }
+
+    /// loads a byte from the memory operand into `dest`, sign-extending it
+    fn ldbs(&mut self) -> Result<usize, CpuFault> {
+        let (dest, addr) = self.mem_operand()?;
+
+        debug!("LDBS r{}, [0x{:x}]", dest, addr);
+        let v = self.memory.read_u8(addr)? as i8 as i32 as u32;
+        self.set_reg(dest, v)?;
+        Ok(7)
+    }
+
+    /// loads a halfword from the memory operand into `dest`, zero-extending it
+    fn ldh(&mut self) -> Result<usize, CpuFault> {
+        let (dest, addr) = self.mem_operand()?;
+
+        debug!("LDH r{}, [0x{:x}]", dest, addr);
+        let v = self.memory.read_u16(addr)? as u32;
+        self.set_reg(dest, v)?;
+        Ok(7)
+    }
+
+    /// loads a halfword from the memory operand into `dest`, sign-extending it
+    fn ldhs(&mut self) -> Result<usize, CpuFault> {
+        let (dest, addr) = self.mem_operand()?;
+
+        debug!("LDHS r{}, [0x{:x}]", dest, addr);
+        let v = self.memory.read_u16(addr)? as i16 as i32 as u32;
+        self.set_reg(dest, v)?;
+        Ok(7)
+    }
+
+    /// loads a word from the memory operand into `dest`
+    fn ldw(&mut self) -> Result<usize, CpuFault> {
+        let (dest, addr) = self.mem_operand()?;
+
+        debug!("LDW r{}, [0x{:x}]", dest, addr);
+        let v = self.memory.read_u32(addr)?;
+        self.set_reg(dest, v)?;
+        Ok(7)
+    }
+
+    /// stores the low byte of `src` to the memory operand
+    fn stb(&mut self) -> Result<usize, CpuFault> {
+        let (src, addr) = self.mem_operand()?;
+
+        debug!("STB [0x{:x}], r{}", addr, src);
+        let v = self.get_reg(src)? as u8;
+        self.memory.write_u8(addr, v)?;
+        Ok(7)
+    }
+
+    /// stores the low halfword of `src` to the memory operand
+    fn sth(&mut self) -> Result<usize, CpuFault> {
+        let (src, addr) = self.mem_operand()?;
+
+        debug!("STH [0x{:x}], r{}", addr, src);
+        let v = self.get_reg(src)? as u16;
+        self.memory.write_u16(addr, v)?;
+        Ok(7)
+    }
+
+    /// stores `src` to the memory operand
+    fn stw(&mut self) -> Result<usize, CpuFault> {
+        let (src, addr) = self.mem_operand()?;
+
+        debug!("STW [0x{:x}], r{}", addr, src);
+        let v = self.get_reg(src)?;
+        self.memory.write_u32(addr, v)?;
+        Ok(7)
+    }
+
+    /// multiplies `dest` with `src`, storing in `dest`
+    fn mul_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
 
         debug!("MUL r{},r{}", dest, src);
-        let o = self.get_reg(src)?;
-        match dest {
-            0 => self.r0 *= o,
-            1 => self.r1 *= o,
-            2 => self.r2 *= o,
-            3 => self.r3 *= o,
-            _ => return Err(format!("Illicit destination value {}", dest))        
-        }
+        let a = self.get_reg(dest)?;
+        let b = self.get_reg(src)?;
+        let v = self.alu_mul(a, b);
+        self.set_reg(dest, v)?;
         Ok(2)
     }
 
     /// multiplies `dest` with `src`, storing in `dest`
-    fn mul_imm(&mut self) -> Result<usize, String> {
-        let dest = self.memory[self.pc + 1]; 
-        let src = self.memory.get_u32(self.pc + 2)?;
-
-        debug!("MULI r{},r{}", dest, src);
-        match dest {
-            0 => self.r0 *= src,
-            1 => self.r1 *= src,
-            2 => self.r2 *= src,
-            3 => self.r3 *= src,
-            _ => return Err(format!("Illicit destination value {}", dest))           
-        }
+    fn mul_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_imm()?;
 
+        debug!("MULI r{},0x{:x}", dest, src);
+        let a = self.get_reg(dest)?;
+        let v = self.alu_mul(a, src);
+        self.set_reg(dest, v)?;
         Ok(6)
     }
 
-    /// subtracts `dest` with `src`, storing in `dest`
-    fn sub_reg(&mut self) -> Result<usize, String> {
-        let dest = (self.memory[self.pc + 1] & 0xf0) >> 4; 
-        let src = self.memory[self.pc + 1] & 0x0f;
+    /// subtracts `src` from `dest`, storing in `dest`
+    fn sub_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
 
         debug!("SUB r{},r{}", dest, src);
-        let o = self.get_reg(src)?;
-        match dest {
-            0 => self.r0 -= o,
-            1 => self.r1 -= o,
-            2 => self.r2 -= o,
-            3 => self.r3 -= o,
-            _ => return Err(format!("Illicit destination value {}", dest))          
-        }
+        let a = self.get_reg(dest)?;
+        let b = self.get_reg(src)?;
+        let v = self.alu_sub(a, b, false);
+        self.set_reg(dest, v)?;
+        Ok(2)
+    }
+
+    /// subtracts `src` from `dest`, storing in `dest`
+    fn sub_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_imm()?;
+
+        debug!("SUBI r{},0x{:x}", dest, src);
+        let a = self.get_reg(dest)?;
+        let v = self.alu_sub(a, src, false);
+        self.set_reg(dest, v)?;
+        Ok(6)
+    }
+
+    /// subtracts `src` and the CARRY (borrow) flag from `dest`, storing in `dest`
+    fn sbb_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
 
+        debug!("SBB r{},r{}", dest, src);
+        let a = self.get_reg(dest)?;
+        let b = self.get_reg(src)?;
+        let v = self.alu_sub(a, b, self.is_flag_set(FLAG_CARRY));
+        self.set_reg(dest, v)?;
         Ok(2)
     }
 
-    /// subtracts `dest` with `src`, storing in `dest`
-    fn sub_imm(&mut self) -> Result<usize, String> {
-        let dest = self.memory[self.pc + 1]; 
-        let src = self.memory.get_u32(self.pc + 2)?;
+    /// subtracts `imm` and the CARRY (borrow) flag from `dest`, storing in `dest`
+    fn sbb_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_imm()?;
 
-        debug!("SUB r{},0x{:x}", dest, src);
-        match dest {
-            0 => self.r0 -= src,
-            1 => self.r1 -= src,
-            2 => self.r2 -= src,
-            3 => self.r3 -= src,
-            _ => return Err(format!("Illicit destination value {}", dest))          
-        }
+        debug!("SBBI r{},0x{:x}", dest, src);
+        let a = self.get_reg(dest)?;
+        let v = self.alu_sub(a, src, self.is_flag_set(FLAG_CARRY));
+        self.set_reg(dest, v)?;
+        Ok(6)
+    }
+
+    /// divides `dest` by `src` (unsigned), storing the quotient in `dest`
+    fn div_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
+
+        debug!("DIV r{},r{}", dest, src);
+        let a = self.get_reg(dest)?;
+        let b = self.get_reg(src)?;
+        let v = self.alu_div(a, b, false)?;
+        self.set_reg(dest, v)?;
+        Ok(2)
+    }
+
+    /// divides `dest` by `imm` (unsigned), storing the quotient in `dest`
+    fn div_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, b) = self.reg_imm()?;
+
+        debug!("DIVI r{},0x{:x}", dest, b);
+        let a = self.get_reg(dest)?;
+        let v = self.alu_div(a, b, false)?;
+        self.set_reg(dest, v)?;
+        Ok(6)
+    }
+
+    /// divides `dest` by `src` (signed), storing the quotient in `dest`
+    fn sdiv_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
 
+        debug!("SDIV r{},r{}", dest, src);
+        let a = self.get_reg(dest)?;
+        let b = self.get_reg(src)?;
+        let v = self.alu_div(a, b, true)?;
+        self.set_reg(dest, v)?;
+        Ok(2)
+    }
+
+    /// divides `dest` by `imm` (signed), storing the quotient in `dest`
+    fn sdiv_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, b) = self.reg_imm()?;
+
+        debug!("SDIVI r{},0x{:x}", dest, b);
+        let a = self.get_reg(dest)?;
+        let v = self.alu_div(a, b, true)?;
+        self.set_reg(dest, v)?;
+        Ok(6)
+    }
+
+    /// divides `dest` by `src` (unsigned), storing the remainder in `dest`
+    fn mod_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
+
+        debug!("MOD r{},r{}", dest, src);
+        let a = self.get_reg(dest)?;
+        let b = self.get_reg(src)?;
+        let v = self.alu_rem(a, b, false)?;
+        self.set_reg(dest, v)?;
+        Ok(2)
+    }
+
+    /// divides `dest` by `imm` (unsigned), storing the remainder in `dest`
+    fn mod_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, b) = self.reg_imm()?;
+
+        debug!("MODI r{},0x{:x}", dest, b);
+        let a = self.get_reg(dest)?;
+        let v = self.alu_rem(a, b, false)?;
+        self.set_reg(dest, v)?;
+        Ok(6)
+    }
+
+    /// divides `dest` by `src` (signed), storing the remainder in `dest`
+    fn smod_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
+
+        debug!("SMOD r{},r{}", dest, src);
+        let a = self.get_reg(dest)?;
+        let b = self.get_reg(src)?;
+        let v = self.alu_rem(a, b, true)?;
+        self.set_reg(dest, v)?;
+        Ok(2)
+    }
+
+    /// divides `dest` by `imm` (signed), storing the remainder in `dest`
+    fn smod_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, b) = self.reg_imm()?;
+
+        debug!("SMODI r{},0x{:x}", dest, b);
+        let a = self.get_reg(dest)?;
+        let v = self.alu_rem(a, b, true)?;
+        self.set_reg(dest, v)?;
+        Ok(6)
+    }
+
+    /// shifts `dest` left by `src` bits
+    fn shl_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
+
+        debug!("SHL r{},r{}", dest, src);
+        let a = self.get_reg(dest)?;
+        let b = self.get_reg(src)?;
+        let v = self.alu_shift(Shift::Shl, a, b);
+        self.set_reg(dest, v)?;
+        Ok(2)
+    }
+
+    /// shifts `dest` left by `imm` bits
+    fn shl_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, b) = self.reg_imm()?;
+
+        debug!("SHLI r{},0x{:x}", dest, b);
+        let a = self.get_reg(dest)?;
+        let v = self.alu_shift(Shift::Shl, a, b);
+        self.set_reg(dest, v)?;
+        Ok(6)
+    }
+
+    /// shifts `dest` right by `src` bits, filling with zeroes
+    fn shr_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
+
+        debug!("SHR r{},r{}", dest, src);
+        let a = self.get_reg(dest)?;
+        let b = self.get_reg(src)?;
+        let v = self.alu_shift(Shift::Shr, a, b);
+        self.set_reg(dest, v)?;
+        Ok(2)
+    }
+
+    /// shifts `dest` right by `imm` bits, filling with zeroes
+    fn shr_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, b) = self.reg_imm()?;
+
+        debug!("SHRI r{},0x{:x}", dest, b);
+        let a = self.get_reg(dest)?;
+        let v = self.alu_shift(Shift::Shr, a, b);
+        self.set_reg(dest, v)?;
+        Ok(6)
+    }
+
+    /// shifts `dest` right by `src` bits, filling with the sign bit
+    fn sar_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
+
+        debug!("SAR r{},r{}", dest, src);
+        let a = self.get_reg(dest)?;
+        let b = self.get_reg(src)?;
+        let v = self.alu_shift(Shift::Sar, a, b);
+        self.set_reg(dest, v)?;
+        Ok(2)
+    }
+
+    /// shifts `dest` right by `imm` bits, filling with the sign bit
+    fn sar_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, b) = self.reg_imm()?;
+
+        debug!("SARI r{},0x{:x}", dest, b);
+        let a = self.get_reg(dest)?;
+        let v = self.alu_shift(Shift::Sar, a, b);
+        self.set_reg(dest, v)?;
+        Ok(6)
+    }
+
+    /// rotates `dest` left by `src` bits
+    fn rol_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
+
+        debug!("ROL r{},r{}", dest, src);
+        let a = self.get_reg(dest)?;
+        let b = self.get_reg(src)?;
+        let v = self.alu_shift(Shift::Rol, a, b);
+        self.set_reg(dest, v)?;
+        Ok(2)
+    }
+
+    /// rotates `dest` left by `imm` bits
+    fn rol_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, b) = self.reg_imm()?;
+
+        debug!("ROLI r{},0x{:x}", dest, b);
+        let a = self.get_reg(dest)?;
+        let v = self.alu_shift(Shift::Rol, a, b);
+        self.set_reg(dest, v)?;
+        Ok(6)
+    }
+
+    /// rotates `dest` right by `src` bits
+    fn ror_reg(&mut self) -> Result<usize, CpuFault> {
+        let (dest, src) = self.reg_pair()?;
+
+        debug!("ROR r{},r{}", dest, src);
+        let a = self.get_reg(dest)?;
+        let b = self.get_reg(src)?;
+        let v = self.alu_shift(Shift::Ror, a, b);
+        self.set_reg(dest, v)?;
+        Ok(2)
+    }
+
+    /// rotates `dest` right by `imm` bits
+    fn ror_imm(&mut self) -> Result<usize, CpuFault> {
+        let (dest, b) = self.reg_imm()?;
+
+        debug!("RORI r{},0x{:x}", dest, b);
+        let a = self.get_reg(dest)?;
+        let v = self.alu_shift(Shift::Ror, a, b);
+        self.set_reg(dest, v)?;
         Ok(6)
     }
 
     /// moves value from `src` (register) into `dest` (register)
-    fn mov_dreg_sreg(&mut self) -> Result<usize, String> {
-        let dest = (self.memory[self.pc + 1] & 0xf0) >> 4; 
-        let src = self.memory[self.pc + 1] & 0x0f;
+    fn mov_dreg_sreg(&mut self) -> Result<usize, CpuFault> {
+        let dest = (self.memory.read_u8(self.pc + 1)? & 0xf0) >> 4; 
+        let src = self.memory.read_u8(self.pc + 1)? & 0x0f;
 
         debug!("MOV r{},r{}", dest, src);
         let o = self.get_reg(src)?;
-        match dest {
-            0 => self.r0 = o,
-            1 => self.r1 = o,
-            2 => self.r2 = o,
-            3 => self.r3 = o,
-            _ => return Err(format!("Illicit destination value {}", dest))           
-        }
+        self.set_reg(dest, o)?;
 
         Ok(2)
     }
 
     /// moves value from `src` (immediate) into `dest` (register)
-    fn mov_dreg_simm(&mut self) -> Result<usize, String> {
-        let dest = self.memory[self.pc + 1]; 
-        let src = self.memory.get_u32(self.pc+2)?;
+    fn mov_dreg_simm(&mut self) -> Result<usize, CpuFault> {
+        let dest = self.memory.read_u8(self.pc + 1)?; 
+        let src = self.memory.read_u32(self.pc+2)?;
 
         debug!("MOVI r{},0x{:x}", dest, src);
-        match dest {
-            0 => self.r0 = src,
-            1 => self.r1 = src,
-            2 => self.r2 = src,
-            3 => self.r3 = src,
-            _ => return Err(format!("Illicit destination value {}", dest))         
-        }
+        self.set_reg(dest, src)?;
 
         Ok(6)
     }
 
     /// moves value from `src` (address) into `dest` (register)
-    fn mov_dreg_saddr(&mut self) -> Result<usize, String> {
-        let dest = self.memory[self.pc+1]; 
-        let src = self.memory.get_u32((self.pc+2) as usize)?;
+    fn mov_dreg_saddr(&mut self) -> Result<usize, CpuFault> {
+        let dest = self.memory.read_u8(self.pc+1)?; 
+        let src = self.memory.read_u32(self.pc+2)?;
 
         debug!("MOVA r{}, 0x{:x}", dest, src);
-        match dest {
-            0 => self.r0 = self.memory.get_u32(src as usize)?,
-            1 => self.r1 = self.memory.get_u32(src as usize)?,
-            2 => self.r2 = self.memory.get_u32(src as usize)?,
-            3 => self.r3 = self.memory.get_u32(src as usize)?,
-            _ => return Err(format!("Illicit destination value {}", dest))           
-        }
+        let v = self.memory.read_u32(src as usize)?;
+        self.set_reg(dest, v)?;
 
         Ok(6)
     }
 
     /// moves value from `src` (register) into `dest` (address)
-    fn mov_daddr_sreg(&mut self) -> Result<usize, String> {
-        let dest = self.memory.get_u32((self.pc+1) as usize)?; 
-        let src = self.memory[self.pc+5];
+    fn mov_daddr_sreg(&mut self) -> Result<usize, CpuFault> {
+        let dest = self.memory.read_u32(self.pc+1)?; 
+        let src = self.memory.read_u8(self.pc+5)?;
 
         debug!("MOVR 0x{:x},r{}", dest, src);
         let o = self.get_reg(src)?;
@@ -573,83 +1549,66 @@ impl CPU {
     }
 
     /// swaps `r1` and `r2`
-    fn swp(&mut self) -> Result<usize, String> {
-        let r1 = (self.memory[self.pc + 1] & 0xf0) >> 4; 
-        let r2 = self.memory[self.pc + 1] & 0x0f;
+    fn swp(&mut self) -> Result<usize, CpuFault> {
+        let r1 = (self.memory.read_u8(self.pc + 1)? & 0xf0) >> 4; 
+        let r2 = self.memory.read_u8(self.pc + 1)? & 0x0f;
 
 
         debug!("SWP r{},r{}", r1, r2);
         let o = self.get_reg(r2)?;
         let t = self.get_reg(r1)?;
 
-        match r2 {
-            0 => self.r0 = t,
-            1 => self.r1 = t,
-            2 => self.r2 = t,
-            3 => self.r3 = t,
-            _ => return Err(format!("Illicit destination value {}", r2))       
-        }
-        match r1 {
-            0 => self.r0 = o,
-            1 => self.r1 = o,
-            2 => self.r2 = o,
-            3 => self.r3 = o,
-            _ => return Err(format!("Illicit destination value {}", r1))           
-        }
+        self.set_reg(r2, t)?;
+        self.set_reg(r1, o)?;
 
         Ok(2)
     }
 
-    /// pushes `val` to the stack
-    fn push_addr(&mut self) -> Result<usize, String> {
-        let val = self.memory.get_u32(self.pc + 1)?;
+    /// pushes `val` to the stack. `sp` only moves once the write succeeds,
+    /// so a push that page faults can be run again
+    fn push_addr(&mut self) -> Result<usize, CpuFault> {
+        let val = self.memory.read_u32(self.pc + 1)?;
         debug!("PUSHA 0x{:x}", val);
 
+        self.memory.write_u32(self.sp + 4, val)?;
         self.sp += 4;
-        self.memory.write_u32(self.sp, val)?;
         Ok(5)
     }
     
     /// pushes `val` to the stack
-    fn push_reg(&mut self) -> Result<usize, String> {
-        let reg = self.memory[self.pc+1];
+    fn push_reg(&mut self) -> Result<usize, CpuFault> {
+        let reg = self.memory.read_u8(self.pc+1)?;
         debug!("PUSH r{}", reg);
         
         let val = self.get_reg(reg)?;
+        self.memory.write_u32(self.sp + 4, val)?;
         self.sp += 4;
-        self.memory.write_u32(self.sp, val)?;
         Ok(2)
     }
 
     /// pops the top value from the stack into `dest`
-    fn pop(&mut self) -> Result<usize, String> {
-        let dest = self.memory[self.pc + 1];
+    fn pop(&mut self) -> Result<usize, CpuFault> {
+        let dest = self.memory.read_u8(self.pc + 1)?;
 
         debug!("POP r{}", dest);
 
-        let o = self.memory.get_u32(self.sp)?;
+        let o = self.memory.read_u32(self.sp)?;
         self.sp -= 4;
 
-        match dest {
-            0 => self.r0 = o,
-            1 => self.r1 = o,
-            2 => self.r2 = o,
-            3 => self.r3 = o,
-            _ => return Err(format!("Illicit destination value {}", dest))      
-        }
+        self.set_reg(dest, o)?;
 
         Ok(2)
     }
 
     /// does nothing
-    fn nop(&mut self) -> Result<usize, String> {
+    fn nop(&mut self) -> Result<usize, CpuFault> {
         debug!("NOP");
         Ok(1)
     }
 
     /// performs a long jump 
-    fn jmp_addr(&mut self) -> Result<usize, String> {
-        let addr = self.memory.get_u32(self.pc+1)? as usize;
+    fn jmp_addr(&mut self) -> Result<usize, CpuFault> {
+        let addr = self.memory.read_u32(self.pc+1)? as usize;
         debug!("JMPL 0x{}", addr);
         self.pc = addr;
 
@@ -657,24 +1616,24 @@ impl CPU {
     }
     
     /// performs a short jump to offset 
-    fn jmp_imm(&mut self) -> Result<usize, String> {
-        let a = self.memory.get_u32(self.pc+1)?;
+    fn jmp_imm(&mut self) -> Result<usize, CpuFault> {
+        let a = self.memory.read_u32(self.pc+1)?;
         let short = convert_to_signed(a);
                 
 
         debug!("JMPI 0x{:x}", short);
         if short < 0 {
-            self.pc -= short.abs() as usize;
+            self.pc -= short.unsigned_abs() as usize;
         } else {
-            self.pc += short.abs() as usize;
+            self.pc += short.unsigned_abs() as usize;
         }
 
         Ok(5)
     }
 
     /// performs a jump to an offset stored in a register
-    fn jmp_reg(&mut self) -> Result<usize, String> {
-        let reg = self.memory[self.pc+1];
+    fn jmp_reg(&mut self) -> Result<usize, CpuFault> {
+        let reg = self.memory.read_u8(self.pc+1)?;
 
         debug!("JMP r{}", reg);
         self.pc = self.get_reg(reg)? as usize;
@@ -683,8 +1642,8 @@ impl CPU {
     }
     
     /// performs a jump to an offset stored in a register
-    fn jeq_imm(&mut self) -> Result<usize, String> {
-        let imm = self.memory.get_u32(self.pc+1)?;
+    fn jeq_imm(&mut self) -> Result<usize, CpuFault> {
+        let imm = self.memory.read_u32(self.pc+1)?;
 
         debug!("JEQI 0x{:x}", imm);
         if self.fl & 0x1 == 1 {
@@ -697,8 +1656,8 @@ impl CPU {
     }
 
     /// performs a jump to an offset stored in a register
-    fn jeq_reg(&mut self) -> Result<usize, String> {
-        let reg = self.memory[self.pc+1];
+    fn jeq_reg(&mut self) -> Result<usize, CpuFault> {
+        let reg = self.memory.read_u8(self.pc+1)?;
 
         debug!("JEQ r{}", reg);
         let o = self.get_reg(reg)?;
@@ -714,37 +1673,434 @@ impl CPU {
     }
 
     /// handles an immediate interrupt
-    fn int_imm(&mut self) -> Result<usize, String> {
-        let code = self.memory.get_u32(self.pc+1)?;
+    fn int_imm(&mut self) -> Result<usize, CpuFault> {
+        let code = self.memory.read_u32(self.pc+1)?;
         debug!("INTI 0x{:x}", code);
         self.handle_interrupt(code)
     }
 
     /// handles an interrupt in a register
-    fn int_reg(&mut self) -> Result<usize, String> {
-        let code = self.get_reg(self.memory[self.pc+1])?;
+    fn int_reg(&mut self) -> Result<usize, CpuFault> {
+        let code = self.get_reg(self.memory.read_u8(self.pc+1)?)?;
         debug!("INTR 0x{:x}", code);
         self.handle_interrupt(code)
     }
 
     /// handles interrupt codes
-    fn handle_interrupt(&mut self, code: u32) -> Result<usize, String> {
+    fn handle_interrupt(&mut self, code: u32) -> Result<usize, CpuFault> {
         let int_func = match self.interrupt_table.get(&code) {
             Some(a) => a,
-            None => return Err(format!("Unknown interrupt code {:x}", code))
+            None => return Err(CpuFault::UnknownInterrupt { pc: self.pc, code })
         };
 
         int_func(self)
     }
 
-    /// halt the program
-    fn hlt(&mut self) -> ! {
+    /// faults unless the CPU is in supervisor mode
+    fn privileged(&self) -> Result<(), CpuFault> {
+        match self.is_user_mode() {
+            true => Err(CpuFault::Privileged { pc: self.pc }),
+            false => Ok(())
+        }
+    }
+
+    /// reads control register `cr` into `dest`
+    fn rdcr(&mut self) -> Result<usize, CpuFault> {
+        self.privileged()?;
+        let (dest, cr) = self.reg_imm()?;
+
+        debug!("RDCR r{}, cr{}", dest, cr);
+        let v = match u8::try_from(cr) {
+            Ok(a) => self.control_reg(a),
+            Err(_) => 0
+        };
+        self.set_reg(dest, v)?;
+        Ok(6)
+    }
+
+    /// writes `src` into control register `cr`
+    fn wrcr(&mut self) -> Result<usize, CpuFault> {
+        self.privileged()?;
+        let cr = self.memory.read_u32(self.pc + 1)?;
+        let src = self.memory.read_u8(self.pc + 5)?;
+
+        debug!("WRCR cr{}, r{}", cr, src);
+        let v = self.get_reg(src)?;
+        if let Ok(a) = u8::try_from(cr) {
+            self.set_control_reg(a, v);
+        }
+        Ok(6)
+    }
+
+    /// returns from a trap handler to the saved pc, flags and mode
+    fn iret(&mut self) -> Result<usize, CpuFault> {
+        self.privileged()?;
+
+        debug!("IRET to 0x{:x}", self.cr[CR_EPC as usize]);
+        self.pc = self.cr[CR_EPC as usize] as usize;
+        self.fl = self.cr[CR_EFLAGS as usize] as u8;
+        self.cr[CR_STATUS as usize] = self.cr[CR_ESTATUS as usize] & STATUS_MASK;
+        self.sync_mmu();
+        Ok(1)
+    }
+
+    /// stops interrupt requests from being delivered
+    fn cli(&mut self) -> Result<usize, CpuFault> {
+        self.privileged()?;
+
+        debug!("CLI");
+        self.cr[CR_STATUS as usize] &= !STATUS_IE;
+        Ok(1)
+    }
+
+    /// lets interrupt requests be delivered, starting after this instruction
+    fn sti(&mut self) -> Result<usize, CpuFault> {
+        self.privileged()?;
+
+        debug!("STI");
+        self.cr[CR_STATUS as usize] |= STATUS_IE;
+        Ok(1)
+    }
+
+    /// halt the program, leaving `pc` on the `hlt`
+    fn hlt(&mut self) -> Result<usize, CpuFault> {
         debug!("HLT");
-        std::process::exit(1);
-    } 
+        self.stop = Some(RunOutcome::Halted(self.get_reg(0)?));
+        Ok(1)
+    }
 
+    /// stops the run loop so a debugger can take over
+    fn brk(&mut self) -> Result<usize, CpuFault> {
+        debug!("BRK");
+        self.stop = Some(RunOutcome::Breakpoint(self.pc));
+        Ok(1)
+    }
+
+}
+
+
+
+/// the vector a fault is delivered to as an exception, and the detail that
+/// goes in `CR_BADADDR`. `None` for faults in the host rather than the 
+/// program, which always stop it
+fn exception(fault: &CpuFault) -> Option<(u32, u32)> {
+    match fault {
+        CpuFault::DivideByZero { .. } => Some((VEC_DIVIDE, 0)),
+        CpuFault::IllegalOpcode { opcode, .. } => Some((VEC_ILLEGAL, *opcode as u32)),
+        CpuFault::IllegalRegister { reg, .. } => Some((VEC_ILLEGAL, *reg as u32)),
+        CpuFault::Privileged { .. } => Some((VEC_PROTECTION, 0)),
+        CpuFault::UnknownInterrupt { code, .. } => Some((VEC_UNKNOWN_INT, *code)),
+        CpuFault::Memory { fault, .. } => match fault {
+            MemFault::Unmapped { addr, .. } | MemFault::Protected { addr, .. } => Some((VEC_PROTECTION, *addr as u32)),
+            MemFault::PageFault { addr, .. } => Some((VEC_PAGE_FAULT, *addr as u32)),
+            MemFault::Swap { .. } => None
+        },
+        CpuFault::Io { .. } | CpuFault::UnhandledIrq { .. } => None
+    }
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::assemble;
+
+    /// assembles and runs `source` on the default machine until it stops
+    fn run(source: &str) -> (CPU, RunOutcome) {
+        let mut cpu = CPU::new(&MachineConfig::default());
+        cpu.load(&assemble(source).unwrap().image()).unwrap();
+        cpu.set_step_limit(Some(1000));
+        let outcome = cpu.run();
+        (cpu, outcome)
+    }
+
+    fn cpu() -> CPU {
+        CPU::new(&MachineConfig::default())
+    }
+
+    #[test]
+    fn add_sets_carry_and_overflow() {
+        let mut cpu = cpu();
+        assert_eq!(cpu.alu_add(0xffffffff, 1, false), 0);
+        assert_eq!(cpu.flags(), FLAG_ZERO | FLAG_CARRY);
+        assert_eq!(cpu.alu_add(0x7fffffff, 1, false), 0x80000000);
+        assert_eq!(cpu.flags(), FLAG_OVERFLOW | FLAG_NEGATIVE);
+        assert_eq!(cpu.alu_add(0x80000000, 0x80000000, false), 0);
+        assert_eq!(cpu.flags(), FLAG_ZERO | FLAG_CARRY | FLAG_OVERFLOW);
+        assert_eq!(cpu.alu_add(2, 3, false), 5);
+        assert_eq!(cpu.flags(), 0);
+    }
+
+    #[test]
+    fn add_carries_in() {
+        let mut cpu = cpu();
+        assert_eq!(cpu.alu_add(2, 3, true), 6);
+        // the carry in alone can carry out
+        assert_eq!(cpu.alu_add(0xffffffff, 0, true), 0);
+        assert_eq!(cpu.flags(), FLAG_ZERO | FLAG_CARRY);
+        assert_eq!(cpu.alu_add(0x7fffffff, 0, true), 0x80000000);
+        assert_eq!(cpu.flags(), FLAG_OVERFLOW | FLAG_NEGATIVE);
+    }
+
+    #[test]
+    fn sub_sets_borrow_and_overflow() {
+        let mut cpu = cpu();
+        assert_eq!(cpu.alu_sub(0, 1, false), 0xffffffff);
+        assert_eq!(cpu.flags(), FLAG_CARRY | FLAG_NEGATIVE);
+        assert_eq!(cpu.alu_sub(0x80000000, 1, false), 0x7fffffff);
+        assert_eq!(cpu.flags(), FLAG_OVERFLOW);
+        assert_eq!(cpu.alu_sub(7, 7, false), 0);
+        assert_eq!(cpu.flags(), FLAG_ZERO);
+        assert_eq!(cpu.alu_sub(7, 7, true), 0xffffffff);
+        assert_eq!(cpu.flags(), FLAG_CARRY | FLAG_NEGATIVE);
+        // the borrow in alone can borrow out
+        assert_eq!(cpu.alu_sub(0, 0, true), 0xffffffff);
+        assert!(cpu.is_flag_set(FLAG_CARRY));
+    }
+
+    #[test]
+    fn mul_keeps_the_low_bits() {
+        let mut cpu = cpu();
+        assert_eq!(cpu.alu_mul(0x10000, 0x10000), 0);
+        assert_eq!(cpu.flags(), FLAG_ZERO | FLAG_CARRY | FLAG_OVERFLOW);
+        // -1 * -1 fits signed but not unsigned
+        assert_eq!(cpu.alu_mul(0xffffffff, 0xffffffff), 1);
+        assert_eq!(cpu.flags(), FLAG_CARRY);
+        assert_eq!(cpu.alu_mul(0x40000000, 2), 0x80000000);
+        assert_eq!(cpu.flags(), FLAG_OVERFLOW | FLAG_NEGATIVE);
+    }
+
+    #[test]
+    fn alu_flags_leave_greater_and_echo_alone() {
+        let mut cpu = cpu();
+        cpu.set_flags(FLAG_GREATER | FLAG_ECHO | FLAG_CARRY | FLAG_OVERFLOW);
+        cpu.alu_logic(0x80000000);
+        assert_eq!(cpu.flags(), FLAG_GREATER | FLAG_ECHO | FLAG_NEGATIVE);
+    }
+
+    #[test]
+    fn lea_computes_every_mode_without_touching_memory() {
+        let source = "section .text
+            movi r1, 0x100
+            movi r2, 3
+            lea r3, [r1 - 4]
+            lea r4, [r1 + r2*4 + 8]
+            lea r5, [pc + .end]
+            lea r6, [0xfffffff0]
+            .end
+            hlt";
+        let (cpu, outcome) = run(source);
+        assert_eq!(outcome, RunOutcome::Halted(0));
+        let end = assemble(source).unwrap().labels[".end"];
+        assert_eq!(&cpu.regs()[3..7], [0xfc, 0x114, end, 0xfffffff0]);
+    }
+
+    #[test]
+    fn signed_division_overflows() {
+        let mut cpu = cpu();
+        assert_eq!(cpu.alu_div(i32::MIN as u32, -1i32 as u32, true), Ok(i32::MIN as u32));
+        assert_eq!(cpu.flags(), FLAG_OVERFLOW | FLAG_NEGATIVE);
+        assert_eq!(cpu.alu_rem(i32::MIN as u32, -1i32 as u32, true), Ok(0));
+        assert_eq!(cpu.flags(), FLAG_ZERO);
+        // unsigned, the same bits are just a big number
+        assert_eq!(cpu.alu_div(0x80000000, 0xffffffff, false), Ok(0));
+        assert_eq!(cpu.flags(), FLAG_ZERO);
+    }
+
+    #[test]
+    fn division_rounds_toward_zero() {
+        let mut cpu = cpu();
+        assert_eq!(cpu.alu_div(-7i32 as u32, 2, true), Ok(-3i32 as u32));
+        assert_eq!(cpu.alu_rem(-7i32 as u32, 2, true), Ok(-1i32 as u32));
+        assert_eq!(cpu.alu_rem(7, -2i32 as u32, true), Ok(1));
+        assert_eq!(cpu.alu_div(7, 2, false), Ok(3));
+        assert_eq!(cpu.alu_rem(7, 2, false), Ok(1));
+    }
+
+    #[test]
+    fn division_by_zero_faults() {
+        let mut cpu = cpu();
+        cpu.set_pc(0x40);
+        assert_eq!(cpu.alu_div(1, 0, false), Err(CpuFault::DivideByZero { pc: 0x40 }));
+        assert_eq!(cpu.alu_rem(1, 0, true), Err(CpuFault::DivideByZero { pc: 0x40 }));
+    }
+
+    #[test]
+    fn shifts_carry_the_last_bit_out() {
+        let mut cpu = cpu();
+        assert_eq!(cpu.alu_shift(Shift::Shl, 0x80000001, 1), 2);
+        assert_eq!(cpu.flags(), FLAG_CARRY);
+        assert_eq!(cpu.alu_shift(Shift::Shr, 3, 1), 1);
+        assert_eq!(cpu.flags(), FLAG_CARRY);
+        assert_eq!(cpu.alu_shift(Shift::Shr, 0x80000000, 31), 1);
+        assert_eq!(cpu.flags(), 0);
+        assert_eq!(cpu.alu_shift(Shift::Sar, 0x80000000, 31), 0xffffffff);
+        assert_eq!(cpu.flags(), FLAG_NEGATIVE);
+        assert_eq!(cpu.alu_shift(Shift::Sar, 0x40000000, 31), 0);
+        assert_eq!(cpu.flags(), FLAG_ZERO | FLAG_CARRY);
+    }
+
+    #[test]
+    fn shift_counts_are_masked() {
+        let mut cpu = cpu();
+        cpu.set_flags(FLAG_CARRY);
+        assert_eq!(cpu.alu_shift(Shift::Shl, 0x80000001, 32), 0x80000001);
+        assert_eq!(cpu.flags(), FLAG_NEGATIVE);
+        assert_eq!(cpu.alu_shift(Shift::Shr, 0x80000001, 33), 0x40000000);
+        assert_eq!(cpu.flags(), FLAG_CARRY);
+        assert_eq!(cpu.alu_shift(Shift::Ror, 1, 0xffffffe0), 1);
+        assert_eq!(cpu.flags(), 0);
+    }
+
+    #[test]
+    fn rotates_carry_the_bit_that_wrapped() {
+        let mut cpu = cpu();
+        assert_eq!(cpu.alu_shift(Shift::Rol, 0x80000000, 1), 1);
+        assert_eq!(cpu.flags(), FLAG_CARRY);
+        assert_eq!(cpu.alu_shift(Shift::Rol, 0x40000000, 1), 0x80000000);
+        assert_eq!(cpu.flags(), FLAG_NEGATIVE);
+        assert_eq!(cpu.alu_shift(Shift::Ror, 1, 1), 0x80000000);
+        assert_eq!(cpu.flags(), FLAG_CARRY | FLAG_NEGATIVE);
+        assert_eq!(cpu.alu_shift(Shift::Ror, 2, 1), 1);
+        assert_eq!(cpu.flags(), 0);
+    }
+
+    #[test]
+    fn adc_and_sbb_chain_through_carry() {
+        // 0x1_ffffffff + 0x0_00000001 across two registers each, then back
+        let (cpu, outcome) = run("section .text
+            movi r0, 0
+            subi r0, 1
+            movi r1, 1
+            movi r2, 1
+            movi r3, 0
+            add r0, r2
+            adc r1, r3
+            sub r0, r2
+            sbb r1, r3
+            hlt");
+        assert_eq!(outcome, RunOutcome::Halted(0xffffffff));
+        assert_eq!(cpu.regs()[1], 1);
+
+        let (cpu, _) = run("section .text
+            movi r0, 0
+            subi r0, 1
+            addi r0, 1
+            movi r1, 5
+            adci r1, 2
+            movi r2, 3
+            sbbi r2, 1
+            hlt");
+        assert_eq!(&cpu.regs()[1..3], [8, 2]);
+    }
+
+    /// a program that installs `handler` for `vector` through a vector
+    /// table at 0x20000, clear of the program and its stack, then runs
+    /// `body`
+    fn with_handler(vector: u32, body: &str, handler: &str) -> String {
+        format!("section .text
+            movi r15, 0x2
+            shli r15, 16
+            wrcr vbr, r15
+            lea r14, [.handler]
+            stw [r15 + {}], r14
+            {}
+            .handler
+            {}", 4 * vector, body, handler)
+    }
+
+    #[test]
+    fn divide_by_zero_traps_and_iret_retries() {
+        let source = with_handler(VEC_DIVIDE, "
+            sti
+            movi r1, 7
+            movi r2, 0
+            .fault
+            div r1, r2
+            mov r0, r1
+            hlt", "
+            rdcr r3, epc
+            rdcr r4, cause
+            rdcr r5, estatus
+            rdcr r6, status
+            movi r2, 1
+            iret");
+        let (cpu, outcome) = run(&source);
+        assert_eq!(outcome, RunOutcome::Halted(7));
+        let fault = assemble(&source).unwrap().labels[".fault"];
+        assert_eq!(&cpu.regs()[3..7], [fault, 0x3, STATUS_IE, 0]);
+        assert_eq!(cpu.control_reg(CR_STATUS), STATUS_IE);
+    }
+
+    #[test]
+    fn illegal_opcode_traps_with_the_opcode() {
+        // no instruction uses 0x7a, `z`
+        let source = with_handler(VEC_ILLEGAL, "
+            .fault
+            bytes \"z\"", "
+            rdcr r0, badaddr
+            rdcr r3, epc
+            rdcr r4, cause
+            hlt");
+        let (cpu, outcome) = run(&source);
+        assert_eq!(outcome, RunOutcome::Halted(b'z' as u32));
+        let fault = assemble(&source).unwrap().labels[".fault"];
+        assert_eq!(&cpu.regs()[3..5], [fault, 0x1]);
+    }
+
+    #[test]
+    fn unknown_interrupt_traps_and_iret_restores_flags() {
+        let source = with_handler(VEC_UNKNOWN_INT, "
+            movi r1, 0
+            cmpi r1, 0
+            int 0x999
+            .after
+            hlt", "
+            rdcr r0, badaddr
+            rdcr r3, cause
+            rdcr r4, eflags
+            lea r5, [.after]
+            wrcr epc, r5
+            cmpi r5, 0
+            iret");
+        let (cpu, outcome) = run(&source);
+        assert_eq!(outcome, RunOutcome::Halted(0x999));
+        assert_eq!(cpu.regs()[3], 0x4);
+        assert_ne!(cpu.regs()[4] as u8 & FLAG_ZERO, 0);
+        assert_eq!(cpu.flags(), cpu.regs()[4] as u8);
+    }
 
+    #[test]
+    fn faults_without_a_handler_stop_the_program() {
+        let body = "
+            movi r2, 0
+            .fault
+            div r1, r2
+            hlt";
+        // no vector table at all
+        let source = format!("section .text{}", body);
+        let fault = assemble(&source).unwrap().labels[".fault"] as usize;
+        assert_eq!(run(&source).1, RunOutcome::Fault(CpuFault::DivideByZero { pc: fault }));
+        // a table without this vector
+        let source = with_handler(VEC_ILLEGAL, body, "hlt");
+        let fault = assemble(&source).unwrap().labels[".fault"] as usize;
+        let (cpu, outcome) = run(&source);
+        assert_eq!(outcome, RunOutcome::Fault(CpuFault::DivideByZero { pc: fault }));
+        assert_eq!(cpu.control_reg(CR_EPC), 0);
+    }
 
+    #[test]
+    fn faults_in_a_handler_do_not_recurse() {
+        let source = with_handler(VEC_DIVIDE, "
+            movi r2, 0
+            .fault
+            div r1, r2
+            hlt", "
+            div r1, r2
+            hlt");
+        let labels = assemble(&source).unwrap().labels;
+        let (cpu, outcome) = run(&source);
+        assert_eq!(outcome, RunOutcome::Fault(CpuFault::DivideByZero { pc: labels[".handler"] as usize }));
+        assert_eq!(cpu.control_reg(CR_EPC), labels[".fault"]);
+    }
+}
@@ -1,16 +1,18 @@
 use std::collections::HashMap;
-use std::io::Write;
 use getch::Getch;
 
 use super::CPU;
+use super::cpu::FLAG_ECHO;
 use crate::debug;
+use crate::error::CpuFault;
 
 
+#[allow(dead_code)]
 pub trait Interrupt {
-    fn run(r0: u32, r1: u32, r2: u32, r3: u32) -> Result<u32, String>;
+    fn run(r0: u32, r1: u32, r2: u32, r3: u32) -> Result<u32, CpuFault>;
 }
 
-pub type IntFn = fn(&mut CPU) -> Result<usize,String>;
+pub type IntFn = fn(&mut CPU) -> Result<usize, CpuFault>;
 
 pub fn build_interrupt_table() -> HashMap<u32, IntFn>{
     let mut map = HashMap::new();
@@ -24,46 +26,59 @@ pub fn build_interrupt_table() -> HashMap<u32, IntFn>{
 }
 
 
-//// INTERRUPT 0x80: WRITE BYTE TO STDOUT ////
+/// INTERRUPT 0x80: WRITE BYTE TO STDOUT
 /// format for this is as follows:
 /// 
 /// R0      ->  Address of byte to write to console 
 /// R1-R3   ->  Not used 
-pub fn int_writeconsole(cpu: &mut CPU) -> Result<usize, String> {
-    let o = cpu.memory[cpu.get_reg(0)? as usize] as char;
-    debug!("INTERRUPTS: writing {}...", o);
-    print!("{}", o);
-    std::io::stdout().flush().unwrap();
+pub fn int_writeconsole(cpu: &mut CPU) -> Result<usize, CpuFault> {
+    let o = cpu.memory.read_u8(cpu.get_reg(0)? as usize)? as char;
+    debug!("writing {}...", o);
+    let pc = cpu.pc();
+    let console = cpu.console();
+    write!(console, "{}", o)
+        .and_then(|_| console.flush())
+        .map_err(|e| CpuFault::Io { pc, error: e.to_string() })?;
     Ok(0)
 }
 
-//// INTERRUPT 0xA0: READ BYTE FROM STDIN ////
+/// INTERRUPT 0xA0: READ BYTE FROM STDIN
 /// format for this is as follows:
 /// 
 /// R0      ->  Address where byte will be written 
 /// R1      ->  Copy of byte read saved
 /// R2-R3   ->  Not used
-pub fn int_readconsole(cpu: &mut CPU) -> Result<usize, String> {
+pub fn int_readconsole(cpu: &mut CPU) -> Result<usize, CpuFault> {
     //let o = cpu.memory[cpu.get_reg(0) as usize] as char;
-    debug!("INTERRUPTS: Waiting for read...");
+    debug!("Waiting for read...");
     
-    let g = Getch::new();
-    let u = match g.getch() {
+    let pc = cpu.pc();
+    let read = match cpu.console_input() {
+        Some(input) => {
+            // like the terminal, end of input reads as a zero byte
+            let mut byte = [0u8];
+            input.read(&mut byte).map(|_| byte[0])
+        },
+        None => Getch::new().getch()
+    };
+    let u = match read {
         Ok(a) => a,
-        Err(e) => panic!("Interrupt Failed: {}", e)
+        Err(e) => return Err(CpuFault::Io { pc, error: e.to_string() })
     };
 
-    if cpu.is_flag_set(1 << 4) {
-        print!("{}", u as char);
-        std::io::stdout().flush().unwrap();
+    if cpu.is_flag_set(FLAG_ECHO) {
+        let console = cpu.console();
+        write!(console, "{}", u as char)
+            .and_then(|_| console.flush())
+            .map_err(|e| CpuFault::Io { pc, error: e.to_string() })?;
         debug!("Flag IS set");
     } else {
         debug!("Flag NOT set");
     }
     
-    debug!("INTERRUPTS: Read {:x}", u);
+    debug!("Read {:x}", u);
     let addr = cpu.get_reg(0)? as usize;
-    cpu.memory[addr] = u;
+    cpu.memory.write_u8(addr, u)?;
     cpu.set_reg(1, u as u32)?;
     Ok(0)
 }
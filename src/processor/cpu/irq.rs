@@ -0,0 +1,146 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+
+/// number of interrupt request lines
+pub const NUM_IRQS: u8 = 8;
+/// the line the interval timer raises
+pub const IRQ_TIMER: u8 = 0;
+
+
+/// raises interrupt request lines on a CPU. Handles can be cloned and sent
+/// to other threads, so host code can deliver asynchronous events; the CPU
+/// takes the highest priority pending line between instructions once the
+/// guest has enabled interrupts
+#[derive(Clone, Debug, Default)]
+pub struct IrqHandle(Arc<AtomicU32>);
+
+impl IrqHandle {
+    /// marks line `irq` as pending. Lines past `NUM_IRQS` are ignored
+    pub fn raise(&self, irq: u8) {
+        if irq < NUM_IRQS {
+            self.0.fetch_or(1 << irq, Ordering::Relaxed);
+        }
+    }
+}
+
+
+/// the lines waiting to be delivered and the interval timer that drives
+/// `IRQ_TIMER`. Masks and priorities live in the control registers
+#[derive(Debug, Default)]
+pub(crate) struct InterruptController {
+    pending: IrqHandle,
+    /// instructions left until the timer next fires
+    countdown: u32
+}
+
+impl InterruptController {
+    pub fn handle(&self) -> IrqHandle {
+        self.pending.clone()
+    }
+
+    /// the pending lines, one bit each
+    pub fn pending(&self) -> u32 {
+        self.pending.0.load(Ordering::Relaxed)
+    }
+
+    /// drops the lines set in `lines` without delivering them
+    pub fn clear(&self, lines: u32) {
+        self.pending.0.fetch_and(!lines, Ordering::Relaxed);
+    }
+
+    /// forgets everything pending and restarts the timer
+    pub fn reset(&mut self) {
+        self.pending.0.store(0, Ordering::Relaxed);
+        self.countdown = 0;
+    }
+
+    /// counts down one instruction of a timer firing every `interval`
+    /// instructions, raising `IRQ_TIMER` when it expires
+    pub fn tick(&mut self, interval: u32) {
+        if self.countdown == 0 || self.countdown > interval {
+            self.countdown = interval;
+        }
+        self.countdown -= 1;
+        if self.countdown == 0 {
+            self.pending.raise(IRQ_TIMER);
+        }
+    }
+
+    /// takes the pending line that isn't set in `mask` with the highest
+    /// priority, where `priorities` holds four bits per line, line 0
+    /// lowest. Ties go to the lower numbered line
+    pub fn take(&self, mask: u32, priorities: u32) -> Option<u8> {
+        let ready = self.pending() & !mask;
+        if ready == 0 {
+            return None;
+        }
+        let irq = (0..NUM_IRQS)
+            .filter(|a| ready & (1 << a) != 0)
+            .min_by_key(|a| (std::cmp::Reverse((priorities >> (4 * a)) & 0xf), *a))?;
+        self.clear(1 << irq);
+        Some(irq)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raised(lines: &[u8]) -> InterruptController {
+        let irqs = InterruptController::default();
+        for irq in lines.iter() {
+            irqs.handle().raise(*irq);
+        }
+        irqs
+    }
+
+    #[test]
+    fn ties_go_to_the_lowest_line() {
+        let irqs = raised(&[5, 2, 7]);
+        assert_eq!(irqs.take(0, 0), Some(2));
+        assert_eq!(irqs.take(0, 0), Some(5));
+        assert_eq!(irqs.take(0, 0), Some(7));
+        assert_eq!(irqs.take(0, 0), None);
+    }
+
+    #[test]
+    fn higher_priorities_go_first() {
+        let irqs = raised(&[1, 3, 6]);
+        // line 6 at 2, line 3 at 9, line 1 at 2
+        let priorities = 2 << 24 | 9 << 12 | 2 << 4;
+        assert_eq!(irqs.take(0, priorities), Some(3));
+        assert_eq!(irqs.take(0, priorities), Some(1));
+        assert_eq!(irqs.take(0, priorities), Some(6));
+    }
+
+    #[test]
+    fn masked_lines_stay_pending() {
+        let irqs = raised(&[0, 4]);
+        assert_eq!(irqs.take(1 << 0 | 1 << 4, 0), None);
+        assert_eq!(irqs.take(1 << 0, 0xf), Some(4));
+        assert_eq!(irqs.pending(), 1 << 0);
+        irqs.clear(1 << 0);
+        assert_eq!(irqs.take(0, 0), None);
+    }
+
+    #[test]
+    fn lines_past_the_last_are_ignored() {
+        let irqs = raised(&[NUM_IRQS, 200]);
+        assert_eq!(irqs.pending(), 0);
+    }
+
+    #[test]
+    fn timer_fires_every_interval() {
+        let mut irqs = InterruptController::default();
+        let mut fired = Vec::new();
+        for i in 1..=9 {
+            irqs.tick(3);
+            if irqs.take(0, 0).is_some() {
+                fired.push(i);
+            }
+        }
+        assert_eq!(fired, [3, 6, 9]);
+    }
+}
@@ -1,149 +1,741 @@
-use std::ops::{Index, IndexMut};
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::path::PathBuf;
 
-use crate::debug;
+use crate::trace;
+use crate::error::{Access, MemFault};
+use crate::processor::cpu::paging::{Tlb, TlbStats, DEFAULT_TLB_ENTRIES, PTE_FRAME, PTE_PRESENT, PTE_USER, PTE_WRITABLE};
+use crate::processor::cpu::swap::{Pager, Replacement, SwapStats};
 
-const PAGE_MASK: usize = 0xff;
+/// bits of an address that pick a byte within a page
+const PAGE_BITS: u32 = 12;
+/// how many bytes a page holds
+pub const PAGE_SIZE: usize = 1 << PAGE_BITS;
+const PAGE_MASK: usize = PAGE_SIZE - 1;
+/// bits of a page number that pick an entry within a page table
+const TABLE_BITS: u32 = 10;
+const TABLE_SIZE: usize = 1 << TABLE_BITS;
+const TABLE_MASK: usize = TABLE_SIZE - 1;
 
 /// implement pages so we dont need to allocate all the memory from the getgo
-#[derive(Clone, Copy, Debug)]
-struct Page {
-    data: [u8; PAGE_MASK+1]
+type Page = Box<[u8; PAGE_SIZE]>;
+
+/// the second level of the page table: `TABLE_SIZE` pages, each allocated
+/// when first written
+type PageTable = Box<[Option<Page>]>;
+
+/// what reads of pages that were never written see
+static ZERO_PAGE: [u8; PAGE_SIZE] = [0u8; PAGE_SIZE];
+
+/// the whole 32-bit address space, the default RAM size
+pub const MAX_RAM_SIZE: usize = 1 << 32;
+
+/// the page can be read
+pub const PERM_READ: u8 = 1 << 0;
+/// the page can be written
+pub const PERM_WRITE: u8 = 1 << 1;
+/// instructions can be fetched from the page
+pub const PERM_EXEC: u8 = 1 << 2;
+/// what pages outside any protected region allow once protection is on
+const PERM_DEFAULT: u8 = PERM_READ | PERM_WRITE;
+
+
+
+/// a single byte access, captured while the MMU is recording. For writes,
+/// `value` is the byte that was overwritten and `new` the one written; for
+/// reads both are the byte read
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MemAccess {
+    pub addr: usize,
+    pub access: Access,
+    pub value: u8,
+    pub new: u8
 }
 
-////// PAGE TRAIT IMPLEMENTATIONS //////
-impl Index<usize> for Page {
-    type Output = u8;
-    fn index(&self, s: usize) -> &u8 {
-        &self.data[s]
-    }
+
+/// the machine's RAM, from address 0 up to `size`. Pages are only
+/// allocated once written, and reads of RAM that was never written return
+/// zero. Accesses past the end of RAM fault, as do ones a page's
+/// permissions don't allow once `protect` has been called.
+///
+/// Pages are found through a two-level table: the top bits of an address
+/// pick a `PageTable` from the directory, the next pick a page within it.
+///
+/// With `set_swap`, pages instead live in a fixed number of frames, and
+/// the rest wait in a swap file.
+///
+/// Once the guest gives it a page table with `set_page_table`, addresses
+/// are virtual: each is translated through the guest's own two-level page
+/// table in RAM, with recent translations cached in a TLB, before the 
+/// checks above apply to the physical address
+pub struct MMU {
+    directory: Vec<Option<PageTable>>,
+    /// how many bytes of RAM there are
+    size: usize,
+    /// how many pages are allocated
+    resident: usize,
+    recording: RefCell<Option<Vec<MemAccess>>>,
+    /// how many pages may be resident at once
+    page_limit: Option<usize>,
+    /// set when a write needed a page past `page_limit`
+    page_limit_hit: bool,
+    /// where writes that would go past `page_limit` end up
+    scratch: Page,
+    /// the `PERM_*` bits of every page, once any have been protected
+    perms: Option<Vec<u8>>,
+    /// physical address of the guest's page directory, while paging is on
+    page_table: Option<usize>,
+    /// whether accesses come from user mode, so need `PTE_USER` pages
+    user: bool,
+    tlb: RefCell<Tlb>,
+    /// holds every page instead of `directory` once swapping is on
+    pager: Option<RefCell<Pager>>
 }
 
-impl IndexMut<usize> for Page {
-    fn index_mut(&mut self, s: usize) -> &mut u8 {
-        &mut self.data[s]
+
+impl Default for MMU {
+    fn default() -> Self {
+        MMU::new()
     }
 }
 
-impl Page {
-    fn new() -> Self {
-        Page { data: [0u8; PAGE_MASK+1] }
+impl MMU {
+    /// RAM covering the whole 32-bit address space
+    pub fn new() -> Self {
+        MMU::with_size(MAX_RAM_SIZE)
+    }
+
+    /// `size` bytes of RAM
+    pub fn with_size(size: usize) -> Self {
+        let tables = size.div_ceil(PAGE_SIZE * TABLE_SIZE);
+        MMU {
+            directory: (0..tables).map(|_| None).collect(),
+            size,
+            resident: 0,
+            recording: RefCell::new(None),
+            page_limit: None,
+            page_limit_hit: false,
+            scratch: Box::new([0u8; PAGE_SIZE]),
+            perms: None,
+            page_table: None,
+            user: false,
+            tlb: RefCell::new(Tlb::new(DEFAULT_TLB_ENTRIES)),
+            pager: None
+        }
     }
-}
 
+    /// how many bytes of RAM there are
+    pub fn size(&self) -> usize {
+        self.size
+    }
 
+    /// gives every page overlapping `len` bytes at `offset` the `PERM_*`
+    /// bits in `perms`. Until this is first called every access is allowed;
+    /// after that, pages it was never called for can be read and written
+    /// but not executed
+    pub fn protect(&mut self, offset: usize, len: usize, perms: u8) {
+        let pages = self.size.div_ceil(PAGE_SIZE);
+        let table = self.perms.get_or_insert_with(|| vec![PERM_DEFAULT; pages]);
+        if len == 0 {
+            return;
+        }
+        let last = ((offset + len - 1) >> PAGE_BITS).min(pages.saturating_sub(1));
+        for page in table.iter_mut().take(last + 1).skip(offset >> PAGE_BITS) {
+            *page = perms;
+        }
+    }
 
+    /// forgets every page's permissions, so all accesses are allowed again
+    pub fn unprotect(&mut self) {
+        self.perms = None;
+    }
 
+    /// the `PERM_*` bits of the page holding `offset`
+    pub fn permissions(&self, offset: usize) -> u8 {
+        match &self.perms {
+            Some(perms) => perms.get(offset >> PAGE_BITS).copied().unwrap_or(0),
+            None => PERM_READ | PERM_WRITE | PERM_EXEC
+        }
+    }
 
-pub struct MMU {
-    pages: HashMap<usize, Page>
-}
+    /// turns paging on, translating addresses through the page directory at
+    /// physical address `base`, or off with `None`. Changing it flushes the
+    /// TLB
+    pub fn set_page_table(&mut self, base: Option<usize>) {
+        if base != self.page_table {
+            self.page_table = base;
+            self.tlb.get_mut().flush();
+        }
+    }
+
+    /// the page directory addresses are translated through, if paging is on
+    pub fn page_table(&self) -> Option<usize> {
+        self.page_table
+    }
 
-////// TRAIT IMPLEMENTATIONS //////
-impl Index<usize> for MMU {
-    type Output = u8;
-    fn index(&self, s: usize) -> &u8 {
-        // translate the address into a useful address
-        let page_num = (s & (0xFFFFFFFF - PAGE_MASK)) >> PAGE_MASK.count_ones();
-        let page_offset = s & PAGE_MASK;
-        debug!("MMU: Reading page number {}, offset {} (s={})", page_num, page_offset, s);
+    /// makes accesses come from user mode, which can only touch pages whose
+    /// page table entries have `PTE_USER` set
+    pub fn set_user_mode(&mut self, user: bool) {
+        self.user = user;
+    }
 
-        &self.pages[&page_num][page_offset]
+    /// how many translations the TLB holds. Resizing it empties it
+    pub fn set_tlb_entries(&mut self, n: usize) {
+        self.tlb.get_mut().resize(n);
     }
-}
 
-impl IndexMut<usize> for MMU {
-    fn index_mut(&mut self, s: usize) -> &mut u8 {
-        // translate the address into a useful address
-        let page_num = (s & (0xFFFFFFFF - PAGE_MASK)) >> PAGE_MASK.count_ones();
-        let page_offset = s & PAGE_MASK;
-        
-        debug!("MMU: Writing page number {}, offset {} (s={})", page_num, page_offset, s);
-
-        // check if the page exists
-        match self.check_page(page_num) {
-            true => (),
-            false => {
-                // it doesn't, so add it
-                self.pages.insert(page_num, Page::new());
+    /// drops every translation from the TLB
+    pub fn flush_tlb(&mut self) {
+        self.tlb.get_mut().flush();
+    }
+
+    /// drops the translation of the page holding `addr` from the TLB
+    pub fn flush_tlb_page(&mut self, addr: usize) {
+        self.tlb.get_mut().flush_page(addr >> PAGE_BITS);
+    }
+
+    /// how often translations have hit and missed the TLB
+    pub fn tlb_stats(&self) -> TlbStats {
+        self.tlb.borrow().stats()
+    }
+
+    /// backs RAM with `frames` physical frames, moving pages out to
+    /// `swap_file` (or a temporary file) when they run out, chosen by
+    /// `policy`. The working set is measured over windows of `ws_window`
+    /// references. Meant for a fresh MMU: anything already in memory is lost
+    pub fn set_swap(&mut self, frames: usize, policy: Replacement, swap_file: Option<PathBuf>, ws_window: u64) {
+        self.directory.iter_mut().for_each(|t| *t = None);
+        self.resident = 0;
+        self.pager = Some(RefCell::new(Pager::new(frames, policy, swap_file, ws_window)));
+    }
+
+    /// how paging to swap has gone, if it's on
+    pub fn swap_stats(&self) -> Option<SwapStats> {
+        self.pager.as_ref().map(|p| p.borrow().stats())
+    }
+
+    /// limits how many pages may be resident at once. Writes that need a page
+    /// past the limit are discarded
+    pub fn set_page_limit(&mut self, limit: Option<usize>) {
+        self.page_limit = limit;
+    }
+
+    /// how many pages are currently resident
+    pub fn resident_pages(&self) -> usize {
+        match &self.pager {
+            Some(p) => p.borrow().resident(),
+            None => self.resident
+        }
+    }
+
+    /// the page limit, if a write has gone past it since the last call
+    pub(crate) fn take_page_limit_hit(&mut self) -> Option<usize> {
+        match std::mem::take(&mut self.page_limit_hit) {
+            true => self.page_limit,
+            false => None
+        }
+    }
+
+    /// starts capturing every byte read and written, discarding anything
+    /// captured so far
+    pub fn start_recording(&self) {
+        *self.recording.borrow_mut() = Some(Vec::new());
+    }
+
+    /// stops capturing accesses and returns them in the order they happened
+    pub fn stop_recording(&self) -> Vec<MemAccess> {
+        self.recording.borrow_mut().take().unwrap_or_default()
+    }
+
+    /// captures a run of accesses if recording is on. `values` are the 
+    /// bytes read or overwritten and `new` what they hold afterwards
+    fn record(&self, addr: usize, access: Access, values: &[u8], new: &[u8]) {
+        if let Some(log) = self.recording.borrow_mut().as_mut() {
+            for (i, (value, new)) in values.iter().zip(new.iter()).enumerate() {
+                log.push(MemAccess { addr: addr + i, access, value: *value, new: *new });
             }
         }
+    }
 
-        &mut self.pages.get_mut(&page_num).unwrap()[page_offset]
+    /// the page `page_num`, or zeroes if it was never written
+    fn page(&self, page_num: usize) -> &[u8; PAGE_SIZE] {
+        let table = match self.directory.get(page_num >> TABLE_BITS) {
+            Some(Some(a)) => a,
+            _ => return &ZERO_PAGE
+        };
+        match &table[page_num & TABLE_MASK] {
+            Some(a) => a,
+            None => &ZERO_PAGE
+        }
     }
-}
 
+    /// the page `page_num`, allocating it if it was never written. Past the
+    /// page limit this hands back a scratch page instead and lets the CPU
+    /// know
+    fn page_mut(&mut self, page_num: usize) -> &mut [u8; PAGE_SIZE] {
+        let table = self.directory[page_num >> TABLE_BITS]
+            .get_or_insert_with(|| (0..TABLE_SIZE).map(|_| None).collect());
+        let entry = &mut table[page_num & TABLE_MASK];
+        if entry.is_none() {
+            if self.page_limit.is_some_and(|n| self.resident >= n) {
+                self.page_limit_hit = true;
+                return &mut self.scratch;
+            }
+            trace!("Allocating page number {}", page_num);
+            self.resident += 1;
+        }
+        entry.get_or_insert_with(|| Box::new([0u8; PAGE_SIZE]))
+    }
 
+    /// checks that `len` bytes at `offset` are all in RAM, faulting on the
+    /// first that isn't
+    fn check_range(&self, offset: usize, len: usize, access: Access) -> Result<(), MemFault> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(MemFault::Unmapped { addr: offset.max(self.size), access })
+        }
+    }
 
-impl MMU {
-    pub fn new() -> Self {
-        MMU {
-            pages: HashMap::new()
+    /// checks that `len` bytes at `offset` are all in RAM and that their
+    /// pages allow `access`
+    fn check(&self, offset: usize, len: usize, access: Access) -> Result<(), MemFault> {
+        self.check_range(offset, len, access)?;
+        let perms = match &self.perms {
+            Some(a) if len > 0 => a,
+            _ => return Ok(())
+        };
+        let need = match access {
+            Access::Read => PERM_READ,
+            Access::Write => PERM_WRITE,
+            Access::Execute => PERM_EXEC
+        };
+        let first = offset >> PAGE_BITS;
+        let last = (offset + len - 1) >> PAGE_BITS;
+        for (page, p) in perms[first..=last].iter().enumerate() {
+            if p & need == 0 {
+                let addr = offset.max((first + page) << PAGE_BITS);
+                return Err(MemFault::Protected { addr, access, perms: *p });
+            }
         }
+        Ok(())
+    }
+
+    /// reads the big-endian word at physical address `addr`, ignoring
+    /// page permissions, as the page table walker does
+    pub fn read_physical_u32(&self, addr: usize) -> Result<u32, MemFault> {
+        self.check_range(addr, 4, Access::Read)?;
+        let mut buf = [0u8; 4];
+        self.load(addr, &mut buf)?;
+        Ok(u32::from_be_bytes(buf))
     }
 
-    /// checks if a page number exists or not
-    fn check_page(&self, page_num: usize) -> bool{
-        match self.pages.get(&page_num) {
-            Some(_) => true,
-            None => false
+    /// walks the page table for virtual address `addr`, returning the frame
+    /// it maps to and the `PTE_*` bits allowed by both levels
+    fn walk(&self, base: usize, addr: usize, access: Access) -> Result<(usize, u32), MemFault> {
+        let not_present = MemFault::PageFault { addr, access, present: false, user: self.user };
+        let pde = self.read_physical_u32(base + ((addr >> (PAGE_BITS + TABLE_BITS)) << 2))?;
+        if pde & PTE_PRESENT == 0 {
+            return Err(not_present);
         }
+        let table = (pde & PTE_FRAME) as usize;
+        let pte = self.read_physical_u32(table + (((addr >> PAGE_BITS) & TABLE_MASK) << 2))?;
+        if pte & PTE_PRESENT == 0 {
+            return Err(not_present);
+        }
+        trace!("Page walk mapped 0x{:x} to frame 0x{:x}", addr, pte & PTE_FRAME);
+        Ok(((pte & PTE_FRAME) as usize, pde & pte & (PTE_PRESENT | PTE_WRITABLE | PTE_USER)))
     }
 
-    /// retrieves a u32 from memory at address `offset`
-    pub fn get_u32(&self, offset: usize) -> Result<u32, String>{
-        // bounds check address
-        if offset > u32::MAX as usize {
-            return Err("Illicit memory access".to_string());
+    /// translates virtual address `addr` to a physical one, going through
+    /// the TLB. With `checked`, faults if its page doesn't allow `access`
+    fn translate(&self, base: usize, addr: usize, access: Access, checked: bool) -> Result<usize, MemFault> {
+        if addr >= MAX_RAM_SIZE {
+            return Err(MemFault::PageFault { addr, access, present: false, user: self.user });
         }
+        let page = addr >> PAGE_BITS;
+        let cached = self.tlb.borrow_mut().lookup(page);
+        let (frame, flags) = match cached {
+            Some(a) => a,
+            None => {
+                let (frame, flags) = self.walk(base, addr, access)?;
+                self.tlb.borrow_mut().insert(page, frame, flags);
+                (frame, flags)
+            }
+        };
+        let allowed = match access {
+            Access::Write => flags & PTE_WRITABLE != 0,
+            Access::Read | Access::Execute => true
+        } && (!self.user || flags & PTE_USER != 0);
+        if checked && !allowed {
+            return Err(MemFault::PageFault { addr, access, present: true, user: self.user });
+        }
+        Ok(frame | (addr & PAGE_MASK))
+    }
 
-        let mut ret = 0u32;
-        ret += (self[offset] as u32) << 24;
-        ret += (self[offset + 1] as u32) << 16;
-        ret += (self[offset + 2] as u32) << 8;
-        ret += self[offset + 3] as u32;
-        Ok(ret)
+    /// the physical runs backing `len` bytes at virtual address `offset`, as
+    /// `(address, length)` pairs. With `checked`, faults unless every page 
+    /// allows `access`; otherwise only unmapped ones fault. Faults on the 
+    /// physical side are reported at the virtual address
+    fn translate_range(&self, base: usize, offset: usize, len: usize, access: Access, checked: bool) -> Result<Vec<(usize, usize)>, MemFault> {
+        let mut runs = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let addr = offset + done;
+            let n = (PAGE_SIZE - (addr & PAGE_MASK)).min(len - done);
+            let phys = self.translate(base, addr, access, checked)?;
+            let res = match checked {
+                true => self.check(phys, n, access),
+                false => self.check_range(phys, n, access)
+            };
+            res.map_err(|e| match e {
+                MemFault::Unmapped { addr: a, access } => MemFault::Unmapped { addr: addr + a - phys, access },
+                MemFault::Protected { addr: a, access, perms } => MemFault::Protected { addr: addr + a - phys, access, perms },
+                e => e
+            })?;
+            runs.push((phys, n));
+            done += n;
+        }
+        Ok(runs)
     }
 
-    /// retrieves a u24 from memory at address `offset` (returned as u32)
-    pub fn _get_u24(&self, offset: usize) -> Result<u32, String> {
-        // bounds check address
-        if offset > u32::MAX as usize {
-            return Err("Illicit memory access".to_string());
+    /// checks that an instruction of `len` bytes can be fetched from `offset`
+    pub fn check_execute(&self, offset: usize, len: usize) -> Result<(), MemFault> {
+        match self.page_table {
+            None => self.check(offset, len, Access::Execute),
+            Some(base) => self.translate_range(base, offset, len, Access::Execute, true).map(|_| ())
         }
-        
-        let mut ret: u32 = 0;
-        ret += (self[offset] as u32) << 16;
-        ret += (self[offset + 1] as u32) << 8;
-        ret += self[offset + 2] as u32;
-        Ok(ret)
     }
 
-    /// retrieves a u16 from memory at address `offset`
-    pub fn _get_u16(&self, offset: usize) -> Result<u16, String> {
-        // bounds check address
-        if offset > u32::MAX as usize {
-            return Err("Illicit memory access".to_string());
+    /// fills `buf` from memory starting at `offset`. Every read goes through
+    /// here, so one that straddles the end of RAM or a page that can't be
+    /// read faults before reading anything
+    pub fn read_bytes(&self, offset: usize, buf: &mut [u8]) -> Result<(), MemFault> {
+        if let Some(base) = self.page_table {
+            return self.read_virtual(base, offset, buf);
         }
-        
-        let mut ret: u16 = 0;
-        ret += (self[offset] as u16) << 8;
-        ret += self[offset + 1] as u16;
-        Ok(ret)
-        
+        self.check(offset, buf.len(), Access::Read)?;
+        self.load(offset, buf)?;
+        self.record(offset, Access::Read, buf, buf);
+        Ok(())
     }
 
-    pub fn write_u32(&mut self, offset: usize, data: u32) -> Result<(), String> {
-        // bounds check address
-        if offset > u32::MAX as usize {
-            return Err("Illicit memory access".to_string());
+    /// `read_bytes` with paging on, kept apart so the common case stays 
+    /// small
+    #[inline(never)]
+    fn read_virtual(&self, base: usize, offset: usize, buf: &mut [u8]) -> Result<(), MemFault> {
+        let mut done = 0;
+        for (phys, n) in self.translate_range(base, offset, buf.len(), Access::Read, true)? {
+            self.load(phys, &mut buf[done..done + n])?;
+            done += n;
         }
+        self.record(offset, Access::Read, buf, buf);
+        Ok(())
+    }
 
-        
-        self[offset] = ((data >> 24) & 0xFF).try_into().unwrap();
-        self[offset + 1] = ((data >> 16) & 0xFF).try_into().unwrap();
-        self[offset + 2] = ((data >> 8) & 0xFF).try_into().unwrap();
-        self[offset + 3] = (data & 0xFF).try_into().unwrap();
+    /// copies physical memory starting at `offset` into `buf`
+    fn load(&self, offset: usize, buf: &mut [u8]) -> Result<(), MemFault> {
+        let mut pager = self.pager.as_ref().map(|p| p.borrow_mut());
+        let mut done = 0;
+        while done < buf.len() {
+            let addr = offset + done;
+            let start = addr & PAGE_MASK;
+            let n = (PAGE_SIZE - start).min(buf.len() - done);
+            let page = match pager.as_mut() {
+                Some(p) => p.frame(addr >> PAGE_BITS, false)?,
+                None => self.page(addr >> PAGE_BITS)
+            };
+            buf[done..done + n].copy_from_slice(&page[start..start + n]);
+            done += n;
+        }
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// writes `data` to memory starting at `offset`. Every write goes through
+    /// here, so one that straddles the end of RAM or a page that can't be
+    /// written faults before writing anything
+    pub fn write_bytes(&mut self, offset: usize, data: &[u8]) -> Result<(), MemFault> {
+        if let Some(base) = self.page_table {
+            return self.write_virtual(base, offset, data, true);
+        }
+        self.check(offset, data.len(), Access::Write)?;
+        self.store(offset, offset, data)
+    }
+
+    /// writes `data` starting at `offset` regardless of page permissions,
+    /// as loaders and debuggers need to
+    pub fn patch_bytes(&mut self, offset: usize, data: &[u8]) -> Result<(), MemFault> {
+        if let Some(base) = self.page_table {
+            return self.write_virtual(base, offset, data, false);
+        }
+        self.check_range(offset, data.len(), Access::Write)?;
+        self.store(offset, offset, data)
+    }
+
+    /// `write_bytes` and `patch_bytes` with paging on, checking permissions
+    /// if `checked`
+    #[inline(never)]
+    fn write_virtual(&mut self, base: usize, offset: usize, data: &[u8], checked: bool) -> Result<(), MemFault> {
+        let mut done = 0;
+        for (phys, n) in self.translate_range(base, offset, data.len(), Access::Write, checked)? {
+            self.store(offset + done, phys, &data[done..done + n])?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// copies `data` into the physical pages it covers from `phys`, 
+    /// recording the bytes it overwrites at virtual address `virt`
+    fn store(&mut self, virt: usize, phys: usize, data: &[u8]) -> Result<(), MemFault> {
+        let mut done = 0;
+        while done < data.len() {
+            let addr = phys + done;
+            let start = addr & PAGE_MASK;
+            let n = (PAGE_SIZE - start).min(data.len() - done);
+            let new = &data[done..done + n];
+            let recording = self.recording.get_mut().is_some();
+            let old = match self.pager.as_mut() {
+                Some(p) => {
+                    let page = p.get_mut().frame(addr >> PAGE_BITS, true)?;
+                    let old = recording.then(|| page[start..start + n].to_vec());
+                    page[start..start + n].copy_from_slice(new);
+                    old
+                },
+                None => {
+                    let old = recording.then(|| self.page(addr >> PAGE_BITS)[start..start + n].to_vec());
+                    self.page_mut(addr >> PAGE_BITS)[start..start + n].copy_from_slice(new);
+                    old
+                }
+            };
+            if let Some(old) = old {
+                self.record(virt + done, Access::Write, &old, new);
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// reads a byte from memory at address `offset`
+    pub fn read_u8(&self, offset: usize) -> Result<u8, MemFault> {
+        let mut buf = [0u8; 1];
+        self.read_bytes(offset, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    /// reads a u16 from memory at address `offset`
+    pub fn read_u16(&self, offset: usize) -> Result<u16, MemFault> {
+        let mut buf = [0u8; 2];
+        self.read_bytes(offset, &mut buf)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    /// reads a u32 from memory at address `offset`
+    pub fn read_u32(&self, offset: usize) -> Result<u32, MemFault> {
+        let mut buf = [0u8; 4];
+        self.read_bytes(offset, &mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    /// writes a byte to memory at address `offset`
+    pub fn write_u8(&mut self, offset: usize, data: u8) -> Result<(), MemFault> {
+        self.write_bytes(offset, &[data])
+    }
+
+    /// writes a u16 to memory at address `offset`
+    pub fn write_u16(&mut self, offset: usize, data: u16) -> Result<(), MemFault> {
+        self.write_bytes(offset, &data.to_be_bytes())
+    }
+
+    /// writes a u32 to memory at address `offset`
+    pub fn write_u32(&mut self, offset: usize, data: u32) -> Result<(), MemFault> {
+        self.write_bytes(offset, &data.to_be_bytes())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{Image, Segment};
+    use crate::processor::cpu::{CPU, MachineConfig};
+
+    /// a CPU with `ram` bytes of RAM and a 4K stack, loaded with a program
+    /// of one executable page at `addr`
+    fn loaded(ram: usize, addr: usize) -> (CPU, Result<(), MemFault>) {
+        let config = MachineConfig { ram_size: Some(ram), stack_size: Some(PAGE_SIZE), ..MachineConfig::default() };
+        let mut cpu = CPU::new(&config);
+        let image = Image {
+            runs: vec![(addr, vec![0u8; 16])],
+            segments: vec![Segment { addr: addr as u32, size: 16, flags: PERM_READ | PERM_EXEC }]
+        };
+        let res = cpu.load(&image);
+        (cpu, res)
+    }
+
+    /// an MMU with paging on, its directory at 0x1000. The first 16 pages
+    /// map to themselves through the table at 0x2000, all writable from
+    /// user mode but page 4, which is read-only and supervisor only. The
+    /// second directory entry, supervisor only, maps 0x400000 to 0x5000
+    fn paged() -> MMU {
+        let mut mmu = MMU::with_size(0x10000);
+        mmu.write_u32(0x1000, 0x2000 | PTE_PRESENT | PTE_WRITABLE | PTE_USER).unwrap();
+        mmu.write_u32(0x1004, 0x3000 | PTE_PRESENT | PTE_WRITABLE).unwrap();
+        for page in 0..16u32 {
+            let flags = match page {
+                4 => PTE_PRESENT,
+                _ => PTE_PRESENT | PTE_WRITABLE | PTE_USER
+            };
+            mmu.write_u32(0x2000 + 4 * page as usize, (page << 12) | flags).unwrap();
+        }
+        mmu.write_u32(0x3000, 0x5000 | PTE_PRESENT | PTE_WRITABLE | PTE_USER).unwrap();
+        mmu.set_page_table(Some(0x1000));
+        mmu
+    }
+
+    #[test]
+    fn unwritten_ram_reads_as_zero() {
+        let mut mmu = MMU::new();
+        assert_eq!(mmu.read_u32(0).unwrap(), 0);
+        assert_eq!(mmu.read_u8(MAX_RAM_SIZE - 1).unwrap(), 0);
+        mmu.write_u8(0x1234, 0xab).unwrap();
+        // the rest of the page that got allocated is zero too
+        assert_eq!(mmu.read_u32(0x1230).unwrap(), 0x0000_0000);
+        assert_eq!(mmu.read_u32(0x1234).unwrap(), 0xab00_0000);
+        assert_eq!(mmu.resident_pages(), 1);
+    }
+
+    #[test]
+    fn accesses_past_the_end_fault() {
+        let mut mmu = MMU::with_size(0x2000);
+        assert_eq!(mmu.read_u8(0x2000), Err(MemFault::Unmapped { addr: 0x2000, access: Access::Read }));
+        assert_eq!(mmu.write_u32(0x5000, 1), Err(MemFault::Unmapped { addr: 0x5000, access: Access::Write }));
+        assert_eq!(mmu.check_execute(0x3000, 4), Err(MemFault::Unmapped { addr: 0x3000, access: Access::Execute }));
+        // one straddling the end faults at the end, and writes nothing
+        assert_eq!(mmu.write_u32(0x1ffe, 0xdead_beef), Err(MemFault::Unmapped { addr: 0x2000, access: Access::Write }));
+        assert_eq!(mmu.read_u16(0x1ffe).unwrap(), 0);
+        assert_eq!(mmu.read_bytes(usize::MAX, &mut [0u8; 2]), Err(MemFault::Unmapped { addr: usize::MAX, access: Access::Read }));
+    }
+
+    #[test]
+    fn accesses_straddle_pages() {
+        let mut mmu = MMU::new();
+        mmu.write_u32(PAGE_SIZE - 2, 0x1122_3344).unwrap();
+        assert_eq!(mmu.read_u32(PAGE_SIZE - 2).unwrap(), 0x1122_3344);
+        assert_eq!(mmu.read_u16(PAGE_SIZE - 2).unwrap(), 0x1122);
+        assert_eq!(mmu.read_u16(PAGE_SIZE).unwrap(), 0x3344);
+        mmu.write_u16(2 * PAGE_SIZE - 1, 0xbeef).unwrap();
+        assert_eq!(mmu.read_u8(2 * PAGE_SIZE - 1).unwrap(), 0xbe);
+        assert_eq!(mmu.read_u8(2 * PAGE_SIZE).unwrap(), 0xef);
+        assert_eq!(mmu.resident_pages(), 3);
+    }
+
+    #[test]
+    fn bulk_accesses_span_many_pages() {
+        let mut mmu = MMU::new();
+        let data: Vec<u8> = (0..3 * PAGE_SIZE).map(|i| i as u8 ^ (i >> 8) as u8).collect();
+        mmu.write_bytes(PAGE_SIZE / 2, &data).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        mmu.read_bytes(PAGE_SIZE / 2, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(mmu.resident_pages(), 4);
+        // a read spilling past what was written sees zeroes
+        let mut tail = [0xffu8; 8];
+        mmu.read_bytes(PAGE_SIZE / 2 + data.len() - 4, &mut tail).unwrap();
+        assert_eq!(tail[..4], data[data.len() - 4..]);
+        assert_eq!(tail[4..], [0u8; 4]);
+    }
+
+    #[test]
+    fn protected_pages_fault() {
+        let mut mmu = MMU::with_size(0x10000);
+        assert_eq!(mmu.permissions(0x1000), PERM_READ | PERM_WRITE | PERM_EXEC);
+        mmu.protect(0x1000, 0x1000, PERM_READ | PERM_EXEC);
+        assert_eq!(mmu.write_u8(0x1800, 1), Err(MemFault::Protected { addr: 0x1800, access: Access::Write, perms: PERM_READ | PERM_EXEC }));
+        // a write running into the page faults where it starts, and writes
+        // nothing before it
+        assert_eq!(mmu.write_u32(0xffe, 0xdead_beef), Err(MemFault::Protected { addr: 0x1000, access: Access::Write, perms: PERM_READ | PERM_EXEC }));
+        assert_eq!(mmu.read_u16(0xffe).unwrap(), 0);
+        assert!(mmu.check_execute(0x1ffc, 4).is_ok());
+        // pages that were never protected can't be executed
+        assert_eq!(mmu.check_execute(0x1ffe, 4), Err(MemFault::Protected { addr: 0x2000, access: Access::Execute, perms: PERM_READ | PERM_WRITE }));
+        mmu.protect(0x3000, 1, 0);
+        assert_eq!(mmu.read_u8(0x3fff), Err(MemFault::Protected { addr: 0x3fff, access: Access::Read, perms: 0 }));
+        // loaders can still write read-only pages
+        mmu.patch_bytes(0x1000, &[1, 2]).unwrap();
+        assert_eq!(mmu.read_u16(0x1000).unwrap(), 0x0102);
+        mmu.unprotect();
+        assert!(mmu.write_u8(0x3000, 1).is_ok());
+    }
+
+    #[test]
+    fn stack_sits_between_guard_pages() {
+        let (mut cpu, res) = loaded(0x10000, 0);
+        res.unwrap();
+        // the program's page, a guard page, the stack, then another guard
+        let stack = 2 * PAGE_SIZE;
+        assert_eq!(cpu.sp(), stack - 4);
+        assert_eq!(cpu.memory.permissions(0), PERM_READ | PERM_EXEC);
+        assert_eq!(cpu.memory.permissions(stack - 1), 0);
+        assert_eq!(cpu.memory.permissions(stack), PERM_READ | PERM_WRITE);
+        assert_eq!(cpu.memory.permissions(stack + PAGE_SIZE), 0);
+        assert!(cpu.memory.write_u32(stack + PAGE_SIZE - 4, 1).is_ok());
+        assert_eq!(cpu.memory.write_u32(stack + PAGE_SIZE - 2, 1), Err(MemFault::Protected { addr: stack + PAGE_SIZE, access: Access::Write, perms: 0 }));
+        assert_eq!(cpu.memory.read_u8(stack - 4), Err(MemFault::Protected { addr: stack - 4, access: Access::Read, perms: 0 }));
+    }
+
+    #[test]
+    fn stack_fits_below_a_program_at_the_top_of_ram() {
+        let (cpu, res) = loaded(0x10000, 0xf000);
+        res.unwrap();
+        assert_eq!(cpu.sp(), PAGE_SIZE - 4);
+        assert_eq!(cpu.memory.permissions(0), 0);
+        assert_eq!(cpu.memory.permissions(2 * PAGE_SIZE), 0);
+        // with no room for the stack and its guard pages, loading fails
+        let (_, res) = loaded(0x3000, 0x1000);
+        assert_eq!(res, Err(MemFault::Unmapped { addr: 0x3000, access: Access::Write }));
+    }
+
+    #[test]
+    fn page_walk_checks_both_levels() {
+        let mut mmu = paged();
+        mmu.write_u32(0x400010, 0x1234_5678).unwrap();
+        assert_eq!(mmu.read_u32(0x5010).unwrap(), 0x1234_5678);
+        // a read-only page
+        assert_eq!(mmu.read_u8(0x4000).unwrap(), 0);
+        assert_eq!(mmu.write_u8(0x4000, 1), Err(MemFault::PageFault { addr: 0x4000, access: Access::Write, present: true, user: false }));
+        // entries that aren't present, in the table and in the directory
+        assert_eq!(mmu.read_u8(0x10000), Err(MemFault::PageFault { addr: 0x10000, access: Access::Read, present: false, user: false }));
+        assert_eq!(mmu.check_execute(0x800000, 4), Err(MemFault::PageFault { addr: 0x800000, access: Access::Execute, present: false, user: false }));
+        mmu.set_user_mode(true);
+        assert!(mmu.write_u8(0x3000, 1).is_ok());
+        assert_eq!(mmu.read_u8(0x4000), Err(MemFault::PageFault { addr: 0x4000, access: Access::Read, present: true, user: true }));
+        // the directory entry leaves out `PTE_USER`, so it doesn't matter
+        // that the page has it
+        assert_eq!(mmu.read_u8(0x400010), Err(MemFault::PageFault { addr: 0x400010, access: Access::Read, present: true, user: true }));
+    }
+
+    #[test]
+    fn tlb_caches_translations_until_flushed() {
+        let mut mmu = paged();
+        mmu.write_u32(0x8000, 8).unwrap();
+        mmu.write_u32(0x9000, 9).unwrap();
+        let stats = mmu.tlb_stats();
+        assert_eq!(mmu.read_u32(0x8000).unwrap(), 8);
+        assert_eq!(mmu.read_u32(0x8004).unwrap(), 0);
+        assert_eq!(mmu.tlb_stats().hits, stats.hits + 2);
+        assert_eq!(mmu.tlb_stats().misses, stats.misses);
+        // remapping page 8 to frame 9 goes unnoticed until it's flushed
+        mmu.write_u32(0x2000 + 4 * 8, 0x9000 | PTE_PRESENT | PTE_WRITABLE | PTE_USER).unwrap();
+        assert_eq!(mmu.read_u32(0x8000).unwrap(), 8);
+        mmu.flush_tlb_page(0x7000);
+        assert_eq!(mmu.read_u32(0x8000).unwrap(), 8);
+        let stats = mmu.tlb_stats();
+        mmu.flush_tlb_page(0x8abc);
+        assert_eq!(mmu.read_u32(0x8000).unwrap(), 9);
+        assert_eq!(mmu.tlb_stats().misses, stats.misses + 1);
+        // without entries, every access walks the table
+        mmu.set_tlb_entries(0);
+        let stats = mmu.tlb_stats();
+        mmu.read_u32(0x8000).unwrap();
+        mmu.read_u32(0x8000).unwrap();
+        assert_eq!(mmu.tlb_stats(), TlbStats { misses: stats.misses + 2, ..stats });
+    }
+}
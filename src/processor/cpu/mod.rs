@@ -1,5 +1,17 @@
+pub mod control;
 pub mod cpu;
 pub mod mmu;
+pub mod paging;
+pub mod swap;
+mod config;
 mod interrupts;
+mod irq;
+mod step;
 
-pub use cpu::CPU;
\ No newline at end of file
+pub use cpu::{CPU, Limit, RunOutcome};
+pub use config::{MachineConfig, DEFAULT_STACK_SIZE};
+pub use irq::{IrqHandle, NUM_IRQS};
+pub use mmu::{MMU, MemAccess};
+pub use paging::TlbStats;
+pub use step::{Effect, Step, StopHandle};
+pub use swap::{Replacement, SwapStats};
@@ -0,0 +1,97 @@
+use std::collections::VecDeque;
+
+/// the entry maps a page table (in the directory) or a page (in a table)
+pub const PTE_PRESENT: u32 = 1 << 0;
+/// the page can be written
+pub const PTE_WRITABLE: u32 = 1 << 1;
+/// the page can be touched from user mode
+pub const PTE_USER: u32 = 1 << 2;
+/// the bits of an entry holding the physical address it points at
+pub const PTE_FRAME: u32 = !0xfff;
+
+/// how many translations the TLB caches unless configured otherwise
+pub const DEFAULT_TLB_ENTRIES: usize = 16;
+
+
+/// how the TLB has fared since the machine started
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TlbStats {
+    /// translations found in the TLB
+    pub hits: u64,
+    /// translations that needed a page table walk
+    pub misses: u64,
+    /// times the whole TLB was emptied
+    pub flushes: u64
+}
+
+
+/// a cached translation of one virtual page
+#[derive(Clone, Copy, Debug)]
+struct TlbEntry {
+    page: usize,
+    frame: usize,
+    /// the `PTE_*` bits allowed by both levels of the page table
+    flags: u32
+}
+
+
+/// a fully associative translation cache that replaces its oldest entry
+/// when full. Like a real one, it isn't kept in step with the page tables:
+/// the guest has to flush entries after changing them
+pub(crate) struct Tlb {
+    entries: VecDeque<TlbEntry>,
+    capacity: usize,
+    stats: TlbStats
+}
+
+impl Tlb {
+    pub fn new(capacity: usize) -> Self {
+        Tlb { entries: VecDeque::with_capacity(capacity), capacity, stats: TlbStats::default() }
+    }
+
+    /// changes how many entries the TLB holds, emptying it
+    pub fn resize(&mut self, capacity: usize) {
+        self.entries.clear();
+        self.capacity = capacity;
+    }
+
+    pub fn stats(&self) -> TlbStats {
+        self.stats
+    }
+
+    /// the frame and flags cached for virtual page `page`
+    pub fn lookup(&mut self, page: usize) -> Option<(usize, u32)> {
+        match self.entries.iter().find(|e| e.page == page) {
+            Some(e) => {
+                self.stats.hits += 1;
+                Some((e.frame, e.flags))
+            },
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// caches a translation, evicting the oldest if the TLB is full
+    pub fn insert(&mut self, page: usize, frame: usize, flags: u32) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(TlbEntry { page, frame, flags });
+    }
+
+    /// drops every cached translation
+    pub fn flush(&mut self) {
+        self.entries.clear();
+        self.stats.flushes += 1;
+    }
+
+    /// drops the translation of virtual page `page`, if cached
+    pub fn flush_page(&mut self, page: usize) {
+        self.entries.retain(|e| e.page != page);
+    }
+}
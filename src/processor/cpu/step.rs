@@ -0,0 +1,58 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::processor::instructions::Instruction;
+use super::RunOutcome;
+
+
+/// a change to machine state, or a memory access, made by an instruction
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Effect {
+    /// a general purpose register changed
+    Reg { reg: u8, old: u32, new: u32 },
+    /// the stack pointer moved
+    Sp { old: usize, new: usize },
+    /// the flag register changed
+    Flags { old: u8, new: u8 },
+    /// contiguous bytes were read. Instruction fetches are not included
+    MemRead { addr: usize, bytes: Vec<u8> },
+    /// contiguous bytes were written
+    MemWrite { addr: usize, old: Vec<u8>, new: Vec<u8> }
+}
+
+
+/// what a single call to `CPU::step` did
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Step {
+    /// address of the executed instruction
+    pub pc: usize,
+    /// the decoded instruction, or `None` if the opcode didn't decode
+    pub inst: Option<Instruction>,
+    /// where `pc` ended up afterwards
+    pub next_pc: usize,
+    /// memory accesses in the order they happened, followed by register,
+    /// stack pointer and flag changes
+    pub effects: Vec<Effect>,
+    /// set when the instruction stopped the program
+    pub outcome: Option<RunOutcome>
+}
+
+
+/// asks a running CPU to stop. Handles can be cloned and sent to other
+/// threads; the CPU notices the request before its next instruction and
+/// returns `RunOutcome::Stopped`
+#[derive(Clone, Debug, Default)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    /// requests a stop
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// clears a pending request, returning whether there was one
+    pub(crate) fn take(&self) -> bool {
+        // checked on every instruction, so avoid the swap in the common case
+        self.0.load(Ordering::Relaxed) && self.0.swap(false, Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,488 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use crate::trace;
+use crate::error::MemFault;
+use crate::processor::cpu::mmu::PAGE_SIZE;
+
+/// how many references make up a window when the working set is measured,
+/// unless configured otherwise
+pub const DEFAULT_WS_WINDOW: u64 = 10_000;
+
+/// bit of a recorded reference that marks it as a write
+const REF_WRITE: u32 = 1 << 31;
+
+
+/// which resident page makes way when a page is needed and every frame is
+/// taken
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Replacement {
+    /// the page that was brought in longest ago
+    #[default]
+    Fifo,
+    /// the page that was used longest ago
+    Lru,
+    /// second chance: sweep the frames, skipping (and clearing) any page
+    /// used since the last sweep
+    Clock,
+    /// the page whose next use is furthest away. The future isn't known
+    /// while the program runs, so it keeps every page resident and works out
+    /// what Optimal would have done from the references once it's over
+    Optimal
+}
+
+impl Replacement {
+    /// picks a policy by name (`fifo`, `lru`, `clock` or `optimal`)
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name.to_lowercase().as_str() {
+            "fifo" => Ok(Replacement::Fifo),
+            "lru" => Ok(Replacement::Lru),
+            "clock" => Ok(Replacement::Clock),
+            "optimal" | "opt" => Ok(Replacement::Optimal),
+            n => Err(format!("unknown replacement policy {} (expected fifo, lru, clock or optimal)", n))
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Replacement::Fifo => "fifo",
+            Replacement::Lru => "lru",
+            Replacement::Clock => "clock",
+            Replacement::Optimal => "optimal"
+        }
+    }
+}
+
+
+/// what paging to swap cost over a run
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SwapStats {
+    /// pages the program touched, counting every page an access spans
+    pub references: u64,
+    /// references to pages that weren't in a frame, including the first
+    /// touch of each page
+    pub faults: u64,
+    /// faults that read the page back from swap
+    pub swap_ins: u64,
+    /// pages pushed out of their frame
+    pub evictions: u64,
+    /// evicted pages that had to be written to swap
+    pub writebacks: u64,
+    /// how many different pages were touched
+    pub pages: usize,
+    /// how many references make up a working set window
+    pub ws_window: u64,
+    /// windows measured, counting a final partial one
+    pub ws_windows: u64,
+    /// the working set sizes of every window, added up
+    pub ws_total: u64,
+    /// the largest working set of any window
+    pub ws_peak: usize
+}
+
+impl SwapStats {
+    /// the average number of pages touched per window
+    pub fn mean_working_set(&self) -> f64 {
+        match self.ws_windows {
+            0 => 0.0,
+            n => self.ws_total as f64 / n as f64
+        }
+    }
+}
+
+
+/// a physical frame and the page it holds
+struct Frame {
+    page: usize,
+    data: Box<[u8; PAGE_SIZE]>,
+    /// written since it was brought in, so must go to swap when evicted
+    dirty: bool,
+    /// used since the clock hand last passed
+    referenced: bool,
+    /// when it was last used, in references
+    last_used: u64
+}
+
+/// where a page is, and when it was last counted in the working set
+#[derive(Default)]
+struct PageInfo {
+    frame: Option<usize>,
+    /// its place in the swap file, once it has been written there
+    slot: Option<u64>,
+    /// the last window it was referenced in, plus one
+    window: u64
+}
+
+
+/// backs memory with a fixed number of frames, moving pages out to a swap
+/// file and back as they're needed
+pub(crate) struct Pager {
+    policy: Replacement,
+    capacity: usize,
+    frames: Vec<Frame>,
+    /// frames in the order their pages came in, for FIFO
+    loaded: VecDeque<usize>,
+    /// the next frame the clock hand looks at
+    hand: usize,
+    pages: HashMap<usize, PageInfo>,
+    swap_path: Option<PathBuf>,
+    /// created on the first writeback
+    swap: Option<File>,
+    next_slot: u64,
+    stats: SwapStats,
+    /// references in the current working set window so far, and how many
+    /// pages they touched
+    window_refs: u64,
+    window_pages: usize,
+    /// every reference as a page number plus `REF_WRITE`, with repeats of
+    /// the same page merged, for working out Optimal afterwards
+    reference_string: Vec<u32>
+}
+
+impl Pager {
+    /// `frames` frames, swapping to `swap_path` (or a temporary file) with
+    /// `policy`
+    pub fn new(frames: usize, policy: Replacement, swap_path: Option<PathBuf>, ws_window: u64) -> Self {
+        Pager {
+            policy,
+            capacity: frames.max(1),
+            frames: Vec::new(),
+            loaded: VecDeque::new(),
+            hand: 0,
+            pages: HashMap::new(),
+            swap_path,
+            swap: None,
+            next_slot: 0,
+            stats: SwapStats { ws_window: ws_window.max(1), ..SwapStats::default() },
+            window_refs: 0,
+            window_pages: 0,
+            reference_string: Vec::new()
+        }
+    }
+
+    /// how many frames hold a page
+    pub fn resident(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// the statistics so far. For Optimal, faults and evictions are worked
+    /// out from the references here
+    pub fn stats(&self) -> SwapStats {
+        let mut stats = self.stats;
+        if self.window_refs > 0 {
+            stats.ws_windows += 1;
+            stats.ws_total += self.window_pages as u64;
+            stats.ws_peak = stats.ws_peak.max(self.window_pages);
+        }
+        if self.policy == Replacement::Optimal {
+            let (faults, swap_ins, evictions, writebacks) = optimal(&self.reference_string, self.capacity);
+            stats.faults = faults;
+            stats.swap_ins = swap_ins;
+            stats.evictions = evictions;
+            stats.writebacks = writebacks;
+        }
+        stats
+    }
+
+    /// the frame holding page `page`, bringing it in if it isn't resident.
+    /// Counts as a reference, and marks the page dirty if `write`
+    pub fn frame(&mut self, page: usize, write: bool) -> Result<&mut [u8; PAGE_SIZE], MemFault> {
+        self.reference(page, write);
+        let frame = match self.pages.get(&page).and_then(|p| p.frame) {
+            Some(a) => a,
+            None => self.fault(page)?
+        };
+
+        let f = &mut self.frames[frame];
+        f.dirty |= write;
+        f.referenced = true;
+        f.last_used = self.stats.references;
+        Ok(&mut f.data)
+    }
+
+    /// counts a reference to `page` towards the statistics
+    fn reference(&mut self, page: usize, write: bool) {
+        self.stats.references += 1;
+        if self.window_refs == self.stats.ws_window {
+            self.stats.ws_windows += 1;
+            self.stats.ws_total += self.window_pages as u64;
+            self.stats.ws_peak = self.stats.ws_peak.max(self.window_pages);
+            self.window_refs = 0;
+            self.window_pages = 0;
+        }
+        self.window_refs += 1;
+
+        let window = self.stats.ws_windows + 1;
+        let info = self.pages.entry(page).or_default();
+        if info.window != window {
+            info.window = window;
+            self.window_pages += 1;
+        }
+        self.stats.pages = self.pages.len();
+
+        if self.policy == Replacement::Optimal {
+            let r = page as u32 | if write { REF_WRITE } else { 0 };
+            match self.reference_string.last_mut() {
+                Some(last) if *last & !REF_WRITE == page as u32 => *last |= r,
+                _ => self.reference_string.push(r)
+            }
+        }
+    }
+
+    /// brings `page` into a frame, evicting another page if they're all
+    /// taken, and returns the frame
+    fn fault(&mut self, page: usize) -> Result<usize, MemFault> {
+        self.stats.faults += 1;
+        let frame = match self.frames.len() < self.capacity || self.policy == Replacement::Optimal {
+            true => {
+                self.frames.push(Frame {
+                    page,
+                    data: Box::new([0u8; PAGE_SIZE]),
+                    dirty: false,
+                    referenced: false,
+                    last_used: 0
+                });
+                self.frames.len() - 1
+            },
+            false => {
+                let victim = self.victim();
+                self.evict(victim)?;
+                self.frames[victim].page = page;
+                self.frames[victim].dirty = false;
+                victim
+            }
+        };
+        if self.policy == Replacement::Fifo {
+            self.loaded.push_back(frame);
+        }
+
+        let slot = self.pages.get(&page).and_then(|p| p.slot);
+        let data = &mut self.frames[frame].data;
+        match slot {
+            Some(slot) => {
+                trace!("Swapping in page number {} from slot {}", page, slot);
+                let fail = |e: std::io::Error| MemFault::Swap { addr: page * PAGE_SIZE, error: e.to_string() };
+                let swap = self.swap.as_mut().expect("pages only have slots once the swap file exists");
+                swap.seek(SeekFrom::Start(slot * PAGE_SIZE as u64)).map_err(fail)?;
+                swap.read_exact(&mut data[..]).map_err(fail)?;
+                self.stats.swap_ins += 1;
+            },
+            None => data.fill(0)
+        }
+        if let Some(info) = self.pages.get_mut(&page) {
+            info.frame = Some(frame);
+        }
+        Ok(frame)
+    }
+
+    /// picks the frame to empty
+    fn victim(&mut self) -> usize {
+        match self.policy {
+            Replacement::Fifo | Replacement::Optimal => self.loaded.pop_front().unwrap_or(0),
+            Replacement::Lru => self.frames.iter().enumerate()
+                .min_by_key(|(_, f)| f.last_used)
+                .map(|(i, _)| i)
+                .unwrap_or(0),
+            Replacement::Clock => loop {
+                let frame = self.hand;
+                self.hand = (self.hand + 1) % self.frames.len();
+                if !std::mem::take(&mut self.frames[frame].referenced) {
+                    break frame;
+                }
+            }
+        }
+    }
+
+    /// writes the page in `frame` out to swap if it has changed since it
+    /// came in, and forgets it's resident
+    fn evict(&mut self, frame: usize) -> Result<(), MemFault> {
+        let page = self.frames[frame].page;
+        self.stats.evictions += 1;
+        trace!("Evicting page number {} from frame {}", page, frame);
+
+        if self.frames[frame].dirty {
+            let slot = match self.pages.get(&page).and_then(|p| p.slot) {
+                Some(a) => a,
+                None => {
+                    self.next_slot += 1;
+                    self.next_slot - 1
+                }
+            };
+            let fail = |e: std::io::Error| MemFault::Swap { addr: page * PAGE_SIZE, error: e.to_string() };
+            self.open_swap().map_err(fail)?;
+            let swap = self.swap.as_mut().expect("the swap file was just opened");
+            swap.seek(SeekFrom::Start(slot * PAGE_SIZE as u64)).map_err(fail)?;
+            swap.write_all(&self.frames[frame].data[..]).map_err(fail)?;
+            self.stats.writebacks += 1;
+            if let Some(info) = self.pages.get_mut(&page) {
+                info.slot = Some(slot);
+            }
+        }
+        if let Some(info) = self.pages.get_mut(&page) {
+            info.frame = None;
+        }
+        Ok(())
+    }
+
+    /// creates the swap file if this is the first writeback. A temporary
+    /// one is unlinked straight away, so it goes however the program exits
+    fn open_swap(&mut self) -> std::io::Result<()> {
+        if self.swap.is_some() {
+            return Ok(());
+        }
+        let path = match &self.swap_path {
+            Some(a) => a.clone(),
+            None => std::env::temp_dir().join(format!("deadbolt-swap-{}-{:p}", std::process::id(), self))
+        };
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+        trace!("Created swap file {}", path.display());
+        if self.swap_path.is_none() {
+            std::fs::remove_file(&path)?;
+        }
+        self.swap = Some(file);
+        Ok(())
+    }
+}
+
+
+/// replays `refs` with `frames` frames, always evicting the page used
+/// furthest in the future (Belady's algorithm). Returns the faults, swap
+/// ins, evictions and writebacks a real run would have seen
+fn optimal(refs: &[u32], frames: usize) -> (u64, u64, u64, u64) {
+    // where each reference's page is next used
+    let mut next_use = vec![usize::MAX; refs.len()];
+    let mut seen: HashMap<u32, usize> = HashMap::new();
+    for (i, r) in refs.iter().enumerate().rev() {
+        let page = r & !REF_WRITE;
+        next_use[i] = seen.get(&page).copied().unwrap_or(usize::MAX);
+        seen.insert(page, i);
+    }
+
+    // resident pages by next use, and whether each is dirty
+    let mut by_next: BTreeSet<(usize, u32)> = BTreeSet::new();
+    let mut resident: HashMap<u32, (usize, bool)> = HashMap::new();
+    let mut in_swap: HashSet<u32> = HashSet::new();
+    let (mut faults, mut swap_ins, mut evictions, mut writebacks) = (0, 0, 0, 0);
+    for (i, r) in refs.iter().enumerate() {
+        let page = r & !REF_WRITE;
+        let write = r & REF_WRITE != 0;
+        let dirty = match resident.remove(&page) {
+            Some((next, dirty)) => {
+                by_next.remove(&(next, page));
+                dirty
+            },
+            None => {
+                faults += 1;
+                if in_swap.contains(&page) {
+                    swap_ins += 1;
+                }
+                if resident.len() >= frames {
+                    if let Some((_, victim)) = by_next.pop_last() {
+                        evictions += 1;
+                        if resident.remove(&victim).is_some_and(|(_, d)| d) {
+                            writebacks += 1;
+                            in_swap.insert(victim);
+                        }
+                    }
+                }
+                false
+            }
+        };
+        resident.insert(page, (next_use[i], dirty || write));
+        by_next.insert((next_use[i], page));
+    }
+    (faults, swap_ins, evictions, writebacks)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the reference string from Silberschatz et al.
+    const REFS: [usize; 20] = [7, 0, 1, 2, 0, 3, 0, 4, 2, 3, 0, 3, 2, 1, 2, 0, 1, 7, 0, 1];
+
+    fn replay(policy: Replacement, frames: usize, refs: &[usize]) -> SwapStats {
+        let mut pager = Pager::new(frames, policy, None, DEFAULT_WS_WINDOW);
+        for page in refs.iter() {
+            pager.frame(*page, false).unwrap();
+        }
+        pager.stats()
+    }
+
+    #[test]
+    fn textbook_fault_counts() {
+        for (policy, faults) in [(Replacement::Fifo, 15), (Replacement::Lru, 12),
+                                 (Replacement::Clock, 14), (Replacement::Optimal, 9)] {
+            let stats = replay(policy, 3, &REFS);
+            assert_eq!(stats.faults, faults, "{}", policy.name());
+            assert_eq!(stats.evictions, faults - 3, "{}", policy.name());
+            assert_eq!(stats.writebacks, 0, "{}", policy.name());
+            assert_eq!(stats.pages, 6);
+        }
+    }
+
+    #[test]
+    fn fifo_shows_beladys_anomaly() {
+        let refs = [1, 2, 3, 4, 1, 2, 5, 1, 2, 3, 4, 5];
+        assert_eq!(replay(Replacement::Fifo, 3, &refs).faults, 9);
+        assert_eq!(replay(Replacement::Fifo, 4, &refs).faults, 10);
+        assert_eq!(replay(Replacement::Optimal, 4, &refs).faults, 6);
+    }
+
+    #[test]
+    fn clock_gives_referenced_pages_a_second_chance() {
+        let mut pager = Pager::new(2, Replacement::Clock, None, DEFAULT_WS_WINDOW);
+        for page in [0, 1, 2] {
+            pager.frame(page, false).unwrap();
+        }
+        // both pages were referenced, so the hand cleared them both and came
+        // back round to page 0; page 1 survives
+        assert_eq!(pager.pages[&0].frame, None);
+        assert_eq!(pager.pages[&1].frame, Some(1));
+        pager.frame(3, false).unwrap();
+        assert_eq!(pager.pages[&1].frame, None);
+        assert_eq!(pager.pages[&2].frame, Some(0));
+    }
+
+    #[test]
+    fn dirty_pages_round_trip_through_one_swap_slot() {
+        let mut pager = Pager::new(1, Replacement::Fifo, None, DEFAULT_WS_WINDOW);
+        pager.frame(0, true).unwrap()[5] = 42;
+        pager.frame(1, false).unwrap();
+        assert_eq!(pager.frame(0, false).unwrap()[5], 42);
+        // page 1 was never written, so it goes without a writeback
+        pager.frame(0, true).unwrap()[5] = 43;
+        pager.frame(1, false).unwrap();
+        assert_eq!(pager.frame(0, false).unwrap()[5], 43);
+
+        let stats = pager.stats();
+        assert_eq!((stats.faults, stats.swap_ins, stats.evictions, stats.writebacks), (5, 2, 4, 2));
+        assert_eq!(pager.next_slot, 1);
+        assert_eq!(pager.pages[&1].slot, None);
+    }
+
+    #[test]
+    fn pages_keep_their_own_swap_slots() {
+        let mut pager = Pager::new(1, Replacement::Lru, None, DEFAULT_WS_WINDOW);
+        for page in 0..3 {
+            pager.frame(page, true).unwrap()[PAGE_SIZE - 1] = page as u8 + 1;
+        }
+        for page in [1, 0, 2, 0] {
+            assert_eq!(pager.frame(page, false).unwrap()[PAGE_SIZE - 1], page as u8 + 1);
+        }
+        assert_eq!(pager.next_slot, 3);
+    }
+
+    #[test]
+    fn optimal_counts_writebacks_of_dirty_victims() {
+        let refs = [1 | REF_WRITE, 2, 3, 1];
+        // pages 2 and 3 are never used again, so 1 stays and nothing is written
+        assert_eq!(optimal(&refs, 2), (3, 0, 1, 0));
+        let refs = [1 | REF_WRITE, 2, 3, 2, 3, 1];
+        // page 1 is used furthest in the future, so it goes out dirty
+        assert_eq!(optimal(&refs, 2), (4, 1, 2, 1));
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt::{Display, Formatter};
+
+/// addressing modes for memory operands, stored in the top two bits of the
+/// addressing-mode byte
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddrMode {
+    /// `[base + disp]`
+    BaseDisp,
+    /// `[base + index*scale + disp]`
+    BaseIndex,
+    /// `[pc + disp]`, relative to the first byte of the instruction
+    PcRelative,
+    /// `[disp]`
+    Absolute
+}
+
+/// a decoded memory operand.
+///
+/// Instructions that take one are encoded as
+/// `opcode, reg << 4 | base, mode << 6 | log2(scale) << 4 | index, disp (u32)`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MemOperand {
+    pub mode: AddrMode,
+    pub base: u8,
+    pub index: u8,
+    pub scale: u8,
+    pub disp: u32
+}
+
+impl MemOperand {
+    /// builds an operand from the base register nibble, the addressing-mode
+    /// byte, and the displacement
+    pub fn decode(base: u8, mode: u8, disp: u32) -> Self {
+        let addr_mode = match mode >> 6 {
+            0 => AddrMode::BaseDisp,
+            1 => AddrMode::BaseIndex,
+            2 => AddrMode::PcRelative,
+            _ => AddrMode::Absolute
+        };
+
+        MemOperand {
+            mode: addr_mode,
+            base,
+            index: mode & 0x0f,
+            scale: 1 << ((mode >> 4) & 0x3),
+            disp
+        }
+    }
+
+    /// builds the addressing-mode byte for this operand
+    pub fn mode_byte(&self) -> u8 {
+        let mode = match self.mode {
+            AddrMode::BaseDisp => 0,
+            AddrMode::BaseIndex => 1,
+            AddrMode::PcRelative => 2,
+            AddrMode::Absolute => 3
+        };
+
+        (mode << 6) | ((self.scale.trailing_zeros() as u8 & 0x3) << 4) | (self.index & 0x0f)
+    }
+}
+
+/// formats the operand the way the assembler accepts it
+impl Display for MemOperand {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let mut terms = match self.mode {
+            AddrMode::BaseDisp => format!("r{}", self.base),
+            AddrMode::BaseIndex => match self.scale {
+                1 => format!("r{} + r{}", self.base, self.index),
+                s => format!("r{} + r{}*{}", self.base, self.index, s)
+            },
+            AddrMode::PcRelative => "pc".to_string(),
+            AddrMode::Absolute => String::new()
+        };
+
+        // show displacements with the top bit set as subtractions
+        if terms.is_empty() {
+            terms = format!("0x{:x}", self.disp);
+        } else if self.disp & 0x80000000 != 0 {
+            terms = format!("{} - 0x{:x}", terms, self.disp.wrapping_neg());
+        } else if self.disp != 0 {
+            terms = format!("{} + 0x{:x}", terms, self.disp);
+        }
+        write!(f, "[{}]", terms)
+    }
+}
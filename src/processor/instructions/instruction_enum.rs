@@ -3,6 +3,15 @@
 pub enum Instruction {
     LdReg,
     LdImm,             
+    Ldb,
+    Ldbs,
+    Ldh,
+    Ldhs,
+    Ldw,
+    Stb,
+    Sth,
+    Stw,
+    Lea,
     Swp,            
     PushAddr,
     PushReg,            
@@ -12,6 +21,28 @@ pub enum Instruction {
     AddImm,            
     SubReg,
     SubImm,          
+    AdcReg,
+    AdcImm,
+    SbbReg,
+    SbbImm,
+    DivReg,
+    DivImm,
+    SdivReg,
+    SdivImm,
+    ModReg,
+    ModImm,
+    SmodReg,
+    SmodImm,
+    ShlReg,
+    ShlImm,
+    ShrReg,
+    ShrImm,
+    SarReg,
+    SarImm,
+    RolReg,
+    RolImm,
+    RorReg,
+    RorImm,
     MulReg,
     MulImm,            
     AndReg,
@@ -29,11 +60,81 @@ pub enum Instruction {
     SfgImm,
     SfgReg,  
     Hlt,            
+    Brk,
     JmpAddr,        
     JmpImm,
     JmpReg,
     JeqReg,
     JeqImm,
     IntReg,
-    IntImm
+    IntImm,
+    Rdcr,
+    Wrcr,
+    Iret,
+    Cli,
+    Sti
+}
+
+impl Instruction {
+    /// the encoded size of the instruction in bytes, including the opcode
+    pub fn size(&self) -> usize {
+        match self {
+            Instruction::AddReg | Instruction::SubReg | Instruction::MulReg | 
+            Instruction::AdcReg | Instruction::SbbReg |
+            Instruction::DivReg | Instruction::SdivReg | Instruction::ModReg | Instruction::SmodReg |
+            Instruction::ShlReg | Instruction::ShrReg | Instruction::SarReg |
+            Instruction::RolReg | Instruction::RorReg |
+            Instruction::AndReg | Instruction::OrReg  | Instruction::XorReg | 
+            Instruction::CmpReg | Instruction::Swp | Instruction::MovDregSreg|
+            Instruction::LdReg => 2,
+            Instruction::AddImm | Instruction::SubImm | Instruction::MulImm | 
+            Instruction::AdcImm | Instruction::SbbImm |
+            Instruction::DivImm | Instruction::SdivImm | Instruction::ModImm | Instruction::SmodImm |
+            Instruction::ShlImm | Instruction::ShrImm | Instruction::SarImm |
+            Instruction::RolImm | Instruction::RorImm |
+            Instruction::AndImm | Instruction::OrImm  | Instruction::XorImm | 
+            Instruction::CmpImm | Instruction::MovDregSaddr | Instruction::MovDregSimm | Instruction::LdImm | Instruction::SfgReg |
+            Instruction::Rdcr => 6,
+            Instruction::PushReg | Instruction::Pop | 
+            Instruction::JmpReg | Instruction::IntReg | Instruction::JeqReg => 2,
+            Instruction::MovDaddrSreg | Instruction::Wrcr => 6,
+            Instruction::Ldb | Instruction::Ldbs | Instruction::Ldh | Instruction::Ldhs | 
+            Instruction::Ldw | Instruction::Stb | Instruction::Sth | Instruction::Stw |
+            Instruction::Lea => 7,
+            Instruction::PushAddr | Instruction::JmpAddr | Instruction::JeqImm | Instruction::JmpImm | Instruction::IntImm => 5,
+            Instruction::SfgImm => 3,
+            Instruction::Hlt | Instruction::Nop | Instruction::Brk | Instruction::Iret |
+            Instruction::Cli | Instruction::Sti => 1   
+        }
+    }
+
+    /// how many cycles the instruction takes in the emulator's simple cost
+    /// model: one for most operations, more for memory accesses, jumps,
+    /// multiplication, division and interrupts
+    pub fn cycles(&self) -> u64 {
+        match self {
+            Instruction::MulReg | Instruction::MulImm => 3,
+            Instruction::DivReg | Instruction::DivImm | Instruction::SdivReg | Instruction::SdivImm |
+            Instruction::ModReg | Instruction::ModImm | Instruction::SmodReg | Instruction::SmodImm => 12,
+            Instruction::LdReg | Instruction::LdImm |
+            Instruction::Ldb | Instruction::Ldbs | Instruction::Ldh | Instruction::Ldhs | Instruction::Ldw |
+            Instruction::Stb | Instruction::Sth | Instruction::Stw |
+            Instruction::MovDregSaddr | Instruction::MovDaddrSreg |
+            Instruction::PushAddr | Instruction::PushReg | Instruction::Pop => 2,
+            Instruction::JmpAddr | Instruction::JmpImm | Instruction::JmpReg |
+            Instruction::JeqReg | Instruction::JeqImm | Instruction::Iret => 2,
+            Instruction::IntReg | Instruction::IntImm => 10,
+            Instruction::Lea | Instruction::Swp | Instruction::Nop | Instruction::Hlt | Instruction::Brk |
+            Instruction::Cli | Instruction::Sti |
+            Instruction::AddReg | Instruction::AddImm | Instruction::SubReg | Instruction::SubImm |
+            Instruction::AdcReg | Instruction::AdcImm | Instruction::SbbReg | Instruction::SbbImm |
+            Instruction::ShlReg | Instruction::ShlImm | Instruction::ShrReg | Instruction::ShrImm |
+            Instruction::SarReg | Instruction::SarImm | Instruction::RolReg | Instruction::RolImm |
+            Instruction::RorReg | Instruction::RorImm |
+            Instruction::AndReg | Instruction::AndImm | Instruction::OrReg | Instruction::OrImm |
+            Instruction::XorReg | Instruction::XorImm | Instruction::CmpReg | Instruction::CmpImm |
+            Instruction::MovDregSreg | Instruction::MovDregSimm | Instruction::SfgImm | Instruction::SfgReg |
+            Instruction::Rdcr | Instruction::Wrcr => 1
+        }
+    }
 }
@@ -1,3 +1,5 @@
 mod instruction_enum;
+mod addressing;
 
 pub use instruction_enum::Instruction;
+pub use addressing::{AddrMode, MemOperand};
@@ -0,0 +1,186 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use crate::disasm::Disassembler;
+use crate::processor::cpu::{CPU, Step};
+use crate::processor::cpu::cpu::REG_LR;
+use crate::processor::instructions::Instruction;
+use crate::symbols::SymbolTable;
+
+
+/// the name of the outermost frame in call stacks
+const ROOT: &str = "[program]";
+/// how many addresses the report lists
+const TOP_ADDRESSES: usize = 20;
+
+/// how much was executed somewhere
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Counts {
+    pub instructions: u64,
+    pub cycles: u64
+}
+
+impl Counts {
+    fn add(&mut self, cycles: u64) {
+        self.instructions += 1;
+        self.cycles += cycles;
+    }
+}
+
+/// a routine entered by a call
+struct Frame {
+    /// where the routine returns to
+    ret: usize
+}
+
+
+/// counts instructions and cycles per address, label, call stack and
+/// opcode. Pass `observe` to `CPU::run_observed`.
+///
+/// The instruction set has no call instruction, so calls are recognised by
+/// convention: a taken jump to a label that leaves `lr` pointing just past
+/// the jump. The call returns when execution reaches that address again
+pub struct Profiler {
+    symbols: SymbolTable,
+    disassembler: Disassembler,
+    total: Counts,
+    by_addr: BTreeMap<usize, Counts>,
+    /// the disassembly of each executed address
+    text: HashMap<usize, String>,
+    by_opcode: HashMap<Instruction, Counts>,
+    by_stack: HashMap<String, Counts>,
+    frames: Vec<Frame>,
+    /// the current call stack as `[program];.a;.b`
+    stack: String
+}
+
+impl Profiler {
+    pub fn new(symbols: SymbolTable) -> Self {
+        Profiler {
+            symbols,
+            disassembler: Disassembler::new(),
+            total: Counts::default(),
+            by_addr: BTreeMap::new(),
+            text: HashMap::new(),
+            by_opcode: HashMap::new(),
+            by_stack: HashMap::new(),
+            frames: Vec::new(),
+            stack: ROOT.to_string()
+        }
+    }
+
+    /// counts one step
+    pub fn observe(&mut self, cpu: &CPU, step: &Step) {
+        let inst = match step.inst {
+            Some(a) => a,
+            None => return
+        };
+        let cycles = inst.cycles();
+        self.total.add(cycles);
+        self.by_addr.entry(step.pc).or_default().add(cycles);
+        self.by_opcode.entry(inst).or_default().add(cycles);
+        self.by_stack.entry(self.stack.clone()).or_default().add(cycles);
+        if !self.text.contains_key(&step.pc) {
+            let bytes: Vec<u8> = (step.pc..step.pc + inst.size()).map_while(|a| cpu.memory.read_u8(a).ok()).collect();
+            self.text.insert(step.pc, self.disassembler.decode(&bytes, step.pc).text);
+        }
+
+        let after = step.pc + inst.size();
+        let is_jump = matches!(inst,
+            Instruction::JmpAddr | Instruction::JmpImm | Instruction::JmpReg |
+            Instruction::JeqReg | Instruction::JeqImm);
+        let label = self.symbols.describe(step.next_pc as u32).filter(|(_, off)| *off == 0).map(|(l, _)| l);
+        match (is_jump && step.next_pc != after, label) {
+            (true, Some(label)) if cpu.get_reg(REG_LR).ok() == Some(after as u32) => {
+                self.frames.push(Frame { ret: after });
+                self.stack = format!("{};{}", self.stack, label);
+            },
+            _ => {
+                while self.frames.last().is_some_and(|f| f.ret == step.next_pc) {
+                    self.frames.pop();
+                    self.stack.truncate(self.stack.rfind(';').unwrap_or(self.stack.len()));
+                }
+            }
+        }
+    }
+
+    /// the totals over the whole run
+    pub fn total(&self) -> Counts {
+        self.total
+    }
+
+    /// counts for every executed address, in address order
+    pub fn by_addr(&self) -> impl Iterator<Item = (usize, Counts)> + '_ {
+        self.by_addr.iter().map(|(a, c)| (*a, *c))
+    }
+
+    /// counts per label, covering the code from each label to the next,
+    /// hottest first. Code before the first label is counted as `[none]`
+    pub fn by_label(&self) -> Vec<(String, Counts)> {
+        let mut labels: HashMap<String, Counts> = HashMap::new();
+        for (addr, counts) in self.by_addr.iter() {
+            let name = self.symbols.describe(*addr as u32).map(|(l, _)| l).unwrap_or("[none]");
+            let entry = labels.entry(name.to_string()).or_default();
+            entry.instructions += counts.instructions;
+            entry.cycles += counts.cycles;
+        }
+        let mut ret: Vec<(String, Counts)> = labels.into_iter().collect();
+        ret.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+        ret
+    }
+
+    /// counts per opcode, keyed by mnemonic, most executed first
+    pub fn by_opcode(&self) -> Vec<(&'static str, Counts)> {
+        let mut ret: Vec<(&'static str, Counts)> = self.by_opcode.iter()
+            .map(|(i, c)| (self.disassembler.mnemonic(*i), *c))
+            .collect();
+        ret.sort_by(|a, b| b.1.instructions.cmp(&a.1.instructions).then(a.0.cmp(b.0)));
+        ret
+    }
+
+    /// cycles per call stack, one `[program];.a;.b <cycles>` line each, as
+    /// flamegraph tools expect
+    pub fn write_folded(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let stacks: BTreeMap<&String, &Counts> = self.by_stack.iter().collect();
+        for (stack, counts) in stacks {
+            writeln!(out, "{} {}", stack, counts.cycles)?;
+        }
+        Ok(())
+    }
+
+    /// writes a text report of the hottest addresses, labels and opcodes
+    pub fn write_report(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let percent = |c: &Counts| match self.total.cycles {
+            0 => 0.0,
+            t => c.cycles as f64 * 100.0 / t as f64
+        };
+        writeln!(out, "profile: {} instructions, {} cycles", self.total.instructions, self.total.cycles)?;
+
+        writeln!(out)?;
+        writeln!(out, "hottest addresses:")?;
+        writeln!(out, "  {:>12} {:>12} {:>7}  {:<10}  {:<20} instruction", "instructions", "cycles", "cycles%", "address", "label")?;
+        let mut addrs: Vec<(usize, Counts)> = self.by_addr().collect();
+        addrs.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+        for (addr, c) in addrs.iter().take(TOP_ADDRESSES) {
+            let label = self.symbols.describe_str(*addr as u32).unwrap_or_default();
+            let text = self.text.get(addr).map(|t| t.as_str()).unwrap_or("");
+            writeln!(out, "  {:>12} {:>12} {:>6.2}%  0x{:08x}  {:<20} {}",
+                c.instructions, c.cycles, percent(c), addr, label, text)?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "by label:")?;
+        writeln!(out, "  {:>12} {:>12} {:>7}  label", "instructions", "cycles", "cycles%")?;
+        for (label, c) in self.by_label() {
+            writeln!(out, "  {:>12} {:>12} {:>6.2}%  {}", c.instructions, c.cycles, percent(&c), label)?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "by opcode:")?;
+        writeln!(out, "  {:>12} {:>12} {:>7}  mnemonic", "count", "cycles", "cycles%")?;
+        for (name, c) in self.by_opcode() {
+            writeln!(out, "  {:>12} {:>12} {:>6.2}%  {}", c.instructions, c.cycles, percent(&c), name)?;
+        }
+        Ok(())
+    }
+}
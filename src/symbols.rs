@@ -0,0 +1,168 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+
+/// the source line that the `size` bytes at `addr` were assembled from
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LineEntry {
+    pub addr: u32,
+    pub size: u32,
+    /// 1-based line number
+    pub line: usize,
+    /// whether the line holds data (a `bytes` directive) rather than code
+    pub data: bool
+}
+
+/// maps label names to addresses and back, and addresses to source lines.
+/// Names include the leading `.`
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SymbolTable {
+    by_name: HashMap<String, u32>,
+    by_addr: BTreeMap<u32, String>,
+    lines: BTreeMap<u32, LineEntry>,
+    source: Option<PathBuf>
+}
+
+impl SymbolTable {
+    /// builds a table from the assembler's labels
+    pub fn from_labels(labels: &HashMap<String, u32>) -> Self {
+        let mut table = SymbolTable::default();
+        for (name, addr) in labels.iter() {
+            table.insert(name.clone(), *addr);
+        }
+        table
+    }
+
+    /// adds a label. When several labels share an address, the
+    /// alphabetically first is used to describe it
+    pub fn insert(&mut self, name: String, addr: u32) {
+        match self.by_addr.get(&addr) {
+            Some(existing) if *existing <= name => (),
+            _ => { self.by_addr.insert(addr, name.clone()); }
+        }
+        self.by_name.insert(name, addr);
+    }
+
+    /// the address of a label
+    pub fn addr_of(&self, name: &str) -> Option<u32> {
+        self.by_name.get(name).copied()
+    }
+
+    /// the closest label at or before `addr`, and how far past it `addr` is
+    pub fn describe(&self, addr: u32) -> Option<(&str, u32)> {
+        self.by_addr.range(..=addr).next_back().map(|(a, name)| (name.as_str(), addr - a))
+    }
+
+    /// the closest label at or before `addr`, formatted as `.label+0x4`
+    pub fn describe_str(&self, addr: u32) -> Option<String> {
+        self.describe(addr).map(|(name, off)| match off {
+            0 => name.to_string(),
+            off => format!("{}+0x{:x}", name, off)
+        })
+    }
+
+    /// labels ordered by address
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &str)> {
+        self.by_name.iter().map(|(n, a)| (*a, n.as_str()))
+            .collect::<BTreeMap<_, _>>().into_iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty() && self.lines.is_empty()
+    }
+
+    /// records which source line the bytes at `entry.addr` came from
+    pub fn insert_line(&mut self, entry: LineEntry) {
+        self.lines.insert(entry.addr, entry);
+    }
+
+    /// the line table entry covering `addr`
+    pub fn line_of(&self, addr: u32) -> Option<LineEntry> {
+        self.lines.range(..=addr).next_back()
+            .map(|(_, e)| *e)
+            .filter(|e| addr < e.addr + e.size.max(1))
+    }
+
+    /// the first instruction assembled from `line` or, if that line has no
+    /// code, from the closest line after it
+    pub fn addr_of_line(&self, line: usize) -> Option<LineEntry> {
+        self.lines.values()
+            .filter(|e| e.line >= line && !e.data)
+            .min_by_key(|e| (e.line, e.addr))
+            .copied()
+    }
+
+    /// where `addr` came from in the source, formatted as `file.dba:12`
+    pub fn location(&self, addr: u32) -> Option<String> {
+        let entry = self.line_of(addr)?;
+        let file = self.source.as_ref()
+            .and_then(|s| s.file_name())
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or("line".to_string());
+        Some(format!("{}:{}", file, entry.line))
+    }
+
+    /// line table entries ordered by address
+    pub fn lines(&self) -> impl Iterator<Item = &LineEntry> {
+        self.lines.values()
+    }
+
+    /// the source file the line table refers to
+    pub fn source(&self) -> Option<&Path> {
+        self.source.as_deref()
+    }
+
+    pub fn set_source(&mut self, path: Option<PathBuf>) {
+        self.source = path;
+    }
+
+    /// reads a symbol file, as written by `write`
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut table = SymbolTable::default();
+        for (i, line) in text.lines().enumerate() {
+            let err = |msg: String| format!("{}:{}: {}", path.display(), i + 1, msg);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let (addr, rest) = match line.split_once(char::is_whitespace) {
+                Some(a) => (a.0, a.1.trim()),
+                None => return Err(err("expected `<addr> <label>`".to_string()))
+            };
+            if addr == "file" {
+                table.source = Some(PathBuf::from(rest));
+                continue;
+            }
+            let addr = u32::from_str_radix(addr.trim_start_matches("0x"), 16)
+                .map_err(|_| err(format!("invalid address {}", addr)))?;
+            match rest.split_whitespace().collect::<Vec<&str>>().as_slice() {
+                [kind @ ("line" | "data"), line, size] => {
+                    let line = line.parse::<usize>().map_err(|_| err(format!("invalid line {}", line)))?;
+                    let size = size.parse::<u32>().map_err(|_| err(format!("invalid size {}", size)))?;
+                    table.insert_line(LineEntry { addr, size, line, data: *kind == "data" });
+                },
+                _ => table.insert(rest.to_string(), addr)
+            }
+        }
+        Ok(table)
+    }
+
+    /// writes the table as one `0x<addr> <label>` line per label, followed
+    /// by the source file as `file <path>` and one `0x<addr> line <n> <size>`
+    /// line per line table entry (`data` instead of `line` for data)
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let mut text: String = self.iter().map(|(a, n)| format!("0x{:08x} {}\n", a, n)).collect();
+        if let Some(source) = &self.source {
+            text.push_str(&format!("file {}\n", source.display()));
+        }
+        for e in self.lines.values() {
+            let kind = match e.data {
+                true => "data",
+                false => "line"
+            };
+            text.push_str(&format!("0x{:08x} {} {} {}\n", e.addr, kind, e.line, e.size));
+        }
+        std::fs::write(path, text)
+    }
+}
@@ -0,0 +1,665 @@
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use crate::disasm::Disassembler;
+use crate::processor::cpu::{CPU, Effect, Step};
+use crate::processor::cpu::cpu::NUM_REGS;
+use crate::symbols::SymbolTable;
+
+
+/// everything a single executed instruction did
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceRecord {
+    /// the cycle the instruction started on, counting the cycles of every
+    /// instruction before it as the profiler does
+    pub cycle: u64,
+    pub pc: usize,
+    /// the instruction as assembly text, e.g. `movi r1, 0x2b`
+    pub inst: String,
+    pub regs_before: [u32; NUM_REGS],
+    pub regs_after: [u32; NUM_REGS],
+    pub sp_before: usize,
+    pub sp_after: usize,
+    pub flags_before: u8,
+    pub flags_after: u8,
+    /// contiguous reads as `(addr, bytes)`, not counting instruction fetches
+    pub reads: Vec<(usize, Vec<u8>)>,
+    /// contiguous writes as `(addr, old, new)`
+    pub writes: Vec<(usize, Vec<u8>, Vec<u8>)>
+}
+
+impl TraceRecord {
+    /// the column names of the CSV format
+    pub const CSV_HEADER: &'static str =
+        "cycle,pc,inst,regs_before,regs_after,sp_before,sp_after,flags_before,flags_after,reads,writes";
+
+    /// builds a record from a step and the machine state after it
+    pub fn from_step(cycle: u64, cpu: &CPU, step: &Step, disassembler: &Disassembler) -> Self {
+        let mut regs_before = *cpu.regs();
+        let regs_after = regs_before;
+        let (mut sp_before, mut flags_before) = (cpu.sp(), cpu.flags());
+        let mut reads = Vec::new();
+        let mut writes = Vec::new();
+        for effect in step.effects.iter() {
+            match effect {
+                Effect::Reg { reg, old, .. } => regs_before[*reg as usize] = *old,
+                Effect::Sp { old, .. } => sp_before = *old,
+                Effect::Flags { old, .. } => flags_before = *old,
+                Effect::MemRead { addr, bytes } => reads.push((*addr, bytes.clone())),
+                Effect::MemWrite { addr, old, new } => writes.push((*addr, old.clone(), new.clone()))
+            }
+        }
+
+        // decode the instruction as it was before it ran, in case it
+        // overwrote itself
+        let mut bytes: Vec<u8> = (step.pc..step.pc + 8).map_while(|a| cpu.memory.read_u8(a).ok()).collect();
+        for (addr, old, _) in writes.iter() {
+            for (i, b) in old.iter().enumerate() {
+                if let Some(slot) = (addr + i).checked_sub(step.pc).and_then(|off| bytes.get_mut(off)) {
+                    *slot = *b;
+                }
+            }
+        }
+        let inst = disassembler.decode(&bytes, step.pc).text;
+
+        TraceRecord {
+            cycle,
+            pc: step.pc,
+            inst,
+            regs_before,
+            regs_after,
+            sp_before,
+            sp_after: cpu.sp(),
+            flags_before,
+            flags_after: cpu.flags(),
+            reads,
+            writes
+        }
+    }
+
+    /// the record as a JSON object. Addresses and registers are numbers, and
+    /// memory contents are hex strings
+    pub fn to_json(&self) -> Value {
+        json!({
+            "cycle": self.cycle,
+            "pc": self.pc,
+            "inst": self.inst,
+            "regs_before": self.regs_before,
+            "regs_after": self.regs_after,
+            "sp_before": self.sp_before,
+            "sp_after": self.sp_after,
+            "flags_before": self.flags_before,
+            "flags_after": self.flags_after,
+            "reads": self.reads.iter()
+                .map(|(addr, bytes)| json!({ "addr": addr, "bytes": hex(bytes) }))
+                .collect::<Vec<Value>>(),
+            "writes": self.writes.iter()
+                .map(|(addr, old, new)| json!({ "addr": addr, "old": hex(old), "new": hex(new) }))
+                .collect::<Vec<Value>>()
+        })
+    }
+
+    /// the record as a line of CSV, in the order of `CSV_HEADER`. Registers
+    /// are space separated, reads are written as `0x<addr>:<hex>` and writes
+    /// as `0x<addr>:<old hex>-><new hex>`
+    pub fn to_csv(&self) -> String {
+        let regs = |r: &[u32]| r.iter().map(|v| format!("0x{:x}", v)).collect::<Vec<String>>().join(" ");
+        let reads: Vec<String> = self.reads.iter()
+            .map(|(addr, bytes)| format!("0x{:x}:{}", addr, hex(bytes)))
+            .collect();
+        let writes: Vec<String> = self.writes.iter()
+            .map(|(addr, old, new)| format!("0x{:x}:{}->{}", addr, hex(old), hex(new)))
+            .collect();
+        format!("{},0x{:x},{},{},{},0x{:x},0x{:x},0x{:02x},0x{:02x},{},{}",
+            self.cycle, self.pc, csv_field(&self.inst),
+            regs(&self.regs_before), regs(&self.regs_after),
+            self.sp_before, self.sp_after, self.flags_before, self.flags_after,
+            reads.join(" "), writes.join(" "))
+    }
+}
+
+/// formats bytes as a string of hex digits
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// quotes a CSV field if it needs it
+fn csv_field(text: &str) -> String {
+    match text.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", text.replace('"', "\"\"")),
+        false => text.to_string()
+    }
+}
+
+
+/// how trace records are written
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TraceFormat {
+    /// one JSON object per line
+    Jsonl,
+    /// comma separated values, with a header line
+    Csv
+}
+
+impl TraceFormat {
+    /// picks a format by name (`jsonl` or `csv`)
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name.to_lowercase().as_str() {
+            "jsonl" | "json" => Ok(TraceFormat::Jsonl),
+            "csv" => Ok(TraceFormat::Csv),
+            n => Err(format!("unknown trace format {} (expected jsonl or csv)", n))
+        }
+    }
+
+    /// guesses the format from a file's extension, defaulting to JSON lines
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().is_some_and(|e| e.eq_ignore_ascii_case("csv")) {
+            true => TraceFormat::Csv,
+            false => TraceFormat::Jsonl
+        }
+    }
+}
+
+
+/// parses a trace filter into the address range it covers. Filters are a
+/// label (covering the code up to the next label), a single address, or a
+/// `start..end` range whose ends are addresses or labels
+pub fn parse_filter(spec: &str, symbols: &SymbolTable) -> Result<Range<usize>, String> {
+    let addr = |text: &str| -> Result<usize, String> {
+        let text = text.trim();
+        if text.starts_with('.') {
+            return symbols.addr_of(text).map(|a| a as usize).ok_or(format!("unknown label {}", text));
+        }
+        let n = match text.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => text.parse::<usize>()
+        };
+        n.map_err(|_| format!("invalid address {}", text))
+    };
+
+    if let Some((start, end)) = spec.split_once("..") {
+        return Ok(addr(start)?..addr(end)?);
+    }
+    let start = addr(spec)?;
+    match spec.trim().starts_with('.') {
+        true => {
+            let end = symbols.iter().map(|(a, _)| a as usize).find(|a| *a > start).unwrap_or(usize::MAX);
+            Ok(start..end)
+        },
+        false => Ok(start..start + 1)
+    }
+}
+
+
+/// writes a record for every instruction a CPU executes. Pass `observe` to
+/// `CPU::run_observed`
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    filters: Vec<Range<usize>>,
+    disassembler: Disassembler,
+    cycle: u64,
+    header: bool,
+    error: Option<std::io::Error>
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, format: TraceFormat) -> Self {
+        Tracer {
+            out,
+            format,
+            filters: Vec::new(),
+            disassembler: Disassembler::new(),
+            cycle: 0,
+            header: format == TraceFormat::Csv,
+            error: None
+        }
+    }
+
+    /// only records instructions in `range`. With several filters, an
+    /// instruction in any of them is recorded
+    pub fn add_filter(&mut self, range: Range<usize>) {
+        self.filters.push(range);
+    }
+
+    /// records one step. Write errors stop the trace, and are reported by
+    /// `finish`
+    pub fn observe(&mut self, cpu: &CPU, step: &Step) {
+        let cycle = self.cycle;
+        self.cycle += step.inst.map_or(0, |i| i.cycles());
+        if self.error.is_some() {
+            return;
+        }
+        if !self.filters.is_empty() && !self.filters.iter().any(|r| r.contains(&step.pc)) {
+            return;
+        }
+
+        let record = TraceRecord::from_step(cycle, cpu, step, &self.disassembler);
+        let line = match self.format {
+            TraceFormat::Jsonl => record.to_json().to_string(),
+            TraceFormat::Csv => record.to_csv()
+        };
+        let result = match std::mem::take(&mut self.header) {
+            true => writeln!(self.out, "{}", TraceRecord::CSV_HEADER),
+            false => Ok(())
+        };
+        if let Err(e) = result.and_then(|_| writeln!(self.out, "{}", line)) {
+            self.error = Some(e);
+        }
+    }
+
+    /// flushes the trace, returning the first write error if there was one
+    pub fn finish(mut self) -> std::io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        if self.header {
+            writeln!(self.out, "{}", TraceRecord::CSV_HEADER)?;
+        }
+        self.out.flush()
+    }
+}
+
+
+impl TraceRecord {
+    /// reads a record written by `to_json`
+    pub fn from_json(v: &Value) -> Result<Self, String> {
+        let num = |key: &str| v[key].as_u64().ok_or(format!("missing or invalid {}", key));
+        let text = |v: &Value, key: &str| v[key].as_str().map(|s| s.to_string()).ok_or(format!("missing or invalid {}", key));
+        let regs = |key: &str| -> Result<[u32; NUM_REGS], String> {
+            let values = v[key].as_array().ok_or(format!("missing or invalid {}", key))?;
+            let values: Vec<u32> = values.iter().map(|r| r.as_u64().map(|r| r as u32)).collect::<Option<_>>()
+                .ok_or(format!("invalid {}", key))?;
+            values.try_into().map_err(|_| format!("{} should have {} registers", key, NUM_REGS))
+        };
+        let list = |key: &str| v[key].as_array().cloned().ok_or(format!("missing or invalid {}", key));
+
+        let mut reads = Vec::new();
+        for r in list("reads")? {
+            let addr = r["addr"].as_u64().ok_or("invalid read address")? as usize;
+            reads.push((addr, unhex(&text(&r, "bytes")?)?));
+        }
+        let mut writes = Vec::new();
+        for w in list("writes")? {
+            let addr = w["addr"].as_u64().ok_or("invalid write address")? as usize;
+            writes.push((addr, unhex(&text(&w, "old")?)?, unhex(&text(&w, "new")?)?));
+        }
+
+        Ok(TraceRecord {
+            cycle: num("cycle")?,
+            pc: num("pc")? as usize,
+            inst: text(v, "inst")?,
+            regs_before: regs("regs_before")?,
+            regs_after: regs("regs_after")?,
+            sp_before: num("sp_before")? as usize,
+            sp_after: num("sp_after")? as usize,
+            flags_before: num("flags_before")? as u8,
+            flags_after: num("flags_after")? as u8,
+            reads,
+            writes
+        })
+    }
+
+    /// reads a line written by `to_csv`
+    pub fn from_csv(line: &str) -> Result<Self, String> {
+        let fields = split_csv(line);
+        let [cycle, pc, inst, regs_before, regs_after, sp_before, sp_after, flags_before, flags_after, reads, writes] =
+            fields.as_slice() else {
+            return Err(format!("expected 11 fields, found {}", fields.len()));
+        };
+        let regs = |text: &str| -> Result<[u32; NUM_REGS], String> {
+            let values: Vec<u32> = text.split_whitespace().map(|r| parse_num(r).map(|r| r as u32))
+                .collect::<Result<_, String>>()?;
+            values.try_into().map_err(|_| format!("expected {} registers", NUM_REGS))
+        };
+        // `0x<addr>:<rest>`
+        let access = |text: &str| -> Result<(usize, String), String> {
+            let (addr, rest) = text.split_once(':').ok_or(format!("invalid memory access {}", text))?;
+            Ok((parse_num(addr)?, rest.to_string()))
+        };
+
+        let mut read_list = Vec::new();
+        for r in reads.split_whitespace() {
+            let (addr, bytes) = access(r)?;
+            read_list.push((addr, unhex(&bytes)?));
+        }
+        let mut write_list = Vec::new();
+        for w in writes.split_whitespace() {
+            let (addr, change) = access(w)?;
+            let (old, new) = change.split_once("->").ok_or(format!("invalid write {}", w))?;
+            write_list.push((addr, unhex(old)?, unhex(new)?));
+        }
+
+        Ok(TraceRecord {
+            cycle: parse_num(cycle)? as u64,
+            pc: parse_num(pc)?,
+            inst: inst.clone(),
+            regs_before: regs(regs_before)?,
+            regs_after: regs(regs_after)?,
+            sp_before: parse_num(sp_before)?,
+            sp_after: parse_num(sp_after)?,
+            flags_before: parse_num(flags_before)? as u8,
+            flags_after: parse_num(flags_after)? as u8,
+            reads: read_list,
+            writes: write_list
+        })
+    }
+}
+
+/// parses a decimal or `0x` prefixed hex number
+fn parse_num(text: &str) -> Result<usize, String> {
+    let text = text.trim();
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse::<usize>()
+    }.map_err(|_| format!("invalid number {}", text))
+}
+
+/// parses a string of hex digits back into bytes
+fn unhex(text: &str) -> Result<Vec<u8>, String> {
+    if !text.len().is_multiple_of(2) {
+        return Err(format!("invalid hex bytes {}", text));
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok()).ok_or(format!("invalid hex bytes {}", text)))
+        .collect()
+}
+
+/// splits a line of CSV into fields, undoing `csv_field`'s quoting
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            },
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(String::new()),
+            (c, _) => fields.last_mut().unwrap().push(c)
+        }
+    }
+    fields
+}
+
+/// reads a trace written by `Tracer`, in the format its extension suggests
+pub fn read_trace(path: &Path) -> Result<Vec<TraceRecord>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let format = TraceFormat::from_path(path);
+    let mut records = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let err = |e: String| format!("{}:{}: {}", path.display(), i + 1, e);
+        if line.trim().is_empty() || (format == TraceFormat::Csv && line == TraceRecord::CSV_HEADER) {
+            continue;
+        }
+        let record = match format {
+            TraceFormat::Jsonl => serde_json::from_str(line)
+                .map_err(|e| e.to_string())
+                .and_then(|v| TraceRecord::from_json(&v)),
+            TraceFormat::Csv => TraceRecord::from_csv(line)
+        };
+        records.push(record.map_err(err)?);
+    }
+    Ok(records)
+}
+
+
+/// where two traces first disagree
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Divergence {
+    /// the position of the first differing record in both traces
+    pub index: usize,
+    /// what differs, e.g. `r1: 0x2c vs 0x2d`
+    pub differences: Vec<String>
+}
+
+/// compares two traces record by record, returning the first where the pc,
+/// registers, flags or memory writes differ, or where one trace ends early
+pub fn first_divergence(a: &[TraceRecord], b: &[TraceRecord]) -> Option<Divergence> {
+    for i in 0..a.len().max(b.len()) {
+        let differences = match (a.get(i), b.get(i)) {
+            (Some(a), Some(b)) => differences(a, b),
+            (Some(_), None) => vec![format!("the second trace ends after {} instructions", i)],
+            (None, Some(_)) => vec![format!("the first trace ends after {} instructions", i)],
+            (None, None) => Vec::new()
+        };
+        if !differences.is_empty() {
+            return Some(Divergence { index: i, differences });
+        }
+    }
+    None
+}
+
+/// lists how two records differ
+fn differences(a: &TraceRecord, b: &TraceRecord) -> Vec<String> {
+    let mut ret = Vec::new();
+    if a.pc != b.pc {
+        ret.push(format!("pc: 0x{:x} vs 0x{:x}", a.pc, b.pc));
+    } else if a.inst != b.inst {
+        ret.push(format!("instruction: {} vs {}", a.inst, b.inst));
+    }
+    for (r, (x, y)) in a.regs_after.iter().zip(b.regs_after.iter()).enumerate() {
+        if x != y {
+            ret.push(format!("r{}: 0x{:x} vs 0x{:x}", r, x, y));
+        }
+    }
+    if a.sp_after != b.sp_after {
+        ret.push(format!("sp: 0x{:x} vs 0x{:x}", a.sp_after, b.sp_after));
+    }
+    if a.flags_after != b.flags_after {
+        ret.push(format!("fl: 0x{:02x} vs 0x{:02x}", a.flags_after, b.flags_after));
+    }
+    let writes = |r: &TraceRecord| -> String {
+        let w: Vec<String> = r.writes.iter().map(|(addr, _, new)| format!("0x{:x}={}", addr, hex(new))).collect();
+        match w.is_empty() {
+            true => "none".to_string(),
+            false => w.join(" ")
+        }
+    };
+    if a.writes.iter().map(|w| (w.0, &w.2)).ne(b.writes.iter().map(|w| (w.0, &w.2))) {
+        ret.push(format!("writes: {} vs {}", writes(a), writes(b)));
+    }
+    ret
+}
+
+/// writes a report of a divergence between traces `a` and `b`, showing
+/// `context` records either side of it from each. Each trace's symbols are
+/// used to label addresses and find source lines
+pub fn write_divergence(
+    out: &mut impl Write,
+    div: &Divergence,
+    a: (&str, &[TraceRecord], &SymbolTable),
+    b: (&str, &[TraceRecord], &SymbolTable),
+    context: usize
+) -> std::io::Result<()> {
+    writeln!(out, "traces diverge at instruction {}:", div.index)?;
+    for d in div.differences.iter() {
+        writeln!(out, "  {}", d)?;
+    }
+
+    for (name, records, symbols) in [a, b] {
+        writeln!(out)?;
+        writeln!(out, "{}:", name)?;
+        let start = div.index.saturating_sub(context);
+        let end = (div.index + context + 1).min(records.len());
+        for (i, r) in records.iter().enumerate().take(end).skip(start) {
+            let marker = match i == div.index {
+                true => ">",
+                false => " "
+            };
+            let mut location = symbols.describe_str(r.pc as u32).unwrap_or_default();
+            if let Some(line) = symbols.location(r.pc as u32) {
+                location = format!("{} {}", location, line).trim().to_string();
+            }
+            let line = format!("{} {:>8}  0x{:08x}  {:<28} {}", marker, r.cycle, r.pc, r.inst, location);
+            writeln!(out, "{}", line.trim_end())?;
+        }
+        if div.index >= records.len() {
+            writeln!(out, "> (end of trace)")?;
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> TraceRecord {
+        let mut regs_after = [0; NUM_REGS];
+        regs_after[1] = 0xffffffff;
+        TraceRecord {
+            cycle: 12,
+            pc: 0x1c,
+            inst: "ldw r1, [r2 + r3*4 - 0x8]".to_string(),
+            regs_before: [7; NUM_REGS],
+            regs_after,
+            sp_before: 0x2000,
+            sp_after: 0x1ffc,
+            flags_before: 0x21,
+            flags_after: 0,
+            reads: vec![(0x100, vec![0, 0xab]), (0x200, vec![0xff])],
+            writes: vec![(0x1ffc, vec![1, 2, 3, 4], vec![0xde, 0xad, 0xbe, 0xef])]
+        }
+    }
+
+    #[test]
+    fn json_round_trips() {
+        let r = record();
+        assert_eq!(TraceRecord::from_json(&r.to_json()), Ok(r.clone()));
+        let line = r.to_json().to_string();
+        assert_eq!(TraceRecord::from_json(&serde_json::from_str(&line).unwrap()), Ok(r));
+    }
+
+    #[test]
+    fn csv_round_trips() {
+        let mut r = record();
+        assert_eq!(TraceRecord::from_csv(&r.to_csv()), Ok(r.clone()));
+        // nothing read or written leaves those fields empty
+        r.reads.clear();
+        r.writes.clear();
+        assert!(r.to_csv().ends_with(",,"));
+        assert_eq!(TraceRecord::from_csv(&r.to_csv()), Ok(r.clone()));
+        r.inst = "bytes \"a,\"\"b\"".to_string();
+        assert_eq!(TraceRecord::from_csv(&r.to_csv()), Ok(r));
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_only_when_needed() {
+        assert_eq!(csv_field("nop"), "nop");
+        assert_eq!(csv_field("mov r0, r1"), "\"mov r0, r1\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(split_csv("a,\"b,c\",\"d\"\"e\",,f"), ["a", "b,c", "d\"e", "", "f"]);
+        assert_eq!(split_csv(""), [""]);
+    }
+
+    /// collects what a `Tracer` writes
+    #[derive(Clone, Default)]
+    struct Buffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn records_count_cycles() {
+        let mut cpu = CPU::new(&crate::MachineConfig::default());
+        let source = "section .text
+            movi r0, 6
+            muli r0, 7
+            nop
+            hlt";
+        cpu.load(&crate::assemble(source).unwrap().image()).unwrap();
+        let buffer = Buffer::default();
+        let mut tracer = Tracer::new(Box::new(buffer.clone()), TraceFormat::Csv);
+        cpu.run_observed(&mut |cpu, step| tracer.observe(cpu, step));
+        tracer.finish().unwrap();
+
+        let text = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        let records: Vec<TraceRecord> = text.lines().skip(1).map(|l| TraceRecord::from_csv(l).unwrap()).collect();
+        let cycles: Vec<u64> = records.iter().map(|r| r.cycle).collect();
+        // `muli` takes 3 cycles
+        assert_eq!(cycles, [0, 1, 4, 5]);
+        assert_eq!(records[1].regs_after[0], 42);
+    }
+
+    #[test]
+    fn traces_diverge_on_registers() {
+        let a = vec![record(), record()];
+        let mut b = a.clone();
+        b[1].regs_after[3] = 0x2d;
+        b[1].flags_after = 1;
+        assert_eq!(first_divergence(&a, &a), None);
+        assert_eq!(first_divergence(&a, &b), Some(Divergence {
+            index: 1,
+            differences: vec!["r3: 0x0 vs 0x2d".to_string(), "fl: 0x00 vs 0x01".to_string()]
+        }));
+    }
+
+    #[test]
+    fn traces_diverge_on_writes() {
+        let a = vec![record()];
+        let mut b = a.clone();
+        // what was there before doesn't matter, only what was written
+        b[0].writes[0].1 = vec![9, 9, 9, 9];
+        assert_eq!(first_divergence(&a, &b), None);
+        b[0].writes[0].2[3] = 0xee;
+        let div = first_divergence(&a, &b).unwrap();
+        assert_eq!(div.differences, ["writes: 0x1ffc=deadbeef vs 0x1ffc=deadbeee"]);
+        b[0].writes.clear();
+        let div = first_divergence(&a, &b).unwrap();
+        assert_eq!(div.differences, ["writes: 0x1ffc=deadbeef vs none"]);
+    }
+
+    #[test]
+    fn traces_diverge_where_one_ends() {
+        let a = vec![record(), record(), record()];
+        assert_eq!(first_divergence(&a, &a[..2]), Some(Divergence {
+            index: 2,
+            differences: vec!["the second trace ends after 2 instructions".to_string()]
+        }));
+        assert_eq!(first_divergence(&[], &a).unwrap().differences, ["the first trace ends after 0 instructions"]);
+    }
+
+    #[test]
+    fn a_different_pc_is_reported_before_the_instruction() {
+        let a = vec![record()];
+        let mut b = a.clone();
+        b[0].inst = "nop".to_string();
+        assert_eq!(first_divergence(&a, &b).unwrap().differences, ["instruction: ldw r1, [r2 + r3*4 - 0x8] vs nop"]);
+        b[0].pc = 0x20;
+        assert_eq!(first_divergence(&a, &b).unwrap().differences, ["pc: 0x1c vs 0x20"]);
+    }
+
+    #[test]
+    fn non_ascii_hex_is_an_error() {
+        assert_eq!(unhex("00ff"), Ok(vec![0, 0xff]));
+        // slicing two bytes at a time would split the `é`
+        assert_eq!(unhex("0é0"), Err("invalid hex bytes 0é0".to_string()));
+        assert_eq!(unhex("0"), Err("invalid hex bytes 0".to_string()));
+        let line = record().to_csv().replace("deadbeef", "deadbéf");
+        assert!(TraceRecord::from_csv(&line).is_err());
+    }
+
+    #[test]
+    fn bad_records_are_errors() {
+        assert_eq!(TraceRecord::from_csv("1,2,3"), Err("expected 11 fields, found 3".to_string()));
+        let mut v = record().to_json();
+        v["regs_after"] = json!([1, 2]);
+        assert_eq!(TraceRecord::from_json(&v), Err(format!("regs_after should have {} registers", NUM_REGS)));
+        v["pc"] = json!("0x10");
+        assert_eq!(TraceRecord::from_json(&v), Err("missing or invalid pc".to_string()));
+    }
+}
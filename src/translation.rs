@@ -1,7 +1,10 @@
 use std::{collections::HashMap};
 use regex::Regex;
-use crate::processor::instructions::Instruction;
+use crate::processor::instructions::{Instruction, AddrMode, MemOperand};
+use crate::processor::cpu::cpu::{NUM_REGS, REG_FP, REG_LR};
+use crate::processor::cpu::control::{CR_NAMES, NUM_CRS};
 use crate::debug;
+use crate::error::{AsmError, Span};
 
 pub fn build_translation_table() -> HashMap<u8, Instruction> {
     let mut map: HashMap<u8, Instruction> = HashMap::new();
@@ -9,6 +12,28 @@ pub fn build_translation_table() -> HashMap<u8, Instruction> {
     map.insert(0x12, Instruction::AddImm);
     map.insert(0x02, Instruction::SubReg);
     map.insert(0x03, Instruction::SubImm);
+    map.insert(0x13, Instruction::AdcReg);
+    map.insert(0x14, Instruction::AdcImm);
+    map.insert(0x04, Instruction::SbbReg);
+    map.insert(0x05, Instruction::SbbImm);
+    map.insert(0x20, Instruction::DivReg);
+    map.insert(0x21, Instruction::DivImm);
+    map.insert(0x22, Instruction::SdivReg);
+    map.insert(0x23, Instruction::SdivImm);
+    map.insert(0x24, Instruction::ModReg);
+    map.insert(0x25, Instruction::ModImm);
+    map.insert(0x26, Instruction::SmodReg);
+    map.insert(0x27, Instruction::SmodImm);
+    map.insert(0x28, Instruction::ShlReg);
+    map.insert(0x29, Instruction::ShlImm);
+    map.insert(0x2a, Instruction::ShrReg);
+    map.insert(0x2b, Instruction::ShrImm);
+    map.insert(0x2c, Instruction::SarReg);
+    map.insert(0x2d, Instruction::SarImm);
+    map.insert(0x2e, Instruction::RolReg);
+    map.insert(0x2f, Instruction::RolImm);
+    map.insert(0x30, Instruction::RorReg);
+    map.insert(0x31, Instruction::RorImm);
     map.insert(0x38, Instruction::MulReg);
     map.insert(0x39, Instruction::MulImm);
     map.insert(0x41, Instruction::AndReg);
@@ -25,6 +50,15 @@ pub fn build_translation_table() -> HashMap<u8, Instruction> {
     map.insert(0x90, Instruction::MovDregSimm);
     map.insert(0xb1, Instruction::LdImm);
     map.insert(0xb2, Instruction::LdReg);
+    map.insert(0xb3, Instruction::Ldb);
+    map.insert(0xb4, Instruction::Ldbs);
+    map.insert(0xb5, Instruction::Ldh);
+    map.insert(0xb6, Instruction::Ldhs);
+    map.insert(0xb7, Instruction::Ldw);
+    map.insert(0xb8, Instruction::Stb);
+    map.insert(0xb9, Instruction::Sth);
+    map.insert(0xba, Instruction::Stw);
+    map.insert(0xbb, Instruction::Lea);
     map.insert(0xc5, Instruction::Swp);
     map.insert(0xd5, Instruction::PushAddr);
     map.insert(0xd6, Instruction::PushReg);
@@ -33,6 +67,7 @@ pub fn build_translation_table() -> HashMap<u8, Instruction> {
     map.insert(0xf2, Instruction::Pop);
     map.insert(0xff, Instruction::Nop);
     map.insert(0x6f, Instruction::Hlt);
+    map.insert(0x6e, Instruction::Brk);
     map.insert(0x81, Instruction::JmpAddr);
     map.insert(0x82, Instruction::JmpImm);
     map.insert(0x83, Instruction::JmpReg);
@@ -40,6 +75,11 @@ pub fn build_translation_table() -> HashMap<u8, Instruction> {
     map.insert(0x85, Instruction::JeqReg);
     map.insert(0xaa, Instruction::IntImm);
     map.insert(0xab, Instruction::IntReg);
+    map.insert(0xc0, Instruction::Rdcr);
+    map.insert(0xc1, Instruction::Wrcr);
+    map.insert(0xc2, Instruction::Iret);
+    map.insert(0xc3, Instruction::Cli);
+    map.insert(0xc4, Instruction::Sti);
     
     map
 }
@@ -50,6 +90,28 @@ pub fn build_compile_table() -> HashMap<Instruction, u8> {
     map.insert(Instruction::AddImm, 0x12);
     map.insert(Instruction::SubReg, 0x02);
     map.insert(Instruction::SubImm, 0x03);
+    map.insert(Instruction::AdcReg, 0x13);
+    map.insert(Instruction::AdcImm, 0x14);
+    map.insert(Instruction::SbbReg, 0x04);
+    map.insert(Instruction::SbbImm, 0x05);
+    map.insert(Instruction::DivReg, 0x20);
+    map.insert(Instruction::DivImm, 0x21);
+    map.insert(Instruction::SdivReg, 0x22);
+    map.insert(Instruction::SdivImm, 0x23);
+    map.insert(Instruction::ModReg, 0x24);
+    map.insert(Instruction::ModImm, 0x25);
+    map.insert(Instruction::SmodReg, 0x26);
+    map.insert(Instruction::SmodImm, 0x27);
+    map.insert(Instruction::ShlReg, 0x28);
+    map.insert(Instruction::ShlImm, 0x29);
+    map.insert(Instruction::ShrReg, 0x2a);
+    map.insert(Instruction::ShrImm, 0x2b);
+    map.insert(Instruction::SarReg, 0x2c);
+    map.insert(Instruction::SarImm, 0x2d);
+    map.insert(Instruction::RolReg, 0x2e);
+    map.insert(Instruction::RolImm, 0x2f);
+    map.insert(Instruction::RorReg, 0x30);
+    map.insert(Instruction::RorImm, 0x31);
     map.insert(Instruction::MulReg, 0x38);
     map.insert(Instruction::MulImm, 0x39);
     map.insert(Instruction::AndReg, 0x41);
@@ -66,6 +128,15 @@ pub fn build_compile_table() -> HashMap<Instruction, u8> {
     map.insert(Instruction::MovDregSimm, 0x90);
     map.insert(Instruction::LdImm, 0xb1);
     map.insert(Instruction::LdReg, 0xb2);
+    map.insert(Instruction::Ldb, 0xb3);
+    map.insert(Instruction::Ldbs, 0xb4);
+    map.insert(Instruction::Ldh, 0xb5);
+    map.insert(Instruction::Ldhs, 0xb6);
+    map.insert(Instruction::Ldw, 0xb7);
+    map.insert(Instruction::Stb, 0xb8);
+    map.insert(Instruction::Sth, 0xb9);
+    map.insert(Instruction::Stw, 0xba);
+    map.insert(Instruction::Lea, 0xbb);
     map.insert(Instruction::Swp, 0xc5);
     map.insert(Instruction::PushAddr, 0xd5);
     map.insert(Instruction::PushReg, 0xd6);
@@ -74,6 +145,7 @@ pub fn build_compile_table() -> HashMap<Instruction, u8> {
     map.insert(Instruction::Pop, 0xf2);
     map.insert(Instruction::Nop, 0xff);
     map.insert(Instruction::Hlt, 0x6f);
+    map.insert(Instruction::Brk, 0x6e);
     map.insert(Instruction::JmpAddr, 0x81);
     map.insert(Instruction::JmpImm, 0x82);
     map.insert(Instruction::JmpReg, 0x83);
@@ -81,6 +153,11 @@ pub fn build_compile_table() -> HashMap<Instruction, u8> {
     map.insert(Instruction::JeqReg, 0x85);
     map.insert(Instruction::IntImm, 0xaa);
     map.insert(Instruction::IntReg, 0xab);
+    map.insert(Instruction::Rdcr, 0xc0);
+    map.insert(Instruction::Wrcr, 0xc1);
+    map.insert(Instruction::Iret, 0xc2);
+    map.insert(Instruction::Cli, 0xc3);
+    map.insert(Instruction::Sti, 0xc4);
     
     
     map
@@ -92,6 +169,28 @@ pub fn build_decode_table() -> HashMap<&'static str, Instruction> {
     map.insert("addi", Instruction::AddImm);
     map.insert("sub", Instruction::SubReg);
     map.insert("subi", Instruction::SubImm);
+    map.insert("adc", Instruction::AdcReg);
+    map.insert("adci", Instruction::AdcImm);
+    map.insert("sbb", Instruction::SbbReg);
+    map.insert("sbbi", Instruction::SbbImm);
+    map.insert("div", Instruction::DivReg);
+    map.insert("divi", Instruction::DivImm);
+    map.insert("sdiv", Instruction::SdivReg);
+    map.insert("sdivi", Instruction::SdivImm);
+    map.insert("mod", Instruction::ModReg);
+    map.insert("modi", Instruction::ModImm);
+    map.insert("smod", Instruction::SmodReg);
+    map.insert("smodi", Instruction::SmodImm);
+    map.insert("shl", Instruction::ShlReg);
+    map.insert("shli", Instruction::ShlImm);
+    map.insert("shr", Instruction::ShrReg);
+    map.insert("shri", Instruction::ShrImm);
+    map.insert("sar", Instruction::SarReg);
+    map.insert("sari", Instruction::SarImm);
+    map.insert("rol", Instruction::RolReg);
+    map.insert("roli", Instruction::RolImm);
+    map.insert("ror", Instruction::RorReg);
+    map.insert("rori", Instruction::RorImm);
     map.insert("mul", Instruction::MulReg);
     map.insert("muli", Instruction::MulImm);
     map.insert("and", Instruction::AndReg);
@@ -108,6 +207,15 @@ pub fn build_decode_table() -> HashMap<&'static str, Instruction> {
     map.insert("movr", Instruction::MovDaddrSreg);
     map.insert("ldi", Instruction::LdImm);
     map.insert("ldr", Instruction::LdReg);
+    map.insert("ldb", Instruction::Ldb);
+    map.insert("ldbs", Instruction::Ldbs);
+    map.insert("ldh", Instruction::Ldh);
+    map.insert("ldhs", Instruction::Ldhs);
+    map.insert("ldw", Instruction::Ldw);
+    map.insert("stb", Instruction::Stb);
+    map.insert("sth", Instruction::Sth);
+    map.insert("stw", Instruction::Stw);
+    map.insert("lea", Instruction::Lea);
     map.insert("swp", Instruction::Swp);
     map.insert("pusha", Instruction::PushAddr);
     map.insert("push", Instruction::PushReg);
@@ -116,6 +224,7 @@ pub fn build_decode_table() -> HashMap<&'static str, Instruction> {
     map.insert("pop", Instruction::Pop);
     map.insert("nop", Instruction::Nop);
     map.insert("hlt", Instruction::Hlt);
+    map.insert("brk", Instruction::Brk);
     map.insert("jmpl", Instruction::JmpAddr);
     map.insert("jmpi", Instruction::JmpImm);
     map.insert("jmp", Instruction::JmpReg);
@@ -123,14 +232,68 @@ pub fn build_decode_table() -> HashMap<&'static str, Instruction> {
     map.insert("jeqi", Instruction::JeqImm);
     map.insert("int", Instruction::IntImm);
     map.insert("intr", Instruction::IntReg);
+    map.insert("rdcr", Instruction::Rdcr);
+    map.insert("wrcr", Instruction::Wrcr);
+    map.insert("iret", Instruction::Iret);
+    map.insert("cli", Instruction::Cli);
+    map.insert("sti", Instruction::Sti);
 
     
     map
 }
 
 
+/// a line of source being assembled, used to point errors at the text that 
+/// caused them
+#[derive(Clone, Copy, Debug)]
+pub struct SourceLine<'a> {
+    pub line_no: usize,
+    pub text: &'a str
+}
+
+impl SourceLine<'_> {
+    /// the span of `token` within this line
+    fn span(&self, token: &str) -> Span {
+        Span::of(self.line_no, self.text, token)
+    }
+}
+
+/// encodes the data of a `bytes` directive. Strings are copied verbatim,
+/// hex values are appended after them
+fn encode_bytes(line: &str) -> Vec<u8> {
+    let line = &line[5..];
+    let mut ret: Vec<u8> = Vec::new();
+    let str_check = Regex::new("\"(.*?)\"").unwrap();
+    let byte_check = Regex::new("0[xX][0-9a-fA-F]+").unwrap();
+
+    // look for strings
+    for str_match in str_check.captures_iter(line) {
+        let str_raw = &str_match[1];
+
+        for c in str_raw.as_bytes() {
+            ret.push(*c);
+        }
+    }
+
+    debug!("Bytes");
+    // look for bytes
+    for byte_match in byte_check.captures_iter(line) {
+        debug!("{:?}", &byte_match[0]);
+        let byte = &byte_match[0][1..byte_match[0].len()-1];
+        let mut ret_byte = match u32::from_str_radix(byte, 16) {
+            Ok(a) => a.to_be_bytes().to_vec(),
+            Err(_e) => {
+                vec![0u8]
+            }
+        };
+        ret.append(&mut ret_byte);
+    }
+
+    ret
+}
+
 pub fn get_bytes_from_line(
-    line: &String,
+    line: &str,
     dt: &HashMap<&'static str, Instruction>,
 ) -> u32 {
     let components: Vec<&str> = line.split(" ").collect();
@@ -139,310 +302,159 @@ pub fn get_bytes_from_line(
         Some(a) => a,
         None => {
             if components[0] == "bytes" {
-                let line = &line[5..];
-                let mut ret: Vec<u8> = Vec::new();
-                let str_check = Regex::new("\"(.*?)\"").unwrap();
-                let byte_check = Regex::new("0[xX][0-9a-fA-F]+").unwrap();
-
-                // look for strings
-                for str_match in str_check.captures_iter(line) {
-                    let str_raw = &str_match[1];
-
-                    for c in str_raw.as_bytes() {
-                        ret.push(c.clone());
-                    }
-                }
-
-                debug!("Bytes");
-                // look for bytes
-                for byte_match in byte_check.captures_iter(line) {
-                    debug!("{:?}", &byte_match[0]);
-                    let byte = &byte_match[0][1..byte_match[0].len()-1];
-                    let mut ret_byte = match u32::from_str_radix(byte, 16) {
-                        Ok(a) => a.to_be_bytes().to_vec(),
-                        Err(_e) => {
-                            vec![0u8]
-                        }
-                    };
-                    ret.append(&mut ret_byte);
-                }
-
-                return ret.len() as u32;
+                return encode_bytes(line).len() as u32;
             }
             return 0;
         }
     };
 
-    match decode_inst {
-        Instruction::AddReg | Instruction::SubReg | Instruction::MulReg | 
-        Instruction::AndReg | Instruction::OrReg  | Instruction::XorReg | 
-        Instruction::CmpReg | Instruction::Swp | Instruction::MovDregSreg|
-        Instruction::LdReg => 2,
-        Instruction::AddImm | Instruction::SubImm | Instruction::MulImm | 
-        Instruction::AndImm | Instruction::OrImm  | Instruction::XorImm | 
-        Instruction::CmpImm | Instruction::MovDregSaddr | Instruction::MovDregSimm | Instruction::LdImm | Instruction::SfgReg => 6,
-        Instruction::PushReg | Instruction::Pop | 
-        Instruction::JmpReg | Instruction::IntReg | Instruction::JeqReg => 2,
-        Instruction::MovDaddrSreg => 6,
-        Instruction::PushAddr | Instruction::JmpAddr | Instruction::JeqImm | Instruction::JmpImm | Instruction::IntImm => 5,
-        Instruction::SfgImm => 3,
-        Instruction::Hlt | Instruction::Nop => 1   
-    }
+    decode_inst.size() as u32
 }
 
 pub fn encode_instruction(
-    line: String, 
+    src: SourceLine,
     ct: &HashMap<Instruction, u8>, 
     dt: &HashMap<&'static str, Instruction>,
-    labels: &HashMap<String, u32>
-) -> Result<Vec<u8>, String> {
-    
+    labels: &HashMap<String, u32>,
+    addr: u32
+) -> Result<Vec<u8>, AsmError> {
+    let line = src.text;
     let components: Vec<&str> = line.split(" ").collect();
 
     let decoded_inst = match dt.get(components[0]) {
         Some(a) => a,
         None => {
             if components[0] == "bytes" {
-                let line = &line[5..];
-                let mut ret: Vec<u8> = Vec::new();
-                let str_check = Regex::new("\"(.*?)\"").unwrap();
-                let byte_check = Regex::new("0[xX][0-9a-fA-F]+").unwrap();
-
-                // look for strings
-                for str_match in str_check.captures_iter(line) {
-                    let str_raw = &str_match[1];
-
-                    for c in str_raw.as_bytes() {
-                        ret.push(c.clone());
-                    }
-                }
-
-                debug!("Bytes");
-                // look for bytes
-                for byte_match in byte_check.captures_iter(line) {
-                    debug!("{:?}", &byte_match[0]);
-                    let byte = &byte_match[0][1..byte_match[0].len()-1];
-                    let mut ret_byte = match u32::from_str_radix(byte, 16) {
-                        Ok(a) => a.to_be_bytes().to_vec(),
-                        Err(_e) => {
-                            vec![0u8]
-                        }
-                    };
-                    ret.append(&mut ret_byte);
-                }
-
-                return Ok(ret);
+                return Ok(encode_bytes(line));
             }
             
-            return Err(format!("Unknown instruction {}", components[0]));
+            return Err(AsmError::UnknownInstruction { 
+                span: src.span(components[0]), 
+                name: components[0].to_string() 
+            });
         }
     };
     let oc = *(ct.get(decoded_inst).unwrap());
+    let ops = split_operands(line);
             
     debug!("{:?}", decoded_inst);
     
     let rt = match decoded_inst {
         Instruction::AddReg | Instruction::SubReg | Instruction::MulReg | 
+        Instruction::AdcReg | Instruction::SbbReg |
+        Instruction::DivReg | Instruction::SdivReg | Instruction::ModReg | Instruction::SmodReg |
+        Instruction::ShlReg | Instruction::ShrReg | Instruction::SarReg |
+        Instruction::RolReg | Instruction::RorReg |
         Instruction::AndReg | Instruction::OrReg  | Instruction::XorReg | 
         Instruction::CmpReg | Instruction::Swp | Instruction::MovDregSreg|
         Instruction::LdReg => {
             // format: inst REG, REG
-            let mut dest = components[1].to_string();
-            dest.retain(|x| x != ',');
-            let dest_byte = match reg_to_byte(&dest){
-                Ok(a) => a,
-                Err(e) => return Err(e)
-            };
-            let src_byte = match reg_to_byte(components[2]){
-                Ok(a) => a,
-                Err(e) => return Err(e)
-            };
+            expect_operands(&ops, 2, src)?;
+            let dest_byte = reg_to_byte(&ops[0], src)?;
+            let src_byte = reg_to_byte(&ops[1], src)?;
 
-            let regs = (dest_byte << 4) + src_byte;
-            
-            let mut ret = Vec::new();
-            ret.push(oc);
-            ret.push(regs);
-            
-            ret
-            
+            vec![oc, (dest_byte << 4) + src_byte]
         },
         Instruction::AddImm | Instruction::SubImm | Instruction::MulImm | 
+        Instruction::AdcImm | Instruction::SbbImm |
+        Instruction::DivImm | Instruction::SdivImm | Instruction::ModImm | Instruction::SmodImm |
+        Instruction::ShlImm | Instruction::ShrImm | Instruction::SarImm |
+        Instruction::RolImm | Instruction::RorImm |
         Instruction::AndImm | Instruction::OrImm  | Instruction::XorImm | 
         Instruction::CmpImm | Instruction::MovDregSaddr | Instruction::MovDregSimm | Instruction::LdImm | Instruction::SfgReg => {
             // format: inst REG, IMM 
-            let mut dest = components[1].to_string();
-            dest.retain(|x| x != ',');
-            let dest_byte = match reg_to_byte(&dest){
-                Ok(a) => a,
-                Err(e) => return Err(e)
-            };
-            let mut src_byte = match labels.get(components[2]) {
-                Some(a) => a.to_be_bytes().to_vec(),
-                None => {
-                    debug!("{}", &components[2][2..]);
-                    match u32::from_str_radix(&components[2][2..], 16){
-                        Ok(a) => a.to_be_bytes().to_vec(),
-                        Err(e) => return Err(format!("Failed to convert address {}: {}", &components[2][2..],  e))
-                    }
-                }
-            };
+            expect_operands(&ops, 2, src)?;
+            let dest_byte = reg_to_byte(&ops[0], src)?;
+            let imm = parse_number(&ops[1], labels, src)?;
 
             // put it together
-            let mut ret = Vec::new();
-            ret.push(oc);
-            ret.push(dest_byte);
-            ret.append(&mut src_byte);
+            let mut ret = vec![oc, dest_byte];
+            ret.extend_from_slice(&imm.to_be_bytes());
             ret
         },
-        
         Instruction::PushReg | Instruction::Pop | 
         Instruction::JmpReg | Instruction::IntReg | Instruction::JeqReg => {
             // format: inst REG
-            let dest_byte = match reg_to_byte(components[1]){
-                Ok(a) => a,
-                Err(e) => return Err(e)
-            };
+            expect_operands(&ops, 1, src)?;
+            let dest_byte = reg_to_byte(&ops[0], src)?;
 
-            let mut ret = Vec::new();
-            ret.push(oc);
-            ret.push(dest_byte);
+            vec![oc, dest_byte]
+        },
+        Instruction::Ldb | Instruction::Ldbs | Instruction::Ldh | Instruction::Ldhs | 
+        Instruction::Ldw | Instruction::Lea => {
+            // format: inst REG, [MEM]
+            expect_operands(&ops, 2, src)?;
+            let reg = reg_to_byte(&ops[0], src)?;
+            let mem = parse_mem_operand(&ops[1], labels, addr, src)?;
+
+            let mut ret = vec![oc, (reg << 4) + mem.base, mem.mode_byte()];
+            ret.extend_from_slice(&mem.disp.to_be_bytes());
+            ret
+        },
+        Instruction::Stb | Instruction::Sth | Instruction::Stw => {
+            // format: inst [MEM], REG
+            expect_operands(&ops, 2, src)?;
+            let mem = parse_mem_operand(&ops[0], labels, addr, src)?;
+            let reg = reg_to_byte(&ops[1], src)?;
+
+            let mut ret = vec![oc, (reg << 4) + mem.base, mem.mode_byte()];
+            ret.extend_from_slice(&mem.disp.to_be_bytes());
             ret
         },
         Instruction::MovDaddrSreg => {
             // format: inst ADDR, REG
-            let mut dest = match labels.get(components[1]) {
-                Some(a) => a.to_be_bytes().to_vec(),
-                None => {
-                    match u32::from_str_radix(&components[1][2..components[1][2..].len()], 16){
-                        Ok(a) => a.to_be_bytes().to_vec(),
-                        Err(e) => return Err(format!("Failed to convert address {}: {}", &components[1][2..],  e))
-                    }
-                }
-            };
-            let src = match reg_to_byte(components[2]){
-                Ok(a) => a,
-                Err(e) => return Err(e)
-            };
+            expect_operands(&ops, 2, src)?;
+            let dest = parse_number(&ops[0], labels, src)?;
+            let src_byte = reg_to_byte(&ops[1], src)?;
 
-            let mut ret = Vec::new();
-            ret.push(oc);
-            ret.append(&mut dest);
-            ret.push(src);
+            let mut ret = vec![oc];
+            ret.extend_from_slice(&dest.to_be_bytes());
+            ret.push(src_byte);
             ret
         }
-        /*Instruction::MovDregSaddr | Instruction::MovDregSimm => {
-            // format: inst REG, ADDR
-            let dest = match reg_to_byte(components[1]){
-                Ok(a) => a as u32,
-                Err(e) => return Err(e)
-            };
-            let src: u32 = match labels.get(components[2]) {
-                Some(a) => *a,
-                None => {
-                    match u32::from_str_radix(&components[2][2..], 16){
-                        Ok(a) => a,
-                        Err(e) => return Err(format!("Failed to convert address {}: {}", &components[2][2..], e))
-                    }
-                }
-            };
-
-            (oc << 24) + (dest << 16) + (src & 0xFFFF)
-        } */
+        Instruction::Rdcr => {
+            // format: inst REG, CR
+            expect_operands(&ops, 2, src)?;
+            let dest_byte = reg_to_byte(&ops[0], src)?;
+            let cr = parse_control_reg(&ops[1], labels, src)?;
+
+            let mut ret = vec![oc, dest_byte];
+            ret.extend_from_slice(&cr.to_be_bytes());
+            ret
+        },
+        Instruction::Wrcr => {
+            // format: inst CR, REG
+            expect_operands(&ops, 2, src)?;
+            let cr = parse_control_reg(&ops[0], labels, src)?;
+            let src_byte = reg_to_byte(&ops[1], src)?;
+
+            let mut ret = vec![oc];
+            ret.extend_from_slice(&cr.to_be_bytes());
+            ret.push(src_byte);
+            ret
+        },
         Instruction::PushAddr | Instruction::JmpAddr | Instruction::JeqImm | Instruction::JmpImm | Instruction::IntImm => {
             // format: inst ADDR
-            let mut dest = match labels.get(components[1]) {
-                Some(a) => a.to_be_bytes().to_vec(),
-                None => {
-                    match u32::from_str_radix(&components[1][2..], 16){
-                        Ok(a) => a.to_be_bytes().to_vec(),
-                        Err(e) => return Err(format!("Failed to convert address: {}", e))
-                    }
-                }
-            };
+            expect_operands(&ops, 1, src)?;
+            let dest = parse_number(&ops[0], labels, src)?;
 
-            
-            let mut ret = Vec::new();
-            ret.push(oc);
-            ret.append(&mut dest);
+            let mut ret = vec![oc];
+            ret.extend_from_slice(&dest.to_be_bytes());
             ret
         },
-        /*Instruction::LdImm => {
-            // format: inst REG, ADDR
-            let mut dest = components[1].to_string();
-            dest.retain(|x| x != ',');
-            let dest_byte = match reg_to_byte(&dest){
-                Ok(a) => a as u32,
-                Err(e) => return Err(e)
-            };
-            let src: u32 = match labels.get(components[2]) {
-                Some(a) => *a,
-                None => match u32::from_str_radix(&components[2][2..], 16){
-                    Ok(a) => a,
-                    Err(e) => return Err(format!("Failed to convert address: {}", e))
-                }
-            };
-
-            // put it together
-            (oc << 24) + (dest_byte << 16) + src
-        },*/
-        /*Instruction::JmpImm => {
-            // format: inst ADDR
-            let dest_byte = match labels.get(components[1]) {
-                Some(a) => *a,
-                None => {
-                    match reg_to_byte(components[1]){
-                        Ok(a) => a as u32,
-                        Err(e) => return Err(e)
-                    }
-                }
-            }; 
-            
-            
-            
-            (oc << 24) + (dest_byte << 16)
-        },*/
-        /*Instruction::IntImm => {
-            // format: inst ADDR
-            let dest_byte = match u32::from_str_radix(&components[1][2..], 16){
-                Ok(a) => a,
-                Err(e) => return Err(format!("Failed to convert address: {}", e))
-            };
-
-            (oc << 24) + (dest_byte << 16)
-        },*/
         Instruction::SfgImm => {
-            // format: inst BYTE ADDR
-            let dest: u8 = match labels.get(components[1]) {
-                Some(a) => (*a).try_into().unwrap(),
-                None => {
-                    match u32::from_str_radix(&components[1][2..components[1].len()-1], 16){
-                        Ok(a) => a.try_into().unwrap(),
-                        Err(e) => return Err(format!("Failed to convert address: {}", e))
-                    }
-                }
-            };
+            // format: inst BYTE, BYTE
+            expect_operands(&ops, 2, src)?;
+            let dest = parse_byte(&ops[0], labels, src)?;
+            let val = parse_byte(&ops[1], labels, src)?;
 
-            let src: u8 = match labels.get(components[2]) {
-                Some(a) => (*a).try_into().unwrap(),
-                None => {
-                    match u32::from_str_radix(&components[2][2..], 16){
-                        Ok(a) => a.try_into().unwrap(),
-                        Err(e) => return Err(format!("Failed to convert address: {}", e))
-                    }
-                }
-            };
-            let mut ret = Vec::new();
-            ret.push(oc);
-            ret.push(dest);
-            ret.push(src);
-            ret
+            vec![oc, dest, val]
         },
-        Instruction::Hlt | Instruction::Nop => {
+        Instruction::Hlt | Instruction::Nop | Instruction::Brk | Instruction::Iret |
+        Instruction::Cli | Instruction::Sti => {
             // format: inst
-            let mut ret = Vec::new();
-            ret.push(oc);
-            ret
+            expect_operands(&ops, 0, src)?;
+            vec![oc]
         }
         
     };
@@ -456,18 +468,275 @@ pub fn encode_instruction(
 /// converts an encoded u32 to a signed i32
 pub fn convert_to_signed(a: u32) -> i32 {
     if a & 0x80000000 != 0 {
-        return (a & 0x7FFFFFFF) as i32 * -1;
+        -((a & 0x7FFFFFFF) as i32)
     } else {
-        return (a & 0x7FFFFFFF) as i32;
+        (a & 0x7FFFFFFF) as i32
     }
 }
 
-fn reg_to_byte(register: &str) -> Result<u8, String> {
-    match &register[..2] {
-        "r0" => Ok(0),
-        "r1" => Ok(1),
-        "r2" => Ok(2),
-        "r3" => Ok(3),
-        _ => Err(format!("Invalid register {}", register))
+/// splits the operands of an instruction line on commas, ignoring any commas
+/// inside brackets and stripping trailing comments
+fn split_operands(line: &str) -> Vec<String> {
+    let line = line.split(';').next().unwrap_or("");
+    let operands = match line.trim().split_once(char::is_whitespace) {
+        Some((_, a)) => a,
+        None => return Vec::new()
+    };
+
+    let mut ret = Vec::new();
+    let mut depth = 0;
+    let mut current = String::new();
+    for c in operands.chars() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ',' if depth == 0 => {
+                ret.push(current.trim().to_string());
+                current.clear();
+                continue;
+            },
+            _ => ()
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() {
+        ret.push(current.trim().to_string());
+    }
+    ret
+}
+
+/// checks that an instruction was given `n` operands
+fn expect_operands(ops: &[String], n: usize, src: SourceLine) -> Result<(), AsmError> {
+    if ops.len() != n {
+        return Err(AsmError::InvalidOperand { 
+            span: Span::line(src.line_no, src.text), 
+            reason: format!("expected {} operand(s), found {}", n, ops.len())
+        });
+    }
+    Ok(())
+}
+
+/// parses a number in hex (`0x` prefixed) or decimal, or the address of a label
+fn parse_number(num: &str, labels: &HashMap<String, u32>, src: SourceLine) -> Result<u32, AsmError> {
+    if num.starts_with('.') {
+        return match labels.get(num) {
+            Some(a) => Ok(*a),
+            None => Err(AsmError::UndefinedLabel { span: src.span(num), name: num.to_string() })
+        };
+    }
+    let parsed = match num.strip_prefix("0x").or(num.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => num.parse::<u32>()
+    };
+    parsed.map_err(|_| AsmError::InvalidNumber { span: src.span(num), text: num.to_string() })
+}
+
+/// parses a number that must fit in a single byte
+fn parse_byte(num: &str, labels: &HashMap<String, u32>, src: SourceLine) -> Result<u8, AsmError> {
+    parse_number(num, labels, src)?
+        .try_into()
+        .map_err(|_| AsmError::InvalidNumber { span: src.span(num), text: num.to_string() })
+}
+
+/// parses a control register, by name (e.g. `ptbr`) or number
+fn parse_control_reg(cr: &str, labels: &HashMap<String, u32>, src: SourceLine) -> Result<u32, AsmError> {
+    if let Some(n) = CR_NAMES.iter().position(|a| *a == cr) {
+        return Ok(n as u32);
+    }
+    match parse_number(cr, labels, src)? {
+        n if (n as usize) < NUM_CRS => Ok(n),
+        _ => Err(AsmError::InvalidRegister { span: src.span(cr), name: cr.to_string() })
+    }
+}
+
+/// parses a memory operand such as `[r1]`, `[r1 - 0x4]`, `[r1 + r2*4 + 8]`, 
+/// `[pc + .label]` or `[.label]`. `addr` is the address of the instruction the
+/// operand belongs to; labels in `pc`-relative operands are converted into 
+/// their distance from it
+fn parse_mem_operand(
+    op: &str, 
+    labels: &HashMap<String, u32>, 
+    addr: u32, 
+    src: SourceLine
+) -> Result<MemOperand, AsmError> {
+    let invalid = |reason: String| AsmError::InvalidOperand { span: src.span(op), reason };
+    let inner = match op.trim().strip_prefix('[').and_then(|a| a.strip_suffix(']')) {
+        Some(a) => a,
+        None => return Err(invalid(format!("invalid memory operand {}", op)))
+    };
+
+    // split into signed terms
+    let mut terms: Vec<(bool, String)> = Vec::new();
+    let mut negative = false;
+    let mut current = String::new();
+    for c in inner.chars() {
+        match c {
+            '+' | '-' => {
+                terms.push((negative, current.trim().to_string()));
+                current.clear();
+                negative = c == '-';
+            },
+            _ => current.push(c)
+        }
+    }
+    terms.push((negative, current.trim().to_string()));
+
+    let pc_relative = terms.iter().any(|(_, t)| t == "pc");
+    let mut base: Option<u8> = None;
+    let mut index: Option<(u8, u8)> = None;
+    let mut disp: u32 = 0;
+    for (negative, term) in terms.iter() {
+        if term.is_empty() && !*negative {
+            // leading `+`, or an empty operand
+            continue;
+        }
+        if term == "pc" {
+            if *negative {
+                return Err(invalid(format!("cannot subtract pc in {}", op)));
+            }
+            continue;
+        }
+
+        // registers, optionally scaled
+        let (reg, scale) = match term.split_once('*') {
+            Some((r, s)) => (r.trim(), Some(s.trim())),
+            None => (term.as_str(), None)
+        };
+        // a term that can only be a register, like `r16` or `x*4`, is a bad
+        // register rather than a bad number
+        let numbered = reg.strip_prefix('r').is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+        let reg_byte = match reg_to_byte(reg, src) {
+            Ok(a) => Some(a),
+            Err(e) if numbered || scale.is_some() => return Err(e),
+            Err(_) => None
+        };
+        if let Some(r) = reg_byte {
+            if *negative {
+                return Err(invalid(format!("cannot subtract register {} in {}", reg, op)));
+            }
+            let scale = match scale {
+                Some(s) => match parse_number(s, labels, src)? {
+                    a @ (1 | 2 | 4 | 8) => a as u8,
+                    a => return Err(invalid(format!("invalid scale {} in {} (must be 1, 2, 4 or 8)", a, op)))
+                },
+                None => 1
+            };
+            if base.is_none() && scale == 1 {
+                base = Some(r);
+            } else if index.is_none() {
+                index = Some((r, scale));
+            } else {
+                return Err(invalid(format!("too many registers in {}", op)));
+            }
+            continue;
+        }
+
+        // otherwise its part of the displacement
+        let mut v = parse_number(term, labels, src)?;
+        if pc_relative && labels.contains_key(term) {
+            v = v.wrapping_sub(addr);
+        }
+        disp = match negative {
+            true => disp.wrapping_sub(v),
+            false => disp.wrapping_add(v)
+        };
+    }
+
+    // a lone scaled index can't be encoded without a base
+    let (base, index) = match (base, index) {
+        (None, Some((r, 1))) => (Some(r), None),
+        (None, Some(_)) => return Err(invalid(format!("scaled index requires a base register in {}", op))),
+        a => a
+    };
+
+    let mode = match (pc_relative, base, index) {
+        (true, None, None) => AddrMode::PcRelative,
+        (true, _, _) => return Err(invalid(format!("cannot combine pc with other registers in {}", op))),
+        (false, Some(_), Some(_)) => AddrMode::BaseIndex,
+        (false, Some(_), None) => AddrMode::BaseDisp,
+        (false, None, _) => AddrMode::Absolute
+    };
+    let (index, scale) = index.unwrap_or((0, 1));
+
+    Ok(MemOperand {
+        mode,
+        base: base.unwrap_or(0),
+        index,
+        scale,
+        disp
+    })
+}
+
+/// converts a register name (`r0`-`r15`, `fp` or `lr`) into its register number
+fn reg_to_byte(register: &str, src: SourceLine) -> Result<u8, AsmError> {
+    let register = register.trim().trim_end_matches(',');
+    match register {
+        "fp" => return Ok(REG_FP),
+        "lr" => return Ok(REG_LR),
+        _ => ()
+    }
+
+    match register.strip_prefix('r').and_then(|n| n.parse::<u8>().ok()) {
+        Some(n) if (n as usize) < NUM_REGS => Ok(n),
+        _ => Err(AsmError::InvalidRegister { span: src.span(register), name: register.to_string() })
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::Disassembler;
+
+    /// assembles one line at `addr`, where `.data` is at 0x2000
+    fn encode(line: &str, addr: u32) -> Vec<u8> {
+        let labels = HashMap::from([(".data".to_string(), 0x2000)]);
+        let src = SourceLine { line_no: 1, text: line };
+        encode_instruction(src, &build_compile_table(), &build_decode_table(), &labels, addr).unwrap()
+    }
+
+    /// checks that `line` encodes to `operand`, disassembles to `text`, and
+    /// that `text` encodes back to the same bytes
+    fn round_trip(line: &str, addr: u32, operand: MemOperand, text: &str) {
+        let bytes = encode(line, addr);
+        let disp = u32::from_be_bytes([bytes[3], bytes[4], bytes[5], bytes[6]]);
+        assert_eq!(MemOperand::decode(bytes[1] & 0x0f, bytes[2], disp), operand, "{}", line);
+        let decoded = Disassembler::new().decode(&bytes, addr as usize);
+        assert_eq!(decoded.text, text);
+        assert_eq!(decoded.size(), bytes.len());
+        assert_eq!(encode(&decoded.text, addr), bytes, "{}", text);
+    }
+
+    fn operand(mode: AddrMode, base: u8, index: u8, scale: u8, disp: u32) -> MemOperand {
+        MemOperand { mode, base, index, scale, disp }
+    }
+
+    #[test]
+    fn base_displacement_round_trips() {
+        round_trip("ldw r3, [r1]", 0, operand(AddrMode::BaseDisp, 1, 0, 1, 0), "ldw r3, [r1]");
+        round_trip("ldb r3, [r1 + 8]", 0, operand(AddrMode::BaseDisp, 1, 0, 1, 8), "ldb r3, [r1 + 0x8]");
+        round_trip("stw [fp - 4], r2", 0, operand(AddrMode::BaseDisp, 13, 0, 1, -4i32 as u32), "stw [r13 - 0x4], r2");
+    }
+
+    #[test]
+    fn base_index_round_trips_every_scale() {
+        round_trip("ldw r0, [r1 + r2]", 0, operand(AddrMode::BaseIndex, 1, 2, 1, 0), "ldw r0, [r1 + r2]");
+        round_trip("ldh r0, [r1 + r2*2 + 0x10]", 0, operand(AddrMode::BaseIndex, 1, 2, 2, 0x10), "ldh r0, [r1 + r2*2 + 0x10]");
+        round_trip("sth [r15 + r14*4], r0", 0, operand(AddrMode::BaseIndex, 15, 14, 4, 0), "sth [r15 + r14*4], r0");
+        round_trip("lea r0, [r1 + r2*8 - 8]", 0, operand(AddrMode::BaseIndex, 1, 2, 8, -8i32 as u32), "lea r0, [r1 + r2*8 - 0x8]");
+    }
+
+    #[test]
+    fn pc_relative_round_trips() {
+        round_trip("ldw r0, [pc + 8]", 0x100, operand(AddrMode::PcRelative, 0, 0, 1, 8), "ldw r0, [pc + 0x8]");
+        // labels become their distance from the instruction
+        round_trip("ldw r0, [pc + .data]", 0x100, operand(AddrMode::PcRelative, 0, 0, 1, 0x1f00), "ldw r0, [pc + 0x1f00]");
+        round_trip("lea r0, [pc + .data]", 0x3000, operand(AddrMode::PcRelative, 0, 0, 1, -0x1000i32 as u32), "lea r0, [pc - 0x1000]");
+    }
+
+    #[test]
+    fn absolute_round_trips() {
+        round_trip("ldbs r0, [0x1000]", 0, operand(AddrMode::Absolute, 0, 0, 1, 0x1000), "ldbs r0, [0x1000]");
+        round_trip("stb [.data + 2], r1", 0, operand(AddrMode::Absolute, 0, 0, 1, 0x2002), "stb [0x2002], r1");
+    }
+}